@@ -0,0 +1,365 @@
+/*
+ * Async counterpart of `Session`, built on tokio.
+ *
+ * The handshake and message encoding are shared with the blocking session
+ * through `handshake` and `codec`. After the handshake the session can be
+ * split into a sender for client messages and a `Stream` of server
+ * messages, so both directions can be driven concurrently from one task or
+ * many. Both halves share the pixel format, so a SetPixelFormat sent after
+ * the split changes how the stream decodes what follows.
+ */
+
+use crate::codec::{ClientMessage, DecodeError, ServerMessage};
+use crate::handshake::{Handshake, Step};
+use crate::rfb::{self, PixelFormat, RfbVersion};
+use crate::session::{HandshakeError, ReceiveError};
+use futures_core::Stream;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+#[derive(Debug)]
+pub struct AsyncSession {
+    stream: TcpStream,
+    rfb_version: RfbVersion,
+    pixel_format: Option<PixelFormat>,
+    screen_w: u16,
+    screen_h: u16,
+    name: String,
+}
+
+#[derive(Debug)]
+pub struct AsyncSender {
+    stream: OwnedWriteHalf,
+    pixel_format: Arc<Mutex<PixelFormat>>,
+}
+
+#[derive(Debug)]
+pub struct MessageStream {
+    stream: OwnedReadHalf,
+    pixel_format: Arc<Mutex<PixelFormat>>,
+    recv_buf: Vec<u8>,
+    /** Bytes to buffer before decoding again. */
+    needed: usize,
+    done: bool,
+}
+
+impl AsyncSession {
+    pub async fn new(address: &str, port: u16) -> Result<Self, std::io::Error> {
+        let stream = TcpStream::connect((address, port)).await?;
+        Ok(Self {
+            stream,
+            rfb_version: RfbVersion::Unsupported,
+            pixel_format: None,
+            screen_w: 0,
+            screen_h: 0,
+            name: String::new(),
+        })
+    }
+
+    pub fn screen_w(&self) -> u16 {
+        self.screen_w
+    }
+
+    pub fn screen_h(&self) -> u16 {
+        self.screen_h
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /**
+     * Perform the RFB 3.8 handshake. The password is only used if the server
+     * insists on VncAuth.
+     */
+    pub async fn handshake(&mut self, password: Option<&str>) -> Result<(), HandshakeError> {
+        let mut handshake = Handshake::new();
+        let mut out = Vec::new();
+        let mut step = handshake.step(&[], &mut out)?;
+        loop {
+            self.stream.write_all(&out).await?;
+            out.clear();
+            step = match (step, password) {
+                (Step::Read(len), _) => {
+                    let mut input = vec![0u8; len];
+                    self.stream.read_exact(&mut input).await?;
+                    handshake.step(&input, &mut out)?
+                }
+                (Step::Password, Some(password)) => handshake.password(password, &mut out),
+                (Step::Password, None) => {
+                    return Err(HandshakeError::UnsupportedSecurity(
+                        "Password required".to_string(),
+                    ))
+                }
+                (Step::Done(init), _) => {
+                    self.rfb_version = RfbVersion::Rfb38;
+                    self.screen_w = init.width;
+                    self.screen_h = init.height;
+                    self.pixel_format = Some(init.pixel_format);
+                    self.name = init.name;
+                    return Ok(());
+                }
+            };
+        }
+    }
+
+    pub async fn send(&mut self, msg: &ClientMessage) -> Result<(), std::io::Error> {
+        let mut encoded = Vec::new();
+        msg.encode(&mut encoded);
+        self.stream.write_all(&encoded).await
+    }
+
+    pub async fn set_pixel_format(&mut self, format: &PixelFormat) -> Result<(), std::io::Error> {
+        self.send(&ClientMessage::SetPixelFormat(format.clone()))
+            .await?;
+        self.pixel_format = Some(format.clone());
+        Ok(())
+    }
+
+    /**
+     * Set encodings in order of preference. First element is most preferred.
+     */
+    pub async fn set_encodings(
+        &mut self,
+        encodings: &[rfb::Encoding],
+    ) -> Result<(), std::io::Error> {
+        self.send(&ClientMessage::SetEncodings(
+            encodings.iter().map(|&x| x as i32).collect(),
+        ))
+        .await
+    }
+
+    /**
+     * Split the session into its sending and receiving halves, after the
+     * handshake.
+     */
+    pub fn into_split(self) -> Result<(AsyncSender, MessageStream), ReceiveError> {
+        let pixel_format = self.pixel_format.ok_or(ReceiveError::NotInitialized)?;
+        let pixel_format = Arc::new(Mutex::new(pixel_format));
+        let (read_half, write_half) = self.stream.into_split();
+        Ok((
+            AsyncSender {
+                stream: write_half,
+                pixel_format: pixel_format.clone(),
+            },
+            MessageStream {
+                stream: read_half,
+                pixel_format,
+                recv_buf: Vec::new(),
+                needed: 0,
+                done: false,
+            },
+        ))
+    }
+}
+
+impl AsyncSender {
+    /**
+     * Encode and write a message. After a SetPixelFormat, the message
+     * stream decodes with the new format.
+     */
+    pub async fn send(&mut self, msg: &ClientMessage) -> Result<(), std::io::Error> {
+        let mut encoded = Vec::new();
+        msg.encode(&mut encoded);
+        self.stream.write_all(&encoded).await?;
+        if let ClientMessage::SetPixelFormat(format) = msg {
+            *self.pixel_format.lock().unwrap() = format.clone();
+        }
+        Ok(())
+    }
+
+    pub async fn set_pixel_format(&mut self, format: &PixelFormat) -> Result<(), std::io::Error> {
+        self.send(&ClientMessage::SetPixelFormat(format.clone()))
+            .await
+    }
+
+    pub async fn framebuffer_update_request(
+        &mut self,
+        incremental: bool,
+        xpos: u16,
+        ypos: u16,
+        width: u16,
+        height: u16,
+    ) -> Result<(), std::io::Error> {
+        self.send(&ClientMessage::FramebufferUpdateRequest {
+            incremental,
+            x: xpos,
+            y: ypos,
+            width,
+            height,
+        })
+        .await
+    }
+}
+
+impl MessageStream {
+    /**
+     * Wait for the next server message. Returns None once the server closed
+     * the connection cleanly.
+     */
+    pub async fn next_message(&mut self) -> Option<Result<ServerMessage, ReceiveError>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for MessageStream {
+    type Item = Result<ServerMessage, ReceiveError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            if this.recv_buf.len() >= this.needed {
+                let format = this.pixel_format.lock().unwrap().clone();
+                match ServerMessage::decode(&this.recv_buf, &format) {
+                    Ok((msg, len)) => {
+                        this.recv_buf.drain(..len);
+                        this.needed = 0;
+                        return Poll::Ready(Some(Ok(msg)));
+                    }
+                    Err(DecodeError::Incomplete(needed)) => this.needed = needed,
+                    Err(err) => {
+                        this.done = true;
+                        return Poll::Ready(Some(Err(ReceiveError::DecodeError(err))));
+                    }
+                }
+            }
+
+            let mut chunk = [0u8; 4096];
+            let mut read_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.stream).poll_read(cx, &mut read_buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(err.into())));
+                }
+                Poll::Ready(Ok(())) if read_buf.filled().is_empty() => {
+                    this.done = true;
+                    if this.recv_buf.is_empty() {
+                        return Poll::Ready(None);
+                    }
+                    return Poll::Ready(Some(Err(ReceiveError::IoError(
+                        std::io::ErrorKind::UnexpectedEof.into(),
+                    ))));
+                }
+                Poll::Ready(Ok(())) => this.recv_buf.extend_from_slice(read_buf.filled()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockServer, Script};
+    use crate::session::Session;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_handshake_and_messages() {
+        let server = MockServer::start(
+            Script::new()
+                .handshake(4, 2, &Session::PREFERRED_PIXEL_FORMAT, "async")
+                .expect(&[3, 0, 0, 0, 0, 0, 0, 4, 0, 2])
+                .framebuffer_update(&[(0, 0, 4, 2, rfb::Encoding::DesktopSizePseudo as i32, &[])])
+                .send(&[2]),
+        );
+        block_on(async {
+            let mut session = AsyncSession::new("127.0.0.1", server.port()).await.unwrap();
+            session.handshake(None).await.unwrap();
+            assert_eq!(session.screen_w(), 4);
+            assert_eq!(session.screen_h(), 2);
+            assert_eq!(session.name(), "async");
+
+            let (mut sender, mut messages) = session.into_split().unwrap();
+            sender
+                .framebuffer_update_request(false, 0, 0, 4, 2)
+                .await
+                .unwrap();
+            assert!(matches!(
+                messages.next_message().await,
+                Some(Ok(ServerMessage::FramebufferUpdate(_)))
+            ));
+            assert!(matches!(
+                messages.next_message().await,
+                Some(Ok(ServerMessage::Bell))
+            ));
+            server.finish();
+            assert!(messages.next_message().await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_vnc_auth() {
+        let challenge = [7u8; 16];
+        let server = MockServer::start(
+            Script::new()
+                .version()
+                .security(&[2], 2)
+                .send(&challenge)
+                // DES of the challenge with the bit reversed password as key.
+                .expect(&[0x02, 0xb0, 0x48, 0xd0, 0x34, 0x8e, 0x64, 0x76].repeat(2))
+                .security_result(0)
+                .client_init(false)
+                .server_init(1, 1, &Session::PREFERRED_PIXEL_FORMAT, ""),
+        );
+        block_on(async {
+            let mut session = AsyncSession::new("127.0.0.1", server.port()).await.unwrap();
+            session.handshake(Some("secret")).await.unwrap();
+        });
+        server.finish();
+    }
+
+    #[test]
+    fn test_pixel_format_after_split() {
+        let format = PixelFormat {
+            bits_per_pixel: 8,
+            depth: 8,
+            big_endian_flag: 0,
+            true_color_flag: 1,
+            red_max: 7,
+            green_max: 7,
+            blue_max: 3,
+            red_shift: 5,
+            green_shift: 2,
+            blue_shift: 0,
+        };
+        let server = MockServer::start(
+            Script::new()
+                .handshake(2, 1, &Session::PREFERRED_PIXEL_FORMAT, "")
+                .skip(20)
+                .framebuffer_update(&[(0, 0, 2, 1, rfb::Encoding::Raw as i32, &[0xe0, 0x03])])
+                .send(&[2]),
+        );
+        block_on(async {
+            let mut session = AsyncSession::new("127.0.0.1", server.port()).await.unwrap();
+            session.handshake(None).await.unwrap();
+            let (mut sender, mut messages) = session.into_split().unwrap();
+            sender.set_pixel_format(&format).await.unwrap();
+            match messages.next_message().await {
+                Some(Ok(ServerMessage::FramebufferUpdate(rects))) => {
+                    assert_eq!(rects[0].data, [0xe0, 0x03])
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+            assert!(matches!(
+                messages.next_message().await,
+                Some(Ok(ServerMessage::Bell))
+            ));
+        });
+        server.finish();
+    }
+}
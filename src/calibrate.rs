@@ -0,0 +1,388 @@
+/*
+ * Touchscreen calibration.
+ *
+ * A calibration is an affine transform from raw touch coordinates to
+ * screen coordinates. Both are normalised to 0..1 over the axis range and
+ * the screen size, so a calibration does not depend on the resolution:
+ *
+ *   x' = a * x + b * y + c
+ *   y' = d * x + e * y + f
+ *
+ * `Calibrator` shows a series of targets and fits the transform to where
+ * they were touched. Calibrations are stored per device name, one device
+ * per line of the file: the six coefficients followed by the name.
+ */
+
+use crate::canvas::{Canvas, Rect};
+use crate::evdev::{self, InputEvent};
+use crate::pointer::AbsRange;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calibration(pub [f64; 6]);
+
+/**
+ * Target positions, inset from the edges where resistive panels are
+ * least accurate.
+ */
+pub const TARGETS: [(f64, f64); 5] = [(0.1, 0.1), (0.9, 0.1), (0.9, 0.9), (0.1, 0.9), (0.5, 0.5)];
+
+const TARGET_SIZE: u16 = 15;
+const BACKGROUND: u32 = 0x000000;
+const FOREGROUND: u32 = 0xffffff;
+const HIGHLIGHT: u32 = 0xff0000;
+
+#[derive(Clone, Debug)]
+pub struct Calibrator {
+    x_range: AbsRange,
+    y_range: AbsRange,
+    raw: (i32, i32),
+    touching: bool,
+    sum: (f64, f64, u32),
+    samples: Vec<(f64, f64)>,
+}
+
+impl Calibration {
+    pub const IDENTITY: Calibration = Calibration([1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+    pub const SWAP_AXES: Calibration = Calibration([0.0, 1.0, 0.0, 1.0, 0.0, 0.0]);
+    pub const INVERT_X: Calibration = Calibration([-1.0, 0.0, 1.0, 0.0, 1.0, 0.0]);
+    pub const INVERT_Y: Calibration = Calibration([1.0, 0.0, 0.0, 0.0, -1.0, 1.0]);
+
+    pub fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        let [a, b, c, d, e, f] = self.0;
+        (a * x + b * y + c, d * x + e * y + f)
+    }
+
+    /**
+     * This transform followed by `next`.
+     */
+    pub fn then(&self, next: &Calibration) -> Calibration {
+        let [a, b, c, d, e, f] = self.0;
+        let [na, nb, nc, nd, ne, nf] = next.0;
+        Calibration([
+            na * a + nb * d,
+            na * b + nb * e,
+            na * c + nb * f + nc,
+            nd * a + ne * d,
+            nd * b + ne * e,
+            nd * c + ne * f + nf,
+        ])
+    }
+
+    /**
+     * Least squares fit of the transform mapping each raw point to its
+     * screen point. Needs at least three points that are not on a line.
+     */
+    pub fn fit(raw: &[(f64, f64)], screen: &[(f64, f64)]) -> Option<Calibration> {
+        let mut m = [[0.0; 3]; 3];
+        let mut vx = [0.0; 3];
+        let mut vy = [0.0; 3];
+        for (&(x, y), &(sx, sy)) in raw.iter().zip(screen) {
+            let row = [x, y, 1.0];
+            for i in 0..3 {
+                for j in 0..3 {
+                    m[i][j] += row[i] * row[j];
+                }
+                vx[i] += row[i] * sx;
+                vy[i] += row[i] * sy;
+            }
+        }
+        let [a, b, c] = solve(&m, &vx)?;
+        let [d, e, f] = solve(&m, &vy)?;
+        Some(Calibration([a, b, c, d, e, f]))
+    }
+}
+
+fn determinant(m: &[[f64; 3]; 3]) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+/**
+ * Solve m * x = v by Cramer's rule.
+ */
+fn solve(m: &[[f64; 3]; 3], v: &[f64; 3]) -> Option<[f64; 3]> {
+    let det = determinant(m);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let mut x = [0.0; 3];
+    for (col, x) in x.iter_mut().enumerate() {
+        let mut replaced = *m;
+        for row in 0..3 {
+            replaced[row][col] = v[row];
+        }
+        *x = determinant(&replaced) / det;
+    }
+    Some(x)
+}
+
+/**
+ * Default calibration file, in the XDG config directory.
+ */
+pub fn default_path() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config.join("vncvwr").join("calibration"))
+}
+
+fn parse_line(line: &str) -> Option<(Calibration, &str)> {
+    let mut rest = line.trim();
+    let mut coefficients = [0.0; 6];
+    for coefficient in coefficients.iter_mut() {
+        let (field, tail) = rest.split_once(char::is_whitespace)?;
+        *coefficient = field.parse().ok()?;
+        rest = tail.trim_start();
+    }
+    Some((Calibration(coefficients), rest))
+}
+
+/**
+ * The stored calibration of the named device, if there is one.
+ */
+pub fn load<P: AsRef<Path>>(path: P, device: &str) -> Result<Option<Calibration>, std::io::Error> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    Ok(text
+        .lines()
+        .filter_map(parse_line)
+        .find(|(_, name)| *name == device)
+        .map(|(calibration, _)| calibration))
+}
+
+/**
+ * Store the calibration of the named device, replacing an earlier one.
+ */
+pub fn save<P: AsRef<Path>>(
+    path: P,
+    device: &str,
+    calibration: &Calibration,
+) -> Result<(), std::io::Error> {
+    let path = path.as_ref();
+    let mut text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    text = text
+        .lines()
+        .filter(|line| parse_line(line).is_none_or(|(_, name)| name != device))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    let [a, b, c, d, e, f] = calibration.0;
+    text.push_str(&format!("{} {} {} {} {} {} {}\n", a, b, c, d, e, f, device));
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, text)
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl Calibrator {
+    /**
+     * Ranges of ABS_X and ABS_Y of the device. Multi-touch devices report
+     * the first contact on these as well.
+     */
+    pub fn new(x_range: AbsRange, y_range: AbsRange) -> Self {
+        Self {
+            x_range,
+            y_range,
+            raw: (0, 0),
+            touching: false,
+            sum: (0.0, 0.0, 0),
+            samples: Vec::new(),
+        }
+    }
+
+    /**
+     * The target to touch next, normalised, or None when done.
+     */
+    pub fn target(&self) -> Option<(f64, f64)> {
+        TARGETS.get(self.samples.len()).copied()
+    }
+
+    /**
+     * Feed an event. Returns true when a target was completed and the
+     * canvas should be rendered again.
+     */
+    pub fn handle(&mut self, event: &InputEvent) -> bool {
+        match (event.event_type, event.code) {
+            (evdev::EV_ABS, evdev::ABS_X) => self.raw.0 = event.value,
+            (evdev::EV_ABS, evdev::ABS_Y) => self.raw.1 = event.value,
+            (evdev::EV_KEY, evdev::BTN_TOUCH) if event.value != 2 => {
+                self.touching = event.value != 0
+            }
+            (evdev::EV_SYN, evdev::SYN_REPORT) if self.touching => {
+                // Average over the whole touch, the first samples of a
+                // press are often off on resistive panels.
+                self.sum.0 += self.x_range.normalise(self.raw.0);
+                self.sum.1 += self.y_range.normalise(self.raw.1);
+                self.sum.2 += 1;
+            }
+            (evdev::EV_SYN, evdev::SYN_REPORT) if self.sum.2 > 0 => {
+                let (x, y, n) = std::mem::take(&mut self.sum);
+                if self.target().is_some() {
+                    self.samples.push((x / n as f64, y / n as f64));
+                    return true;
+                }
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /**
+     * The fitted calibration once all targets were touched.
+     */
+    pub fn result(&self) -> Option<Calibration> {
+        if self.target().is_some() {
+            return None;
+        }
+        Calibration::fit(&self.samples, &TARGETS)
+    }
+
+    /**
+     * Draw the current target as a cross on a blank canvas, with a dot for
+     * every target already touched.
+     */
+    pub fn render(&self, canvas: &mut Canvas) {
+        let (width, height) = (canvas.width(), canvas.height());
+        canvas.fill_rect(Rect::new(0, 0, width, height), BACKGROUND);
+        let position = |(x, y): (f64, f64)| {
+            (
+                (x * width.saturating_sub(1) as f64).round() as u16,
+                (y * height.saturating_sub(1) as f64).round() as u16,
+            )
+        };
+        for &target in &TARGETS[..self.samples.len()] {
+            let (x, y) = position(target);
+            canvas.fill_rect(
+                Rect::new(x.saturating_sub(1), y.saturating_sub(1), 3, 3),
+                FOREGROUND,
+            );
+        }
+        if let Some(target) = self.target() {
+            let (x, y) = position(target);
+            let arm = TARGET_SIZE / 2;
+            canvas.fill_rect(
+                Rect::new(x.saturating_sub(arm), y, TARGET_SIZE, 1),
+                FOREGROUND,
+            );
+            canvas.fill_rect(
+                Rect::new(x, y.saturating_sub(arm), 1, TARGET_SIZE),
+                FOREGROUND,
+            );
+            canvas.fill_rect(
+                Rect::new(x.saturating_sub(1), y.saturating_sub(1), 3, 3),
+                HIGHLIGHT,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: (f64, f64), expected: (f64, f64)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-9 && (actual.1 - expected.1).abs() < 1e-9,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_axis_transforms() {
+        assert_eq!(Calibration::SWAP_AXES.apply(0.25, 0.5), (0.5, 0.25));
+        assert_eq!(Calibration::INVERT_X.apply(0.25, 0.5), (0.75, 0.5));
+        assert_eq!(Calibration::INVERT_Y.apply(0.25, 0.25), (0.25, 0.75));
+        let swapped_inverted = Calibration::SWAP_AXES.then(&Calibration::INVERT_X);
+        assert_eq!(swapped_inverted.apply(0.25, 0.5), (0.5, 0.25));
+        assert_eq!(swapped_inverted.apply(0.0, 0.1), (0.9, 0.0));
+    }
+
+    #[test]
+    fn test_fit() {
+        let skewed = Calibration([0.9, 0.05, 0.03, -0.02, 1.1, -0.05]);
+        let raw: Vec<(f64, f64)> = TARGETS.iter().map(|&(x, y)| (x * 0.8 + 0.1, y)).collect();
+        let screen: Vec<(f64, f64)> = raw.iter().map(|&(x, y)| skewed.apply(x, y)).collect();
+        let fitted = Calibration::fit(&raw, &screen).unwrap();
+        for (i, &(x, y)) in raw.iter().enumerate() {
+            assert_close(fitted.apply(x, y), screen[i]);
+        }
+        assert_eq!(
+            Calibration::fit(&[(0.0, 0.0), (0.5, 0.5), (1.0, 1.0)], &TARGETS[..3]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_calibrator() {
+        let range = AbsRange { min: 0, max: 1000 };
+        let mut calibrator = Calibrator::new(range, range);
+        let mut canvas = Canvas::new(101, 101);
+        calibrator.render(&mut canvas);
+        assert_eq!(canvas.pixel(10, 10), HIGHLIGHT);
+        assert_eq!(canvas.pixel(3, 10), FOREGROUND);
+
+        // A panel with swapped axes.
+        for &(x, y) in &TARGETS {
+            let touch = [
+                (evdev::EV_ABS, evdev::ABS_X, (y * 1000.0) as i32),
+                (evdev::EV_ABS, evdev::ABS_Y, (x * 1000.0) as i32),
+                (evdev::EV_KEY, evdev::BTN_TOUCH, 1),
+                (evdev::EV_SYN, evdev::SYN_REPORT, 0),
+                (evdev::EV_KEY, evdev::BTN_TOUCH, 0),
+                (evdev::EV_SYN, evdev::SYN_REPORT, 0),
+            ];
+            let completed: Vec<bool> = touch
+                .iter()
+                .map(|&(event_type, code, value)| {
+                    calibrator.handle(&InputEvent {
+                        event_type,
+                        code,
+                        value,
+                    })
+                })
+                .collect();
+            assert_eq!(completed, [false, false, false, false, false, true]);
+        }
+        assert_eq!(calibrator.target(), None);
+        let calibration = calibrator.result().unwrap();
+        assert_close(calibration.apply(0.2, 0.7), (0.7, 0.2));
+
+        calibrator.render(&mut canvas);
+        assert_eq!(canvas.pixel(10, 10), FOREGROUND);
+        assert_eq!(canvas.pixel(50, 50), FOREGROUND);
+        assert_eq!(canvas.pixel(3, 10), BACKGROUND);
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = std::env::temp_dir().join(format!("vncvwr-calibration-{}", std::process::id()));
+        let path = dir.join("calibration");
+        assert_eq!(load(&path, "Panel").unwrap(), None);
+        save(&path, "Resistive Panel", &Calibration::SWAP_AXES).unwrap();
+        save(&path, "Panel", &Calibration::INVERT_X).unwrap();
+        save(&path, "Resistive Panel", &Calibration::INVERT_Y).unwrap();
+        assert_eq!(load(&path, "Panel").unwrap(), Some(Calibration::INVERT_X));
+        assert_eq!(
+            load(&path, "Resistive Panel").unwrap(),
+            Some(Calibration::INVERT_Y)
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
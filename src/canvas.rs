@@ -0,0 +1,527 @@
+/*
+ * Client side copy of the remote framebuffer.
+ *
+ * Pixels are stored as 0x00RRGGBB regardless of the pixel format used on
+ * the wire. Rectangles from FramebufferUpdate messages are decoded straight
+ * into the canvas, and the area they touched is reported back as damage.
+ */
+
+use crate::codec::{
+    DecodeError, Rectangle, HEXTILE_ANY_SUBRECTS, HEXTILE_BACKGROUND_SPECIFIED,
+    HEXTILE_FOREGROUND_SPECIFIED, HEXTILE_RAW, HEXTILE_SUBRECTS_COLOURED,
+};
+use crate::rfb::{Encoding, PixelFormat};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub hotspot_x: u16,
+    pub hotspot_y: u16,
+    pub width: u16,
+    pub height: u16,
+    pub pixels: Vec<u32>,
+    pub mask: Vec<bool>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Canvas {
+    width: u16,
+    height: u16,
+    pixels: Vec<u32>,
+    colour_map: Vec<u32>,
+    cursor: Option<Cursor>,
+}
+
+/**
+ * What applying a rectangle changed on the canvas.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Damaged(Rect),
+    Resized(u16, u16),
+    CursorChanged,
+    CursorMoved(u16, u16),
+    /** Nothing on the canvas, the rectangle only announced a capability. */
+    Unchanged,
+}
+
+impl Rect {
+    pub fn new(x: u16, y: u16, width: u16, height: u16) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn right(&self) -> u32 {
+        self.x as u32 + self.width as u32
+    }
+
+    pub fn bottom(&self) -> u32 {
+        self.y as u32 + self.height as u32
+    }
+
+    /**
+     * Move the rectangle by `x`, `y`. Positions past the largest coordinate
+     * are kept at it, where the rectangle is outside of any canvas.
+     */
+    pub fn offset(&self, x: u16, y: u16) -> Rect {
+        Rect::new(
+            self.x.saturating_add(x),
+            self.y.saturating_add(y),
+            self.width,
+            self.height,
+        )
+    }
+
+    /**
+     * Clip the rectangle to a `width` x `height` area at the origin.
+     */
+    pub fn clip(&self, width: u16, height: u16) -> Rect {
+        let x = std::cmp::min(self.x, width);
+        let y = std::cmp::min(self.y, height);
+        Rect {
+            x,
+            y,
+            width: (std::cmp::min(self.right(), width as u32) - x as u32) as u16,
+            height: (std::cmp::min(self.bottom(), height as u32) - y as u32) as u16,
+        }
+    }
+}
+
+impl Canvas {
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize],
+            colour_map: vec![0; 256],
+            cursor: None,
+        }
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        &mut self.pixels
+    }
+
+    pub fn pixel(&self, x: u16, y: u16) -> u32 {
+        self.pixels[y as usize * self.width as usize + x as usize]
+    }
+
+    pub fn resize(&mut self, width: u16, height: u16) {
+        self.width = width;
+        self.height = height;
+        self.pixels = vec![0; width as usize * height as usize];
+    }
+
+    pub fn set_colour_map_entries(&mut self, first_colour: u16, colours: &[[u16; 3]]) {
+        for (i, colour) in colours.iter().enumerate() {
+            if let Some(entry) = self.colour_map.get_mut(first_colour as usize + i) {
+                *entry = ((colour[0] as u32 >> 8) << 16)
+                    | ((colour[1] as u32 >> 8) << 8)
+                    | (colour[2] as u32 >> 8);
+            }
+        }
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, colour: u32) {
+        let rect = rect.clip(self.width, self.height);
+        for y in rect.y as usize..rect.bottom() as usize {
+            let row = y * self.width as usize;
+            self.pixels[row + rect.x as usize..row + rect.right() as usize].fill(colour);
+        }
+    }
+
+    pub fn copy_rect(&mut self, src_x: u16, src_y: u16, dst: Rect) {
+        let src = Rect::new(src_x, src_y, dst.width, dst.height).clip(self.width, self.height);
+        let dst = Rect::new(dst.x, dst.y, src.width, src.height).clip(self.width, self.height);
+        let width = self.width as usize;
+        let copy_row = |pixels: &mut Vec<u32>, row: usize| {
+            let from = (src.y as usize + row) * width + src.x as usize;
+            let to = (dst.y as usize + row) * width + dst.x as usize;
+            pixels.copy_within(from..from + dst.width as usize, to);
+        };
+        if dst.y > src.y {
+            for row in (0..dst.height as usize).rev() {
+                copy_row(&mut self.pixels, row);
+            }
+        } else {
+            for row in 0..dst.height as usize {
+                copy_row(&mut self.pixels, row);
+            }
+        }
+    }
+
+    /**
+     * Decode a rectangle of a FramebufferUpdate into the canvas.
+     */
+    pub fn apply(&mut self, rect: &Rectangle, format: &PixelFormat) -> Result<Change, DecodeError> {
+        if !format.is_supported() {
+            return Err(DecodeError::UnsupportedPixelFormat);
+        }
+        let area = Rect::new(rect.x, rect.y, rect.width, rect.height);
+        match rect.encoding {
+            e if e == Encoding::Raw as i32 => self.apply_raw(area, &rect.data, format),
+            e if e == Encoding::CopyRect as i32 => {
+                let src_x = u16::from_be_bytes([rect.data[0], rect.data[1]]);
+                let src_y = u16::from_be_bytes([rect.data[2], rect.data[3]]);
+                self.copy_rect(src_x, src_y, area);
+            }
+            e if e == Encoding::RRE as i32 => self.apply_rre(area, &rect.data, format),
+            e if e == Encoding::Hextile as i32 => self.apply_hextile(area, &rect.data, format),
+            e if e == Encoding::DesktopSizePseudo as i32 => {
+                self.resize(rect.width, rect.height);
+                return Ok(Change::Resized(rect.width, rect.height));
+            }
+            e if e == Encoding::CursorPseudo as i32 => {
+                self.apply_cursor(rect, format);
+                return Ok(Change::CursorChanged);
+            }
+            e if e == Encoding::PointerPosPseudo as i32 => {
+                return Ok(Change::CursorMoved(rect.x, rect.y));
+            }
+            e if e == Encoding::QemuExtendedKeyEventPseudo as i32 => {
+                return Ok(Change::Unchanged);
+            }
+            other => return Err(DecodeError::UnsupportedEncoding(other)),
+        }
+        Ok(Change::Damaged(area.clip(self.width, self.height)))
+    }
+
+    fn colour(&self, data: &[u8], format: &PixelFormat) -> u32 {
+        let value = format.read_pixel(data);
+        if format.true_color_flag != 0 {
+            format.to_rgb(value)
+        } else {
+            self.colour_map.get(value as usize).copied().unwrap_or(0)
+        }
+    }
+
+    fn put_pixel(&mut self, x: u32, y: u32, colour: u32) {
+        if x < self.width as u32 && y < self.height as u32 {
+            self.pixels[y as usize * self.width as usize + x as usize] = colour;
+        }
+    }
+
+    fn put_pixels(&mut self, area: Rect, data: &[u8], format: &PixelFormat) {
+        let bpp = format.bytes_per_pixel();
+        for (i, pixel) in data.chunks_exact(bpp).enumerate() {
+            let x = area.x as u32 + (i % area.width as usize) as u32;
+            let y = area.y as u32 + (i / area.width as usize) as u32;
+            let colour = self.colour(pixel, format);
+            self.put_pixel(x, y, colour);
+        }
+    }
+
+    fn apply_raw(&mut self, area: Rect, data: &[u8], format: &PixelFormat) {
+        if !area.is_empty() {
+            self.put_pixels(area, data, format);
+        }
+    }
+
+    fn apply_rre(&mut self, area: Rect, data: &[u8], format: &PixelFormat) {
+        let bpp = format.bytes_per_pixel();
+        let background = self.colour(&data[4..4 + bpp], format);
+        self.fill_rect(area, background);
+        for subrect in data[4 + bpp..].chunks_exact(bpp + 8) {
+            let colour = self.colour(&subrect[..bpp], format);
+            let field = |i: usize| u16::from_be_bytes([subrect[bpp + i], subrect[bpp + i + 1]]);
+            let sub =
+                Rect::new(field(0), field(2), field(4), field(6)).clip(area.width, area.height);
+            self.fill_rect(sub.offset(area.x, area.y), colour);
+        }
+    }
+
+    fn apply_hextile(&mut self, area: Rect, data: &[u8], format: &PixelFormat) {
+        let bpp = format.bytes_per_pixel();
+        let mut pos = 0;
+        let mut background = 0;
+        let mut foreground = 0;
+        for tile_y in (0..area.height).step_by(16) {
+            for tile_x in (0..area.width).step_by(16) {
+                let tile = Rect::new(
+                    tile_x,
+                    tile_y,
+                    std::cmp::min(16, area.width - tile_x),
+                    std::cmp::min(16, area.height - tile_y),
+                )
+                .offset(area.x, area.y);
+                let subencoding = data[pos];
+                pos += 1;
+                if subencoding & HEXTILE_RAW != 0 {
+                    let len = tile.width as usize * tile.height as usize * bpp;
+                    self.put_pixels(tile, &data[pos..pos + len], format);
+                    pos += len;
+                    continue;
+                }
+                if subencoding & HEXTILE_BACKGROUND_SPECIFIED != 0 {
+                    background = self.colour(&data[pos..pos + bpp], format);
+                    pos += bpp;
+                }
+                self.fill_rect(tile, background);
+                if subencoding & HEXTILE_FOREGROUND_SPECIFIED != 0 {
+                    foreground = self.colour(&data[pos..pos + bpp], format);
+                    pos += bpp;
+                }
+                if subencoding & HEXTILE_ANY_SUBRECTS == 0 {
+                    continue;
+                }
+                let count = data[pos];
+                pos += 1;
+                for _ in 0..count {
+                    let mut colour = foreground;
+                    if subencoding & HEXTILE_SUBRECTS_COLOURED != 0 {
+                        colour = self.colour(&data[pos..pos + bpp], format);
+                        pos += bpp;
+                    }
+                    let (xy, wh) = (data[pos] as u16, data[pos + 1] as u16);
+                    pos += 2;
+                    let sub = Rect::new(xy >> 4, xy & 0xf, (wh >> 4) + 1, (wh & 0xf) + 1)
+                        .clip(tile.width, tile.height);
+                    self.fill_rect(sub.offset(tile.x, tile.y), colour);
+                }
+            }
+        }
+    }
+
+    fn apply_cursor(&mut self, rect: &Rectangle, format: &PixelFormat) {
+        let bpp = format.bytes_per_pixel();
+        let (width, height) = (rect.width as usize, rect.height as usize);
+        let pixel_len = width * height * bpp;
+        let pixels = rect.data[..pixel_len]
+            .chunks_exact(bpp)
+            .map(|pixel| self.colour(pixel, format))
+            .collect();
+        let mask_stride = width.div_ceil(8);
+        let mask_data = &rect.data[pixel_len..];
+        let mask = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                mask_data[y * mask_stride + x / 8] & (0x80 >> (x % 8)) != 0
+            })
+            .collect();
+        self.cursor = Some(Cursor {
+            hotspot_x: rect.x,
+            hotspot_y: rect.y,
+            width: rect.width,
+            height: rect.height,
+            pixels,
+            mask,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+
+    fn rectangle(
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        encoding: Encoding,
+        data: &[u8],
+    ) -> Rectangle {
+        Rectangle {
+            x,
+            y,
+            width,
+            height,
+            encoding: encoding as i32,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_raw_and_copy_rect() {
+        let format = Session::PREFERRED_PIXEL_FORMAT;
+        let mut canvas = Canvas::new(3, 2);
+        let raw = [0x11, 0x22, 0x33, 0, 0x44, 0x55, 0x66, 0];
+        let change = canvas
+            .apply(&rectangle(0, 0, 2, 1, Encoding::Raw, &raw), &format)
+            .unwrap();
+        assert_eq!(change, Change::Damaged(Rect::new(0, 0, 2, 1)));
+        assert_eq!(canvas.pixels(), [0x332211, 0x665544, 0, 0, 0, 0]);
+
+        canvas
+            .apply(
+                &rectangle(1, 1, 2, 1, Encoding::CopyRect, &[0, 0, 0, 0]),
+                &format,
+            )
+            .unwrap();
+        assert_eq!(
+            canvas.pixels(),
+            [0x332211, 0x665544, 0, 0, 0x332211, 0x665544]
+        );
+
+        canvas
+            .apply(
+                &rectangle(1, 0, 2, 2, Encoding::CopyRect, &[0, 0, 0, 0]),
+                &format,
+            )
+            .unwrap();
+        assert_eq!(
+            canvas.pixels(),
+            [0x332211, 0x332211, 0x665544, 0, 0, 0x332211]
+        );
+    }
+
+    #[test]
+    fn test_rre() {
+        let format = Session::PREFERRED_PIXEL_FORMAT;
+        let mut canvas = Canvas::new(4, 4);
+        let mut data = vec![0, 0, 0, 1, 0x01, 0, 0, 0];
+        data.extend_from_slice(&[0x02, 0, 0, 0, 0, 1, 0, 1, 0, 2, 0, 9]);
+        canvas
+            .apply(&rectangle(0, 0, 4, 4, Encoding::RRE, &data), &format)
+            .unwrap();
+        assert_eq!(canvas.pixel(0, 0), 0x01);
+        assert_eq!(canvas.pixel(1, 1), 0x02);
+        assert_eq!(canvas.pixel(2, 3), 0x02);
+        assert_eq!(canvas.pixel(3, 1), 0x01);
+    }
+
+    #[test]
+    fn test_hextile() {
+        let format = Session::PREFERRED_PIXEL_FORMAT;
+        let mut canvas = Canvas::new(17, 2);
+        let mut data = vec![
+            HEXTILE_BACKGROUND_SPECIFIED | HEXTILE_FOREGROUND_SPECIFIED | HEXTILE_ANY_SUBRECTS,
+        ];
+        data.extend_from_slice(&[0x01, 0, 0, 0]);
+        data.extend_from_slice(&[0x02, 0, 0, 0]);
+        data.extend_from_slice(&[1, 0x21, 0x00]);
+        data.push(HEXTILE_RAW);
+        data.extend_from_slice(&[0x03, 0, 0, 0, 0x04, 0, 0, 0]);
+        canvas
+            .apply(&rectangle(0, 0, 17, 2, Encoding::Hextile, &data), &format)
+            .unwrap();
+        assert_eq!(canvas.pixel(0, 0), 0x01);
+        assert_eq!(canvas.pixel(2, 1), 0x02);
+        assert_eq!(canvas.pixel(3, 1), 0x01);
+        assert_eq!(canvas.pixel(16, 0), 0x03);
+        assert_eq!(canvas.pixel(16, 1), 0x04);
+    }
+
+    #[test]
+    fn test_pseudo_encodings() {
+        let format = Session::PREFERRED_PIXEL_FORMAT;
+        let mut canvas = Canvas::new(2, 2);
+        let change = canvas
+            .apply(
+                &rectangle(0, 0, 8, 4, Encoding::DesktopSizePseudo, &[]),
+                &format,
+            )
+            .unwrap();
+        assert_eq!(change, Change::Resized(8, 4));
+        assert_eq!(canvas.pixels().len(), 32);
+
+        let mut cursor = vec![0xff; 2 * 2 * 4];
+        cursor.extend_from_slice(&[0b1000_0000, 0b0100_0000]);
+        let change = canvas
+            .apply(
+                &rectangle(1, 0, 2, 2, Encoding::CursorPseudo, &cursor),
+                &format,
+            )
+            .unwrap();
+        assert_eq!(change, Change::CursorChanged);
+        let cursor = canvas.cursor.as_ref().unwrap();
+        assert_eq!((cursor.hotspot_x, cursor.hotspot_y), (1, 0));
+        assert_eq!(cursor.pixels, [0xffffff; 4]);
+        assert_eq!(cursor.mask, [true, false, false, true]);
+
+        let change = canvas
+            .apply(
+                &rectangle(5, 3, 0, 0, Encoding::PointerPosPseudo, &[]),
+                &format,
+            )
+            .unwrap();
+        assert_eq!(change, Change::CursorMoved(5, 3));
+
+        let change = canvas
+            .apply(
+                &rectangle(0, 0, 4, 4, Encoding::QemuExtendedKeyEventPseudo, &[]),
+                &format,
+            )
+            .unwrap();
+        assert_eq!(change, Change::Unchanged);
+    }
+
+    #[test]
+    fn test_rect_clip_and_offset() {
+        assert_eq!(Rect::new(2, 2, 10, 10).clip(5, 4), Rect::new(2, 2, 3, 2));
+        assert!(Rect::new(6, 0, 1, 1).clip(5, 4).is_empty());
+        assert_eq!(
+            Rect::new(1, 2, 3, 4).offset(10, 20),
+            Rect::new(11, 22, 3, 4)
+        );
+        assert_eq!(
+            Rect::new(1, 0, 1, 1).offset(u16::MAX, 0),
+            Rect::new(u16::MAX, 0, 1, 1)
+        );
+    }
+
+    #[test]
+    fn test_hostile_rectangles() {
+        let format = Session::PREFERRED_PIXEL_FORMAT;
+        let mut canvas = Canvas::new(4, 4);
+        // Subrectangles and tiles of a rectangle reaching past 65535.
+        let mut rre = vec![0, 0, 0, 1, 0x01, 0, 0, 0];
+        rre.extend_from_slice(&[0x02, 0, 0, 0, 0, 8, 0, 0, 0, 1, 0, 1]);
+        let change = canvas
+            .apply(&rectangle(65530, 0, 16, 1, Encoding::RRE, &rre), &format)
+            .unwrap();
+        assert!(matches!(change, Change::Damaged(area) if area.is_empty()));
+        let hextile = [HEXTILE_BACKGROUND_SPECIFIED, 0x01, 0, 0, 0, 0];
+        canvas
+            .apply(
+                &rectangle(65530, 0, 17, 1, Encoding::Hextile, &hextile),
+                &format,
+            )
+            .unwrap();
+        assert_eq!(canvas.pixels(), [0; 16]);
+
+        for format in [
+            PixelFormat {
+                bits_per_pixel: 0,
+                ..format.clone()
+            },
+            PixelFormat {
+                red_shift: 32,
+                ..format.clone()
+            },
+        ] {
+            assert_eq!(
+                canvas.apply(&rectangle(0, 0, 1, 1, Encoding::Raw, &[]), &format),
+                Err(DecodeError::UnsupportedPixelFormat)
+            );
+        }
+    }
+}
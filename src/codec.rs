@@ -0,0 +1,626 @@
+/*
+ * Sans-IO codec for the RFB messages exchanged after the handshake.
+ *
+ * Decoding works on whatever bytes are buffered so far. If a complete
+ * message is not available yet, `DecodeError::Incomplete` is returned with
+ * the number of bytes that must at least be buffered before decoding can
+ * get further, and the caller should read that much and try again with the
+ * same buffer.
+ * On success the number of consumed bytes is returned along with the
+ * message.
+ */
+
+use crate::rfb::{Encoding, PixelFormat};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientMessage {
+    SetPixelFormat(PixelFormat),
+    SetEncodings(Vec<i32>),
+    FramebufferUpdateRequest {
+        incremental: bool,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    },
+    KeyEvent {
+        down: bool,
+        key: u32,
+    },
+    PointerEvent {
+        button_mask: u8,
+        x: u16,
+        y: u16,
+    },
+    ClientCutText(Vec<u8>),
+    /**
+     * QEMU client message, subtype 0. `keycode` is the XT scancode, with
+     * 0xe0 prefixed scancodes sent as 0x80 | scancode.
+     */
+    QemuExtendedKeyEvent {
+        down: bool,
+        keysym: u32,
+        keycode: u32,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerMessage {
+    FramebufferUpdate(Vec<Rectangle>),
+    SetColourMapEntries {
+        first_colour: u16,
+        colours: Vec<[u16; 3]>,
+    },
+    Bell,
+    ServerCutText(Vec<u8>),
+}
+
+/**
+ * A rectangle of a FramebufferUpdate. `data` holds the encoding specific
+ * payload exactly as it was sent on the wire.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rectangle {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    pub encoding: i32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /** At least this many bytes are needed, counting from the start. */
+    Incomplete(usize),
+    UnknownMessageType(u8),
+    UnsupportedEncoding(i32),
+    UnsupportedPixelFormat,
+    /** A rectangle's payload length does not fit in `usize`. */
+    TooLarge,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::Incomplete(len) => write!(f, "incomplete message, {} bytes needed", len),
+            DecodeError::UnknownMessageType(kind) => write!(f, "unknown message type {}", kind),
+            DecodeError::UnsupportedEncoding(encoding) => {
+                write!(f, "unsupported encoding {}", encoding)
+            }
+            DecodeError::UnsupportedPixelFormat => write!(f, "unsupported pixel format"),
+            DecodeError::TooLarge => write!(f, "rectangle too large"),
+        }
+    }
+}
+
+/**
+ * The product of `factors` plus `extra`, or `TooLarge` if that overflows.
+ */
+fn length(factors: &[usize], extra: usize) -> Result<usize, DecodeError> {
+    factors
+        .iter()
+        .try_fold(1usize, |acc, &n| acc.checked_mul(n))
+        .and_then(|n| n.checked_add(extra))
+        .ok_or(DecodeError::TooLarge)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.buf.len() - self.pos < len {
+            return Err(DecodeError::Incomplete(self.pos.saturating_add(len)));
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), DecodeError> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn i32(&mut self) -> Result<i32, DecodeError> {
+        Ok(self.u32()? as i32)
+    }
+}
+
+impl ClientMessage {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            ClientMessage::SetPixelFormat(format) => {
+                out.extend_from_slice(&[0u8, 0, 0, 0]);
+                out.extend_from_slice(&<[u8; 16]>::from(format));
+            }
+            ClientMessage::SetEncodings(encodings) => {
+                out.extend_from_slice(&[2u8, 0]);
+                out.extend_from_slice(&(encodings.len() as u16).to_be_bytes());
+                for encoding in encodings {
+                    out.extend_from_slice(&encoding.to_be_bytes());
+                }
+            }
+            ClientMessage::FramebufferUpdateRequest {
+                incremental,
+                x,
+                y,
+                width,
+                height,
+            } => {
+                out.extend_from_slice(&[3u8, *incremental as u8]);
+                out.extend_from_slice(&x.to_be_bytes());
+                out.extend_from_slice(&y.to_be_bytes());
+                out.extend_from_slice(&width.to_be_bytes());
+                out.extend_from_slice(&height.to_be_bytes());
+            }
+            ClientMessage::KeyEvent { down, key } => {
+                out.extend_from_slice(&[4u8, *down as u8, 0, 0]);
+                out.extend_from_slice(&key.to_be_bytes());
+            }
+            ClientMessage::PointerEvent { button_mask, x, y } => {
+                out.extend_from_slice(&[5u8, *button_mask]);
+                out.extend_from_slice(&x.to_be_bytes());
+                out.extend_from_slice(&y.to_be_bytes());
+            }
+            ClientMessage::ClientCutText(text) => {
+                out.extend_from_slice(&[6u8, 0, 0, 0]);
+                out.extend_from_slice(&(text.len() as u32).to_be_bytes());
+                out.extend_from_slice(text);
+            }
+            ClientMessage::QemuExtendedKeyEvent {
+                down,
+                keysym,
+                keycode,
+            } => {
+                out.extend_from_slice(&[255u8, 0]);
+                out.extend_from_slice(&(*down as u16).to_be_bytes());
+                out.extend_from_slice(&keysym.to_be_bytes());
+                out.extend_from_slice(&keycode.to_be_bytes());
+            }
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let mut r = Reader::new(buf);
+        let msg = match r.u8()? {
+            0 => {
+                r.skip(3)?;
+                let mut format = [0u8; 16];
+                format.copy_from_slice(r.bytes(16)?);
+                ClientMessage::SetPixelFormat(format.into())
+            }
+            2 => {
+                r.skip(1)?;
+                let count = r.u16()?;
+                let mut encodings = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    encodings.push(r.i32()?);
+                }
+                ClientMessage::SetEncodings(encodings)
+            }
+            3 => ClientMessage::FramebufferUpdateRequest {
+                incremental: r.u8()? != 0,
+                x: r.u16()?,
+                y: r.u16()?,
+                width: r.u16()?,
+                height: r.u16()?,
+            },
+            4 => {
+                let down = r.u8()? != 0;
+                r.skip(2)?;
+                ClientMessage::KeyEvent {
+                    down,
+                    key: r.u32()?,
+                }
+            }
+            5 => ClientMessage::PointerEvent {
+                button_mask: r.u8()?,
+                x: r.u16()?,
+                y: r.u16()?,
+            },
+            6 => {
+                r.skip(3)?;
+                let len = r.u32()? as usize;
+                ClientMessage::ClientCutText(r.bytes(len)?.to_vec())
+            }
+            255 => match r.u8()? {
+                0 => ClientMessage::QemuExtendedKeyEvent {
+                    down: r.u16()? != 0,
+                    keysym: r.u32()?,
+                    keycode: r.u32()?,
+                },
+                _ => return Err(DecodeError::UnknownMessageType(255)),
+            },
+            other => return Err(DecodeError::UnknownMessageType(other)),
+        };
+        Ok((msg, r.pos))
+    }
+}
+
+impl ServerMessage {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            ServerMessage::FramebufferUpdate(rects) => {
+                out.extend_from_slice(&[0u8, 0]);
+                out.extend_from_slice(&(rects.len() as u16).to_be_bytes());
+                for rect in rects {
+                    out.extend_from_slice(&rect.x.to_be_bytes());
+                    out.extend_from_slice(&rect.y.to_be_bytes());
+                    out.extend_from_slice(&rect.width.to_be_bytes());
+                    out.extend_from_slice(&rect.height.to_be_bytes());
+                    out.extend_from_slice(&rect.encoding.to_be_bytes());
+                    out.extend_from_slice(&rect.data);
+                }
+            }
+            ServerMessage::SetColourMapEntries {
+                first_colour,
+                colours,
+            } => {
+                out.extend_from_slice(&[1u8, 0]);
+                out.extend_from_slice(&first_colour.to_be_bytes());
+                out.extend_from_slice(&(colours.len() as u16).to_be_bytes());
+                for colour in colours {
+                    for component in colour {
+                        out.extend_from_slice(&component.to_be_bytes());
+                    }
+                }
+            }
+            ServerMessage::Bell => out.push(2u8),
+            ServerMessage::ServerCutText(text) => {
+                out.extend_from_slice(&[3u8, 0, 0, 0]);
+                out.extend_from_slice(&(text.len() as u32).to_be_bytes());
+                out.extend_from_slice(text);
+            }
+        }
+    }
+
+    /**
+     * Decode a server message. The pixel format is the one most recently
+     * set with SetPixelFormat, it determines the size of pixel data.
+     */
+    pub fn decode(buf: &[u8], format: &PixelFormat) -> Result<(Self, usize), DecodeError> {
+        let mut r = Reader::new(buf);
+        let msg = match r.u8()? {
+            0 => {
+                r.skip(1)?;
+                let count = r.u16()?;
+                let mut rects = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    rects.push(Self::decode_rectangle(&mut r, format)?);
+                }
+                ServerMessage::FramebufferUpdate(rects)
+            }
+            1 => {
+                r.skip(1)?;
+                let first_colour = r.u16()?;
+                let count = r.u16()?;
+                let mut colours = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    colours.push([r.u16()?, r.u16()?, r.u16()?]);
+                }
+                ServerMessage::SetColourMapEntries {
+                    first_colour,
+                    colours,
+                }
+            }
+            2 => ServerMessage::Bell,
+            3 => {
+                r.skip(3)?;
+                let len = r.u32()? as usize;
+                ServerMessage::ServerCutText(r.bytes(len)?.to_vec())
+            }
+            other => return Err(DecodeError::UnknownMessageType(other)),
+        };
+        Ok((msg, r.pos))
+    }
+
+    fn decode_rectangle(r: &mut Reader, format: &PixelFormat) -> Result<Rectangle, DecodeError> {
+        let x = r.u16()?;
+        let y = r.u16()?;
+        let width = r.u16()?;
+        let height = r.u16()?;
+        let encoding = r.i32()?;
+        let start = r.pos;
+        Self::skip_rectangle_data(r, encoding, width as usize, height as usize, format)?;
+        Ok(Rectangle {
+            x,
+            y,
+            width,
+            height,
+            encoding,
+            data: r.buf[start..r.pos].to_vec(),
+        })
+    }
+
+    fn skip_rectangle_data(
+        r: &mut Reader,
+        encoding: i32,
+        width: usize,
+        height: usize,
+        format: &PixelFormat,
+    ) -> Result<(), DecodeError> {
+        let bpp = format.bytes_per_pixel();
+        match encoding {
+            e if e == Encoding::Raw as i32 => r.skip(length(&[width, height, bpp], 0)?),
+            e if e == Encoding::CopyRect as i32 => r.skip(4),
+            e if e == Encoding::RRE as i32 => {
+                let subrects = r.u32()? as usize;
+                r.skip(length(&[subrects, bpp + 8], bpp)?)
+            }
+            e if e == Encoding::Hextile as i32 => Self::skip_hextile(r, width, height, bpp),
+            e if e == Encoding::ZRLE as i32 => {
+                let len = r.u32()? as usize;
+                r.skip(len)
+            }
+            e if e == Encoding::CursorPseudo as i32 => {
+                let mask = length(&[width.div_ceil(8), height], 0)?;
+                r.skip(length(&[width, height, bpp], mask)?)
+            }
+            e if e == Encoding::DesktopSizePseudo as i32 => Ok(()),
+            e if e == Encoding::PointerPosPseudo as i32 => Ok(()),
+            e if e == Encoding::QemuExtendedKeyEventPseudo as i32 => Ok(()),
+            other => Err(DecodeError::UnsupportedEncoding(other)),
+        }
+    }
+
+    fn skip_hextile(
+        r: &mut Reader,
+        width: usize,
+        height: usize,
+        bpp: usize,
+    ) -> Result<(), DecodeError> {
+        for tile_y in (0..height).step_by(16) {
+            for tile_x in (0..width).step_by(16) {
+                let tile_w = std::cmp::min(16, width - tile_x);
+                let tile_h = std::cmp::min(16, height - tile_y);
+                let subencoding = r.u8()?;
+                if subencoding & HEXTILE_RAW != 0 {
+                    r.skip(length(&[tile_w, tile_h, bpp], 0)?)?;
+                    continue;
+                }
+                if subencoding & HEXTILE_BACKGROUND_SPECIFIED != 0 {
+                    r.skip(bpp)?;
+                }
+                if subencoding & HEXTILE_FOREGROUND_SPECIFIED != 0 {
+                    r.skip(bpp)?;
+                }
+                if subencoding & HEXTILE_ANY_SUBRECTS != 0 {
+                    let count = r.u8()? as usize;
+                    if subencoding & HEXTILE_SUBRECTS_COLOURED != 0 {
+                        r.skip(length(&[count, bpp + 2], 0)?)?;
+                    } else {
+                        r.skip(count * 2)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub const HEXTILE_RAW: u8 = 1;
+pub const HEXTILE_BACKGROUND_SPECIFIED: u8 = 2;
+pub const HEXTILE_FOREGROUND_SPECIFIED: u8 = 4;
+pub const HEXTILE_ANY_SUBRECTS: u8 = 8;
+pub const HEXTILE_SUBRECTS_COLOURED: u8 = 16;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian_flag: 0,
+        true_color_flag: 1,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    fn assert_incomplete_prefixes(
+        encoded: &[u8],
+        decode: impl Fn(&[u8]) -> Result<usize, DecodeError>,
+    ) {
+        for len in 0..encoded.len() {
+            match decode(&encoded[..len]) {
+                Err(DecodeError::Incomplete(needed)) => {
+                    assert!(needed > len && needed <= encoded.len(), "prefix {}", len)
+                }
+                other => panic!("prefix {}: unexpected result {:?}", len, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_client_message_roundtrip() {
+        let messages = [
+            ClientMessage::SetPixelFormat(FORMAT),
+            ClientMessage::SetEncodings(vec![0, 1, -239]),
+            ClientMessage::FramebufferUpdateRequest {
+                incremental: true,
+                x: 1,
+                y: 2,
+                width: 300,
+                height: 400,
+            },
+            ClientMessage::KeyEvent {
+                down: true,
+                key: 0xff0d,
+            },
+            ClientMessage::PointerEvent {
+                button_mask: 0b1000_0001,
+                x: 640,
+                y: 480,
+            },
+            ClientMessage::ClientCutText(b"hello".to_vec()),
+            ClientMessage::QemuExtendedKeyEvent {
+                down: true,
+                keysym: 0xffe9,
+                keycode: 0x38,
+            },
+        ];
+        for msg in messages {
+            let mut encoded = Vec::new();
+            msg.encode(&mut encoded);
+            encoded.extend_from_slice(b"trailing");
+            let (decoded, len) = ClientMessage::decode(&encoded).unwrap();
+            assert_eq!(decoded, msg);
+            assert_eq!(len, encoded.len() - b"trailing".len());
+            assert_incomplete_prefixes(&encoded[..len], |buf| {
+                ClientMessage::decode(buf).map(|(_, len)| len)
+            });
+        }
+    }
+
+    #[test]
+    fn test_framebuffer_update_request_encoding() {
+        let mut encoded = Vec::new();
+        ClientMessage::FramebufferUpdateRequest {
+            incremental: false,
+            x: 0x0102,
+            y: 0x0304,
+            width: 0x0506,
+            height: 0x0708,
+        }
+        .encode(&mut encoded);
+        assert_eq!(encoded, [3, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_server_message_roundtrip() {
+        let mut hextile =
+            vec![HEXTILE_BACKGROUND_SPECIFIED | HEXTILE_ANY_SUBRECTS | HEXTILE_SUBRECTS_COLOURED];
+        hextile.extend_from_slice(&[1, 2, 3, 0]);
+        hextile.push(1);
+        hextile.extend_from_slice(&[4, 5, 6, 0, 0x00, 0x11]);
+        hextile.push(HEXTILE_RAW);
+        hextile.extend_from_slice(&[0; 4]);
+        let messages = [
+            ServerMessage::FramebufferUpdate(vec![
+                Rectangle {
+                    x: 0,
+                    y: 0,
+                    width: 2,
+                    height: 1,
+                    encoding: Encoding::Raw as i32,
+                    data: vec![1, 2, 3, 0, 4, 5, 6, 0],
+                },
+                Rectangle {
+                    x: 5,
+                    y: 5,
+                    width: 2,
+                    height: 2,
+                    encoding: Encoding::CopyRect as i32,
+                    data: vec![0, 1, 0, 2],
+                },
+                Rectangle {
+                    x: 0,
+                    y: 0,
+                    width: 4,
+                    height: 4,
+                    encoding: Encoding::RRE as i32,
+                    data: vec![0, 0, 0, 1, 9, 9, 9, 0, 7, 7, 7, 0, 0, 1, 0, 1, 0, 2, 0, 2],
+                },
+                Rectangle {
+                    x: 0,
+                    y: 0,
+                    width: 17,
+                    height: 1,
+                    encoding: Encoding::Hextile as i32,
+                    data: hextile,
+                },
+                Rectangle {
+                    x: 0,
+                    y: 0,
+                    width: 1024,
+                    height: 768,
+                    encoding: Encoding::DesktopSizePseudo as i32,
+                    data: vec![],
+                },
+            ]),
+            ServerMessage::SetColourMapEntries {
+                first_colour: 3,
+                colours: vec![[0xffff, 0, 0x8000]],
+            },
+            ServerMessage::Bell,
+            ServerMessage::ServerCutText(b"clipboard".to_vec()),
+        ];
+        for msg in messages {
+            let mut encoded = Vec::new();
+            msg.encode(&mut encoded);
+            let (decoded, len) = ServerMessage::decode(&encoded, &FORMAT).unwrap();
+            assert_eq!(decoded, msg);
+            assert_eq!(len, encoded.len());
+            assert_incomplete_prefixes(&encoded, |buf| {
+                ServerMessage::decode(buf, &FORMAT).map(|(_, len)| len)
+            });
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(
+            ServerMessage::decode(&[42], &FORMAT),
+            Err(DecodeError::UnknownMessageType(42))
+        );
+        assert_eq!(
+            ClientMessage::decode(&[1]),
+            Err(DecodeError::UnknownMessageType(1))
+        );
+        assert_eq!(
+            ClientMessage::decode(&[255, 1]),
+            Err(DecodeError::UnknownMessageType(255))
+        );
+        let trle = [0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 15];
+        assert_eq!(
+            ServerMessage::decode(&trle, &FORMAT),
+            Err(DecodeError::UnsupportedEncoding(15))
+        );
+    }
+
+    #[test]
+    fn test_incomplete_length() {
+        // A 100x100 raw rectangle needs all of its pixels at once.
+        let header = [0, 0, 0, 1, 0, 0, 0, 0, 0, 100, 0, 100, 0, 0, 0, 0];
+        assert_eq!(
+            ServerMessage::decode(&header, &FORMAT),
+            Err(DecodeError::Incomplete(16 + 100 * 100 * 4))
+        );
+        assert_eq!(
+            ServerMessage::decode(&header[..5], &FORMAT),
+            Err(DecodeError::Incomplete(6))
+        );
+    }
+
+    #[test]
+    fn test_length_overflow() {
+        assert_eq!(length(&[16, 16, 4], 2), Ok(1026));
+        assert_eq!(length(&[usize::MAX, 2], 0), Err(DecodeError::TooLarge));
+        assert_eq!(length(&[usize::MAX], 1), Err(DecodeError::TooLarge));
+    }
+}
@@ -0,0 +1,423 @@
+/*
+ * Dead key composition.
+ *
+ * The table is generated from the Unicode character database: every
+ * precomposed Latin letter that decomposes into a base letter and one of
+ * the accents below.
+ */
+
+use crate::keysym;
+
+/**
+ * Dead keysyms with their combining and spacing accent.
+ */
+const DEAD_KEYS: &[(u32, char, char)] = &[
+    (0xfe50, '\u{300}', '\u{60}'),
+    (0xfe51, '\u{301}', '\u{b4}'),
+    (0xfe52, '\u{302}', '\u{5e}'),
+    (0xfe53, '\u{303}', '\u{7e}'),
+    (0xfe54, '\u{304}', '\u{af}'),
+    (0xfe55, '\u{306}', '\u{2d8}'),
+    (0xfe56, '\u{307}', '\u{2d9}'),
+    (0xfe57, '\u{308}', '\u{a8}'),
+    (0xfe58, '\u{30a}', '\u{2da}'),
+    (0xfe59, '\u{30b}', '\u{2dd}'),
+    (0xfe5a, '\u{30c}', '\u{2c7}'),
+    (0xfe5b, '\u{327}', '\u{b8}'),
+    (0xfe5c, '\u{328}', '\u{2db}'),
+];
+
+/**
+ * (combining accent, base, composed), sorted.
+ */
+const COMPOSED: &[(char, char, char)] = &[
+    ('\u{300}', 'A', '\u{c0}'),
+    ('\u{300}', 'E', '\u{c8}'),
+    ('\u{300}', 'I', '\u{cc}'),
+    ('\u{300}', 'N', '\u{1f8}'),
+    ('\u{300}', 'O', '\u{d2}'),
+    ('\u{300}', 'U', '\u{d9}'),
+    ('\u{300}', 'W', '\u{1e80}'),
+    ('\u{300}', 'Y', '\u{1ef2}'),
+    ('\u{300}', 'a', '\u{e0}'),
+    ('\u{300}', 'e', '\u{e8}'),
+    ('\u{300}', 'i', '\u{ec}'),
+    ('\u{300}', 'n', '\u{1f9}'),
+    ('\u{300}', 'o', '\u{f2}'),
+    ('\u{300}', 'u', '\u{f9}'),
+    ('\u{300}', 'w', '\u{1e81}'),
+    ('\u{300}', 'y', '\u{1ef3}'),
+    ('\u{300}', '\u{c2}', '\u{1ea6}'),
+    ('\u{300}', '\u{ca}', '\u{1ec0}'),
+    ('\u{300}', '\u{d4}', '\u{1ed2}'),
+    ('\u{300}', '\u{dc}', '\u{1db}'),
+    ('\u{300}', '\u{e2}', '\u{1ea7}'),
+    ('\u{300}', '\u{ea}', '\u{1ec1}'),
+    ('\u{300}', '\u{f4}', '\u{1ed3}'),
+    ('\u{300}', '\u{fc}', '\u{1dc}'),
+    ('\u{300}', '\u{102}', '\u{1eb0}'),
+    ('\u{300}', '\u{103}', '\u{1eb1}'),
+    ('\u{300}', '\u{112}', '\u{1e14}'),
+    ('\u{300}', '\u{113}', '\u{1e15}'),
+    ('\u{300}', '\u{14c}', '\u{1e50}'),
+    ('\u{300}', '\u{14d}', '\u{1e51}'),
+    ('\u{300}', '\u{1a0}', '\u{1edc}'),
+    ('\u{300}', '\u{1a1}', '\u{1edd}'),
+    ('\u{300}', '\u{1af}', '\u{1eea}'),
+    ('\u{300}', '\u{1b0}', '\u{1eeb}'),
+    ('\u{301}', 'A', '\u{c1}'),
+    ('\u{301}', 'C', '\u{106}'),
+    ('\u{301}', 'E', '\u{c9}'),
+    ('\u{301}', 'G', '\u{1f4}'),
+    ('\u{301}', 'I', '\u{cd}'),
+    ('\u{301}', 'K', '\u{1e30}'),
+    ('\u{301}', 'L', '\u{139}'),
+    ('\u{301}', 'M', '\u{1e3e}'),
+    ('\u{301}', 'N', '\u{143}'),
+    ('\u{301}', 'O', '\u{d3}'),
+    ('\u{301}', 'P', '\u{1e54}'),
+    ('\u{301}', 'R', '\u{154}'),
+    ('\u{301}', 'S', '\u{15a}'),
+    ('\u{301}', 'U', '\u{da}'),
+    ('\u{301}', 'W', '\u{1e82}'),
+    ('\u{301}', 'Y', '\u{dd}'),
+    ('\u{301}', 'Z', '\u{179}'),
+    ('\u{301}', 'a', '\u{e1}'),
+    ('\u{301}', 'c', '\u{107}'),
+    ('\u{301}', 'e', '\u{e9}'),
+    ('\u{301}', 'g', '\u{1f5}'),
+    ('\u{301}', 'i', '\u{ed}'),
+    ('\u{301}', 'k', '\u{1e31}'),
+    ('\u{301}', 'l', '\u{13a}'),
+    ('\u{301}', 'm', '\u{1e3f}'),
+    ('\u{301}', 'n', '\u{144}'),
+    ('\u{301}', 'o', '\u{f3}'),
+    ('\u{301}', 'p', '\u{1e55}'),
+    ('\u{301}', 'r', '\u{155}'),
+    ('\u{301}', 's', '\u{15b}'),
+    ('\u{301}', 'u', '\u{fa}'),
+    ('\u{301}', 'w', '\u{1e83}'),
+    ('\u{301}', 'y', '\u{fd}'),
+    ('\u{301}', 'z', '\u{17a}'),
+    ('\u{301}', '\u{c2}', '\u{1ea4}'),
+    ('\u{301}', '\u{c5}', '\u{1fa}'),
+    ('\u{301}', '\u{c6}', '\u{1fc}'),
+    ('\u{301}', '\u{c7}', '\u{1e08}'),
+    ('\u{301}', '\u{ca}', '\u{1ebe}'),
+    ('\u{301}', '\u{cf}', '\u{1e2e}'),
+    ('\u{301}', '\u{d4}', '\u{1ed0}'),
+    ('\u{301}', '\u{d5}', '\u{1e4c}'),
+    ('\u{301}', '\u{d8}', '\u{1fe}'),
+    ('\u{301}', '\u{dc}', '\u{1d7}'),
+    ('\u{301}', '\u{e2}', '\u{1ea5}'),
+    ('\u{301}', '\u{e5}', '\u{1fb}'),
+    ('\u{301}', '\u{e6}', '\u{1fd}'),
+    ('\u{301}', '\u{e7}', '\u{1e09}'),
+    ('\u{301}', '\u{ea}', '\u{1ebf}'),
+    ('\u{301}', '\u{ef}', '\u{1e2f}'),
+    ('\u{301}', '\u{f4}', '\u{1ed1}'),
+    ('\u{301}', '\u{f5}', '\u{1e4d}'),
+    ('\u{301}', '\u{f8}', '\u{1ff}'),
+    ('\u{301}', '\u{fc}', '\u{1d8}'),
+    ('\u{301}', '\u{102}', '\u{1eae}'),
+    ('\u{301}', '\u{103}', '\u{1eaf}'),
+    ('\u{301}', '\u{112}', '\u{1e16}'),
+    ('\u{301}', '\u{113}', '\u{1e17}'),
+    ('\u{301}', '\u{14c}', '\u{1e52}'),
+    ('\u{301}', '\u{14d}', '\u{1e53}'),
+    ('\u{301}', '\u{168}', '\u{1e78}'),
+    ('\u{301}', '\u{169}', '\u{1e79}'),
+    ('\u{301}', '\u{1a0}', '\u{1eda}'),
+    ('\u{301}', '\u{1a1}', '\u{1edb}'),
+    ('\u{301}', '\u{1af}', '\u{1ee8}'),
+    ('\u{301}', '\u{1b0}', '\u{1ee9}'),
+    ('\u{302}', 'A', '\u{c2}'),
+    ('\u{302}', 'C', '\u{108}'),
+    ('\u{302}', 'E', '\u{ca}'),
+    ('\u{302}', 'G', '\u{11c}'),
+    ('\u{302}', 'H', '\u{124}'),
+    ('\u{302}', 'I', '\u{ce}'),
+    ('\u{302}', 'J', '\u{134}'),
+    ('\u{302}', 'O', '\u{d4}'),
+    ('\u{302}', 'S', '\u{15c}'),
+    ('\u{302}', 'U', '\u{db}'),
+    ('\u{302}', 'W', '\u{174}'),
+    ('\u{302}', 'Y', '\u{176}'),
+    ('\u{302}', 'Z', '\u{1e90}'),
+    ('\u{302}', 'a', '\u{e2}'),
+    ('\u{302}', 'c', '\u{109}'),
+    ('\u{302}', 'e', '\u{ea}'),
+    ('\u{302}', 'g', '\u{11d}'),
+    ('\u{302}', 'h', '\u{125}'),
+    ('\u{302}', 'i', '\u{ee}'),
+    ('\u{302}', 'j', '\u{135}'),
+    ('\u{302}', 'o', '\u{f4}'),
+    ('\u{302}', 's', '\u{15d}'),
+    ('\u{302}', 'u', '\u{fb}'),
+    ('\u{302}', 'w', '\u{175}'),
+    ('\u{302}', 'y', '\u{177}'),
+    ('\u{302}', 'z', '\u{1e91}'),
+    ('\u{302}', '\u{1ea0}', '\u{1eac}'),
+    ('\u{302}', '\u{1ea1}', '\u{1ead}'),
+    ('\u{302}', '\u{1eb8}', '\u{1ec6}'),
+    ('\u{302}', '\u{1eb9}', '\u{1ec7}'),
+    ('\u{302}', '\u{1ecc}', '\u{1ed8}'),
+    ('\u{302}', '\u{1ecd}', '\u{1ed9}'),
+    ('\u{303}', 'A', '\u{c3}'),
+    ('\u{303}', 'E', '\u{1ebc}'),
+    ('\u{303}', 'I', '\u{128}'),
+    ('\u{303}', 'N', '\u{d1}'),
+    ('\u{303}', 'O', '\u{d5}'),
+    ('\u{303}', 'U', '\u{168}'),
+    ('\u{303}', 'V', '\u{1e7c}'),
+    ('\u{303}', 'Y', '\u{1ef8}'),
+    ('\u{303}', 'a', '\u{e3}'),
+    ('\u{303}', 'e', '\u{1ebd}'),
+    ('\u{303}', 'i', '\u{129}'),
+    ('\u{303}', 'n', '\u{f1}'),
+    ('\u{303}', 'o', '\u{f5}'),
+    ('\u{303}', 'u', '\u{169}'),
+    ('\u{303}', 'v', '\u{1e7d}'),
+    ('\u{303}', 'y', '\u{1ef9}'),
+    ('\u{303}', '\u{c2}', '\u{1eaa}'),
+    ('\u{303}', '\u{ca}', '\u{1ec4}'),
+    ('\u{303}', '\u{d4}', '\u{1ed6}'),
+    ('\u{303}', '\u{e2}', '\u{1eab}'),
+    ('\u{303}', '\u{ea}', '\u{1ec5}'),
+    ('\u{303}', '\u{f4}', '\u{1ed7}'),
+    ('\u{303}', '\u{102}', '\u{1eb4}'),
+    ('\u{303}', '\u{103}', '\u{1eb5}'),
+    ('\u{303}', '\u{1a0}', '\u{1ee0}'),
+    ('\u{303}', '\u{1a1}', '\u{1ee1}'),
+    ('\u{303}', '\u{1af}', '\u{1eee}'),
+    ('\u{303}', '\u{1b0}', '\u{1eef}'),
+    ('\u{304}', 'A', '\u{100}'),
+    ('\u{304}', 'E', '\u{112}'),
+    ('\u{304}', 'G', '\u{1e20}'),
+    ('\u{304}', 'I', '\u{12a}'),
+    ('\u{304}', 'O', '\u{14c}'),
+    ('\u{304}', 'U', '\u{16a}'),
+    ('\u{304}', 'Y', '\u{232}'),
+    ('\u{304}', 'a', '\u{101}'),
+    ('\u{304}', 'e', '\u{113}'),
+    ('\u{304}', 'g', '\u{1e21}'),
+    ('\u{304}', 'i', '\u{12b}'),
+    ('\u{304}', 'o', '\u{14d}'),
+    ('\u{304}', 'u', '\u{16b}'),
+    ('\u{304}', 'y', '\u{233}'),
+    ('\u{304}', '\u{c4}', '\u{1de}'),
+    ('\u{304}', '\u{c6}', '\u{1e2}'),
+    ('\u{304}', '\u{d5}', '\u{22c}'),
+    ('\u{304}', '\u{d6}', '\u{22a}'),
+    ('\u{304}', '\u{dc}', '\u{1d5}'),
+    ('\u{304}', '\u{e4}', '\u{1df}'),
+    ('\u{304}', '\u{e6}', '\u{1e3}'),
+    ('\u{304}', '\u{f5}', '\u{22d}'),
+    ('\u{304}', '\u{f6}', '\u{22b}'),
+    ('\u{304}', '\u{fc}', '\u{1d6}'),
+    ('\u{304}', '\u{1ea}', '\u{1ec}'),
+    ('\u{304}', '\u{1eb}', '\u{1ed}'),
+    ('\u{304}', '\u{226}', '\u{1e0}'),
+    ('\u{304}', '\u{227}', '\u{1e1}'),
+    ('\u{304}', '\u{22e}', '\u{230}'),
+    ('\u{304}', '\u{22f}', '\u{231}'),
+    ('\u{304}', '\u{1e36}', '\u{1e38}'),
+    ('\u{304}', '\u{1e37}', '\u{1e39}'),
+    ('\u{304}', '\u{1e5a}', '\u{1e5c}'),
+    ('\u{304}', '\u{1e5b}', '\u{1e5d}'),
+    ('\u{306}', 'A', '\u{102}'),
+    ('\u{306}', 'E', '\u{114}'),
+    ('\u{306}', 'G', '\u{11e}'),
+    ('\u{306}', 'I', '\u{12c}'),
+    ('\u{306}', 'O', '\u{14e}'),
+    ('\u{306}', 'U', '\u{16c}'),
+    ('\u{306}', 'a', '\u{103}'),
+    ('\u{306}', 'e', '\u{115}'),
+    ('\u{306}', 'g', '\u{11f}'),
+    ('\u{306}', 'i', '\u{12d}'),
+    ('\u{306}', 'o', '\u{14f}'),
+    ('\u{306}', 'u', '\u{16d}'),
+    ('\u{306}', '\u{228}', '\u{1e1c}'),
+    ('\u{306}', '\u{229}', '\u{1e1d}'),
+    ('\u{306}', '\u{1ea0}', '\u{1eb6}'),
+    ('\u{306}', '\u{1ea1}', '\u{1eb7}'),
+    ('\u{307}', 'A', '\u{226}'),
+    ('\u{307}', 'B', '\u{1e02}'),
+    ('\u{307}', 'C', '\u{10a}'),
+    ('\u{307}', 'D', '\u{1e0a}'),
+    ('\u{307}', 'E', '\u{116}'),
+    ('\u{307}', 'F', '\u{1e1e}'),
+    ('\u{307}', 'G', '\u{120}'),
+    ('\u{307}', 'H', '\u{1e22}'),
+    ('\u{307}', 'I', '\u{130}'),
+    ('\u{307}', 'M', '\u{1e40}'),
+    ('\u{307}', 'N', '\u{1e44}'),
+    ('\u{307}', 'O', '\u{22e}'),
+    ('\u{307}', 'P', '\u{1e56}'),
+    ('\u{307}', 'R', '\u{1e58}'),
+    ('\u{307}', 'S', '\u{1e60}'),
+    ('\u{307}', 'T', '\u{1e6a}'),
+    ('\u{307}', 'W', '\u{1e86}'),
+    ('\u{307}', 'X', '\u{1e8a}'),
+    ('\u{307}', 'Y', '\u{1e8e}'),
+    ('\u{307}', 'Z', '\u{17b}'),
+    ('\u{307}', 'a', '\u{227}'),
+    ('\u{307}', 'b', '\u{1e03}'),
+    ('\u{307}', 'c', '\u{10b}'),
+    ('\u{307}', 'd', '\u{1e0b}'),
+    ('\u{307}', 'e', '\u{117}'),
+    ('\u{307}', 'f', '\u{1e1f}'),
+    ('\u{307}', 'g', '\u{121}'),
+    ('\u{307}', 'h', '\u{1e23}'),
+    ('\u{307}', 'm', '\u{1e41}'),
+    ('\u{307}', 'n', '\u{1e45}'),
+    ('\u{307}', 'o', '\u{22f}'),
+    ('\u{307}', 'p', '\u{1e57}'),
+    ('\u{307}', 'r', '\u{1e59}'),
+    ('\u{307}', 's', '\u{1e61}'),
+    ('\u{307}', 't', '\u{1e6b}'),
+    ('\u{307}', 'w', '\u{1e87}'),
+    ('\u{307}', 'x', '\u{1e8b}'),
+    ('\u{307}', 'y', '\u{1e8f}'),
+    ('\u{307}', 'z', '\u{17c}'),
+    ('\u{307}', '\u{15a}', '\u{1e64}'),
+    ('\u{307}', '\u{15b}', '\u{1e65}'),
+    ('\u{307}', '\u{160}', '\u{1e66}'),
+    ('\u{307}', '\u{161}', '\u{1e67}'),
+    ('\u{307}', '\u{17f}', '\u{1e9b}'),
+    ('\u{307}', '\u{1e62}', '\u{1e68}'),
+    ('\u{307}', '\u{1e63}', '\u{1e69}'),
+    ('\u{308}', 'A', '\u{c4}'),
+    ('\u{308}', 'E', '\u{cb}'),
+    ('\u{308}', 'H', '\u{1e26}'),
+    ('\u{308}', 'I', '\u{cf}'),
+    ('\u{308}', 'O', '\u{d6}'),
+    ('\u{308}', 'U', '\u{dc}'),
+    ('\u{308}', 'W', '\u{1e84}'),
+    ('\u{308}', 'X', '\u{1e8c}'),
+    ('\u{308}', 'Y', '\u{178}'),
+    ('\u{308}', 'a', '\u{e4}'),
+    ('\u{308}', 'e', '\u{eb}'),
+    ('\u{308}', 'h', '\u{1e27}'),
+    ('\u{308}', 'i', '\u{ef}'),
+    ('\u{308}', 'o', '\u{f6}'),
+    ('\u{308}', 't', '\u{1e97}'),
+    ('\u{308}', 'u', '\u{fc}'),
+    ('\u{308}', 'w', '\u{1e85}'),
+    ('\u{308}', 'x', '\u{1e8d}'),
+    ('\u{308}', 'y', '\u{ff}'),
+    ('\u{308}', '\u{d5}', '\u{1e4e}'),
+    ('\u{308}', '\u{f5}', '\u{1e4f}'),
+    ('\u{308}', '\u{16a}', '\u{1e7a}'),
+    ('\u{308}', '\u{16b}', '\u{1e7b}'),
+    ('\u{30a}', 'A', '\u{c5}'),
+    ('\u{30a}', 'U', '\u{16e}'),
+    ('\u{30a}', 'a', '\u{e5}'),
+    ('\u{30a}', 'u', '\u{16f}'),
+    ('\u{30a}', 'w', '\u{1e98}'),
+    ('\u{30a}', 'y', '\u{1e99}'),
+    ('\u{30b}', 'O', '\u{150}'),
+    ('\u{30b}', 'U', '\u{170}'),
+    ('\u{30b}', 'o', '\u{151}'),
+    ('\u{30b}', 'u', '\u{171}'),
+    ('\u{30c}', 'A', '\u{1cd}'),
+    ('\u{30c}', 'C', '\u{10c}'),
+    ('\u{30c}', 'D', '\u{10e}'),
+    ('\u{30c}', 'E', '\u{11a}'),
+    ('\u{30c}', 'G', '\u{1e6}'),
+    ('\u{30c}', 'H', '\u{21e}'),
+    ('\u{30c}', 'I', '\u{1cf}'),
+    ('\u{30c}', 'K', '\u{1e8}'),
+    ('\u{30c}', 'L', '\u{13d}'),
+    ('\u{30c}', 'N', '\u{147}'),
+    ('\u{30c}', 'O', '\u{1d1}'),
+    ('\u{30c}', 'R', '\u{158}'),
+    ('\u{30c}', 'S', '\u{160}'),
+    ('\u{30c}', 'T', '\u{164}'),
+    ('\u{30c}', 'U', '\u{1d3}'),
+    ('\u{30c}', 'Z', '\u{17d}'),
+    ('\u{30c}', 'a', '\u{1ce}'),
+    ('\u{30c}', 'c', '\u{10d}'),
+    ('\u{30c}', 'd', '\u{10f}'),
+    ('\u{30c}', 'e', '\u{11b}'),
+    ('\u{30c}', 'g', '\u{1e7}'),
+    ('\u{30c}', 'h', '\u{21f}'),
+    ('\u{30c}', 'i', '\u{1d0}'),
+    ('\u{30c}', 'j', '\u{1f0}'),
+    ('\u{30c}', 'k', '\u{1e9}'),
+    ('\u{30c}', 'l', '\u{13e}'),
+    ('\u{30c}', 'n', '\u{148}'),
+    ('\u{30c}', 'o', '\u{1d2}'),
+    ('\u{30c}', 'r', '\u{159}'),
+    ('\u{30c}', 's', '\u{161}'),
+    ('\u{30c}', 't', '\u{165}'),
+    ('\u{30c}', 'u', '\u{1d4}'),
+    ('\u{30c}', 'z', '\u{17e}'),
+    ('\u{30c}', '\u{dc}', '\u{1d9}'),
+    ('\u{30c}', '\u{fc}', '\u{1da}'),
+    ('\u{30c}', '\u{1b7}', '\u{1ee}'),
+    ('\u{30c}', '\u{292}', '\u{1ef}'),
+    ('\u{327}', 'C', '\u{c7}'),
+    ('\u{327}', 'D', '\u{1e10}'),
+    ('\u{327}', 'E', '\u{228}'),
+    ('\u{327}', 'G', '\u{122}'),
+    ('\u{327}', 'H', '\u{1e28}'),
+    ('\u{327}', 'K', '\u{136}'),
+    ('\u{327}', 'L', '\u{13b}'),
+    ('\u{327}', 'N', '\u{145}'),
+    ('\u{327}', 'R', '\u{156}'),
+    ('\u{327}', 'S', '\u{15e}'),
+    ('\u{327}', 'T', '\u{162}'),
+    ('\u{327}', 'c', '\u{e7}'),
+    ('\u{327}', 'd', '\u{1e11}'),
+    ('\u{327}', 'e', '\u{229}'),
+    ('\u{327}', 'g', '\u{123}'),
+    ('\u{327}', 'h', '\u{1e29}'),
+    ('\u{327}', 'k', '\u{137}'),
+    ('\u{327}', 'l', '\u{13c}'),
+    ('\u{327}', 'n', '\u{146}'),
+    ('\u{327}', 'r', '\u{157}'),
+    ('\u{327}', 's', '\u{15f}'),
+    ('\u{327}', 't', '\u{163}'),
+    ('\u{328}', 'A', '\u{104}'),
+    ('\u{328}', 'E', '\u{118}'),
+    ('\u{328}', 'I', '\u{12e}'),
+    ('\u{328}', 'O', '\u{1ea}'),
+    ('\u{328}', 'U', '\u{172}'),
+    ('\u{328}', 'a', '\u{105}'),
+    ('\u{328}', 'e', '\u{119}'),
+    ('\u{328}', 'i', '\u{12f}'),
+    ('\u{328}', 'o', '\u{1eb}'),
+    ('\u{328}', 'u', '\u{173}'),
+];
+
+pub fn is_dead_key(keysym: u32) -> bool {
+    DEAD_KEYS.iter().any(|&(dead, _, _)| dead == keysym)
+}
+
+/**
+ * Keysym of the accent itself, typed when a dead key is followed by space
+ * or by a key it does not combine with.
+ */
+pub fn spacing_accent(dead: u32) -> Option<u32> {
+    DEAD_KEYS
+        .iter()
+        .find(|&&(k, _, _)| k == dead)
+        .and_then(|&(_, _, spacing)| keysym::from_char(spacing))
+}
+
+/**
+ * Keysym produced by the dead key `dead` followed by `keysym`, if they
+ * combine.
+ */
+pub fn compose(dead: u32, keysym: u32) -> Option<u32> {
+    let &(_, combining, _) = DEAD_KEYS.iter().find(|&&(k, _, _)| k == dead)?;
+    if keysym == dead || keysym == ' ' as u32 {
+        return spacing_accent(dead);
+    }
+    let base = keysym::to_char(keysym)?;
+    COMPOSED
+        .binary_search_by(|&(accent, b, _)| (accent, b).cmp(&(combining, base)))
+        .ok()
+        .and_then(|i| keysym::from_char(COMPOSED[i].2))
+}
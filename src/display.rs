@@ -0,0 +1,194 @@
+/*
+ * Outputs the runtime renders the canvas to.
+ *
+ * After every FramebufferUpdate the runtime hands the canvas and the
+ * damaged areas to its display. Besides the Linux framebuffer there is a
+ * headless display that shows nothing, for CI and for recording, one that
+ * writes every update as image file, and one that draws into the terminal.
+ */
+
+use crate::canvas::{Canvas, Rect};
+use crate::fbdev::{self, FbSettings, FramebufferDisplay};
+use crate::image::{self, ImageFormat};
+use crate::terminal::{TerminalDisplay, TerminalMode};
+use std::path::PathBuf;
+
+pub trait Display: Send {
+    /**
+     * Size of the local display, or None if it follows the remote desktop.
+     */
+    fn size(&self) -> Option<(u16, u16)>;
+
+    /**
+     * Show the damaged areas of the canvas.
+     */
+    fn update(&mut self, canvas: &Canvas, damage: &[Rect]) -> Result<(), std::io::Error>;
+
+    /**
+     * Show the whole canvas after the remote desktop was resized.
+     */
+    fn resized(&mut self, canvas: &Canvas) -> Result<(), std::io::Error> {
+        self.update(canvas, &[Rect::new(0, 0, canvas.width(), canvas.height())])
+    }
+
+    /**
+     * Show everything again after something else drew over the display.
+     */
+    fn redraw(&mut self, canvas: &Canvas) -> Result<(), std::io::Error> {
+        self.update(canvas, &[Rect::new(0, 0, canvas.width(), canvas.height())])
+    }
+}
+
+/**
+ * The display given on the command line.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum DisplaySpec {
+    #[default]
+    Headless,
+    Framebuffer(String),
+    Images(PathBuf, ImageFormat),
+    /** Sixel graphics or half blocks, detected if None. */
+    Terminal(Option<TerminalMode>),
+}
+
+#[derive(Debug, Default)]
+pub struct Headless;
+
+/**
+ * Writes the canvas after every update to numbered files in a directory.
+ */
+#[derive(Debug)]
+pub struct ImageDisplay {
+    dir: PathBuf,
+    format: ImageFormat,
+    frame: u32,
+}
+
+impl DisplaySpec {
+    /**
+     * `headless`, `fb` or `fb:DEVICE`, `ppm:DIR` or `png:DIR`, `term`,
+     * `term:blocks` or `term:sixel`.
+     */
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec.split_once(':') {
+            None if spec == "headless" => Ok(DisplaySpec::Headless),
+            None if spec == "fb" => Ok(DisplaySpec::Framebuffer(fbdev::DEFAULT_DEVICE.to_string())),
+            Some(("fb", device)) => Ok(DisplaySpec::Framebuffer(device.to_string())),
+            Some(("ppm", dir)) => Ok(DisplaySpec::Images(dir.into(), ImageFormat::Ppm)),
+            Some(("png", dir)) => Ok(DisplaySpec::Images(dir.into(), ImageFormat::Png)),
+            None if spec == "term" => Ok(DisplaySpec::Terminal(None)),
+            Some(("term", "blocks")) => Ok(DisplaySpec::Terminal(Some(TerminalMode::Blocks))),
+            Some(("term", "sixel")) => Ok(DisplaySpec::Terminal(Some(TerminalMode::Sixel))),
+            _ => Err(format!("invalid display {}", spec)),
+        }
+    }
+
+    /**
+     * Open the display, `settings` apply to the framebuffer only.
+     */
+    pub fn open(&self, settings: FbSettings) -> Result<Box<dyn Display>, std::io::Error> {
+        Ok(match self {
+            DisplaySpec::Headless => Box::new(Headless),
+            DisplaySpec::Framebuffer(device) => {
+                Box::new(FramebufferDisplay::open(device, settings)?)
+            }
+            DisplaySpec::Images(dir, format) => Box::new(ImageDisplay::new(dir.clone(), *format)?),
+            DisplaySpec::Terminal(mode) => Box::new(TerminalDisplay::open(*mode)?),
+        })
+    }
+
+    /**
+     * Whether the display takes over the terminal the viewer runs in.
+     */
+    pub fn uses_terminal(&self) -> bool {
+        matches!(self, DisplaySpec::Terminal(_))
+    }
+}
+
+impl Display for Headless {
+    fn size(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    fn update(&mut self, _canvas: &Canvas, _damage: &[Rect]) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl ImageDisplay {
+    pub fn new(dir: PathBuf, format: ImageFormat) -> Result<Self, std::io::Error> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            format,
+            frame: 0,
+        })
+    }
+
+    /**
+     * Path of the image of the given update, counting from 1.
+     */
+    pub fn path(&self, frame: u32) -> PathBuf {
+        self.dir
+            .join(format!("frame-{:06}.{}", frame, self.format.extension()))
+    }
+}
+
+impl Display for ImageDisplay {
+    fn size(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    fn update(&mut self, canvas: &Canvas, _damage: &[Rect]) -> Result<(), std::io::Error> {
+        self.frame += 1;
+        let file = std::fs::File::create(self.path(self.frame))?;
+        image::write(canvas, self.format, std::io::BufWriter::new(file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(DisplaySpec::parse("headless"), Ok(DisplaySpec::Headless));
+        assert_eq!(
+            DisplaySpec::parse("fb"),
+            Ok(DisplaySpec::Framebuffer("/dev/fb0".to_string()))
+        );
+        assert_eq!(
+            DisplaySpec::parse("fb:/dev/fb1"),
+            Ok(DisplaySpec::Framebuffer("/dev/fb1".to_string()))
+        );
+        assert_eq!(
+            DisplaySpec::parse("png:/tmp/frames"),
+            Ok(DisplaySpec::Images("/tmp/frames".into(), ImageFormat::Png))
+        );
+        assert_eq!(DisplaySpec::parse("term"), Ok(DisplaySpec::Terminal(None)));
+        assert_eq!(
+            DisplaySpec::parse("term:sixel"),
+            Ok(DisplaySpec::Terminal(Some(TerminalMode::Sixel)))
+        );
+        assert!(DisplaySpec::parse("term:ascii").is_err());
+        assert!(DisplaySpec::parse("x11").is_err());
+    }
+
+    #[test]
+    fn test_image_display() {
+        let dir = std::env::temp_dir().join(format!("vncvwr-display-{}", std::process::id()));
+        let mut display = DisplaySpec::Images(dir.clone(), ImageFormat::Ppm)
+            .open(FbSettings::default())
+            .unwrap();
+        assert_eq!(display.size(), None);
+        let canvas = Canvas::new(2, 1);
+        display.update(&canvas, &[Rect::new(0, 0, 1, 1)]).unwrap();
+        display.resized(&canvas).unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("frame-000002.ppm")).unwrap(),
+            b"P6\n2 1\n255\n\0\0\0\0\0\0"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,430 @@
+/*
+ * Linux evdev input devices (/dev/input/event*).
+ */
+
+use crate::calibrate::Calibration;
+use crate::gesture::{self, Gestures, Touches};
+use crate::keymap::Keyboard;
+use crate::player::{self, Control};
+use crate::pointer::{AbsAxes, AbsRange, Pointer};
+use crate::runtime::InputSender;
+use crate::viewport::{self, Viewport};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+
+pub const SYN_REPORT: u16 = 0;
+
+pub const KEY_A: u16 = 30;
+pub const KEY_UP: u16 = 103;
+pub const KEY_LEFT: u16 = 105;
+pub const KEY_RIGHT: u16 = 106;
+pub const KEY_DOWN: u16 = 108;
+pub const KEY_MAX: u16 = 0x2ff;
+
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+pub const BTN_TOUCH: u16 = 0x14a;
+pub const BTN_STYLUS: u16 = 0x14b;
+pub const BTN_STYLUS2: u16 = 0x14c;
+
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_HWHEEL: u16 = 0x06;
+pub const REL_WHEEL: u16 = 0x08;
+
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const ABS_MT_SLOT: u16 = 0x2f;
+pub const ABS_MT_POSITION_X: u16 = 0x35;
+pub const ABS_MT_POSITION_Y: u16 = 0x36;
+pub const ABS_MT_TRACKING_ID: u16 = 0x39;
+
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputEvent {
+    pub event_type: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+#[derive(Debug)]
+pub struct Device {
+    file: File,
+    path: PathBuf,
+}
+
+const fn ioc(dir: u32, nr: u32, size: usize) -> u32 {
+    (dir << 30) | ((size as u32) << 16) | ((b'E' as u32) << 8) | nr
+}
+
+const fn eviocgbit(event_type: u16, len: usize) -> u32 {
+    ioc(IOC_READ, 0x20 + event_type as u32, len)
+}
+
+const fn eviocgabs(axis: u16) -> u32 {
+    ioc(
+        IOC_READ,
+        0x40 + axis as u32,
+        std::mem::size_of::<libc::input_absinfo>(),
+    )
+}
+
+const EVIOCGNAME_256: u32 = ioc(IOC_READ, 0x06, 256);
+const EVIOCGRAB: u32 = ioc(IOC_WRITE, 0x90, std::mem::size_of::<libc::c_int>());
+
+impl Device {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            file: File::open(path.as_ref())?,
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+
+    /**
+     * All event devices, sorted by path.
+     */
+    pub fn list() -> Result<Vec<PathBuf>, std::io::Error> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir("/dev/input")?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("event"))
+            })
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /**
+     * The first device that looks like a keyboard, i.e. has letter keys.
+     * Devices that can't be opened or queried are skipped.
+     */
+    pub fn find_keyboard() -> Result<Option<Device>, std::io::Error> {
+        for path in Self::list()? {
+            if let Ok(device) = Self::open(&path) {
+                if device.has_key(KEY_A).unwrap_or(false) {
+                    return Ok(Some(device));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /**
+     * All devices that move a pointer: mice, touchscreens and tablets.
+     * Devices that can't be opened or queried are skipped.
+     */
+    pub fn find_pointers() -> Result<Vec<Device>, std::io::Error> {
+        let mut pointers = Vec::new();
+        for path in Self::list()? {
+            if let Ok(device) = Self::open(&path) {
+                let relative = device.has_event(EV_REL, REL_X).unwrap_or(false);
+                if relative || device.has_event(EV_ABS, ABS_X).unwrap_or(false) {
+                    pointers.push(device);
+                }
+            }
+        }
+        Ok(pointers)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn name(&self) -> Result<String, std::io::Error> {
+        let mut buf = [0u8; 256];
+        self.ioctl(EVIOCGNAME_256, buf.as_mut_ptr() as *mut libc::c_void)?;
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+
+    /**
+     * Event codes of the given type this device can report.
+     */
+    pub fn capabilities(&self, event_type: u16) -> Result<Vec<u8>, std::io::Error> {
+        let mut bits = vec![0u8; KEY_MAX as usize / 8 + 1];
+        self.ioctl(
+            eviocgbit(event_type, bits.len()),
+            bits.as_mut_ptr() as *mut libc::c_void,
+        )?;
+        Ok(bits)
+    }
+
+    pub fn has_event(&self, event_type: u16, code: u16) -> Result<bool, std::io::Error> {
+        let bits = self.capabilities(event_type)?;
+        Ok(bits[code as usize / 8] & (1 << (code % 8)) != 0)
+    }
+
+    pub fn has_key(&self, code: u16) -> Result<bool, std::io::Error> {
+        self.has_event(EV_KEY, code)
+    }
+
+    /**
+     * Value range of an absolute axis.
+     */
+    pub fn abs_range(&self, axis: u16) -> Result<AbsRange, std::io::Error> {
+        let mut info: libc::input_absinfo = unsafe { std::mem::zeroed() };
+        self.ioctl(
+            eviocgabs(axis),
+            &mut info as *mut libc::input_absinfo as *mut libc::c_void,
+        )?;
+        Ok(AbsRange {
+            min: info.minimum,
+            max: info.maximum,
+        })
+    }
+
+    /**
+     * Pointer state matching the device, absolute if it reports ABS_X.
+     */
+    pub fn pointer(&self, calibration: Calibration) -> Result<Pointer, std::io::Error> {
+        if self.has_event(EV_ABS, ABS_X)? {
+            Ok(Pointer::absolute(AbsAxes {
+                x: self.abs_range(ABS_X)?,
+                y: self.abs_range(ABS_Y)?,
+                calibration,
+            }))
+        } else {
+            Ok(Pointer::relative())
+        }
+    }
+
+    /**
+     * Contact tracking for a touchscreen, with multi-touch if supported.
+     */
+    pub fn touches(&self, calibration: Calibration) -> Result<Touches, std::io::Error> {
+        let multi_touch = self.has_event(EV_ABS, ABS_MT_SLOT)?;
+        let (x, y) = match multi_touch {
+            true => (ABS_MT_POSITION_X, ABS_MT_POSITION_Y),
+            false => (ABS_X, ABS_Y),
+        };
+        let axes = AbsAxes {
+            x: self.abs_range(x)?,
+            y: self.abs_range(y)?,
+            calibration,
+        };
+        Ok(Touches::new(axes, multi_touch))
+    }
+
+    /**
+     * Take exclusive access, so events don't also reach the console.
+     */
+    pub fn grab(&self, grab: bool) -> Result<(), std::io::Error> {
+        let res =
+            unsafe { libc::ioctl(self.file.as_raw_fd(), EVIOCGRAB as _, grab as libc::c_int) };
+        if res < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /**
+     * Wait until events are available. Returns false on timeout.
+     */
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<bool, std::io::Error> {
+        let mut fd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int
+        });
+        let res = unsafe { libc::poll(&mut fd, 1, timeout) };
+        if res < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(res > 0)
+    }
+
+    /**
+     * Block until events are available and return them.
+     */
+    pub fn read_events(&mut self) -> Result<Vec<InputEvent>, std::io::Error> {
+        const EVENT_SIZE: usize = std::mem::size_of::<libc::input_event>();
+        let mut buf = [0u8; EVENT_SIZE * 64];
+        let len = self.file.read(&mut buf)?;
+        if len == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buf[..len - len % EVENT_SIZE]
+            .chunks_exact(EVENT_SIZE)
+            .map(|chunk| {
+                let event: libc::input_event =
+                    unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const _) };
+                InputEvent {
+                    event_type: event.type_,
+                    code: event.code,
+                    value: event.value,
+                }
+            })
+            .collect())
+    }
+
+    pub(crate) fn ioctl(&self, request: u32, arg: *mut libc::c_void) -> Result<(), std::io::Error> {
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, arg) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Forward key presses and releases from the device until it goes away.
+ * Once the server supports QEMU key events, scancodes are sent and dead
+ * keys are left to the server's layout. Viewport shortcuts are not
+ * forwarded, neither their repeats nor their releases. Their modifiers are,
+ * as they are pressed before it is known whether a shortcut follows, so
+ * the server sees Ctrl+Alt+Shift pressed and released on their own.
+ */
+pub fn forward_keyboard(
+    device: &mut Device,
+    keyboard: &mut Keyboard,
+    viewport: &Viewport,
+    input: &InputSender,
+) -> Result<(), std::io::Error> {
+    let mut shortcuts = HashSet::new();
+    loop {
+        for event in device.read_events()? {
+            if event.event_type != EV_KEY {
+                continue;
+            }
+            let shortcut = viewport::shortcut(keyboard.modifiers(), event.code);
+            let eaten = match event.value {
+                0 => shortcuts.remove(&event.code),
+                1 if shortcut.is_some() => {
+                    shortcuts.insert(event.code);
+                    true
+                }
+                1 => false,
+                _ => shortcuts.contains(&event.code),
+            };
+            if eaten {
+                if let Some((dx, dy)) = shortcut.filter(|_| event.value != 0) {
+                    viewport.scroll(dx, dy)?;
+                }
+                continue;
+            }
+            if input.qemu_key_events() {
+                if let Some((down, keysym)) = keyboard.handle_raw_key(event.code, event.value) {
+                    input.physical_key_event(down, keysym, event.code)?;
+                }
+                continue;
+            }
+            for (down, keysym) in keyboard.handle_key(event.code, event.value) {
+                input.key_event(down, keysym)?;
+            }
+        }
+    }
+}
+
+/**
+ * Send playback controls for the keys pressed on the device, until it goes
+ * away or playback ended.
+ */
+pub fn forward_controls(
+    device: &mut Device,
+    keyboard: &mut Keyboard,
+    controls: &Sender<Control>,
+) -> Result<(), std::io::Error> {
+    loop {
+        for event in device.read_events()? {
+            if event.event_type != EV_KEY {
+                continue;
+            }
+            for (down, keysym) in keyboard.handle_key(event.code, event.value) {
+                let Some(control) = player::control(keysym).filter(|_| down) else {
+                    continue;
+                };
+                if controls.send(control).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Forward pointer movement, buttons and wheel clicks from the device until
+ * it goes away, mapped through the current view. While a mouse rests at an
+ * edge of the display the view scrolls, and the pointer moves along with
+ * the desktop underneath.
+ */
+pub fn forward_pointer(
+    device: &mut Device,
+    pointer: &mut Pointer,
+    viewport: &Viewport,
+    input: &InputSender,
+) -> Result<(), std::io::Error> {
+    let mut scrolling = false;
+    loop {
+        let timeout = scrolling.then_some(viewport::EDGE_INTERVAL);
+        let ready = device.wait(timeout)?;
+        if ready {
+            for event in device.read_events()? {
+                let events = pointer.handle(&event, &viewport.view());
+                for (buttons, x, y) in events {
+                    input.pointer_event(buttons, x, y)?;
+                }
+            }
+        }
+        let Some((x, y)) = pointer.relative_position() else {
+            continue;
+        };
+        let Some((dx, dy)) = viewport.edge(x, y) else {
+            scrolling = false;
+            continue;
+        };
+        scrolling = true;
+        if !ready {
+            scrolling = viewport.scroll(dx * viewport::EDGE_STEP, dy * viewport::EDGE_STEP)?;
+            if scrolling {
+                let (x, y) = viewport.view().to_remote(x, y);
+                input.pointer_event(pointer.buttons(), x, y)?;
+            }
+        }
+    }
+}
+
+/**
+ * Recognise gestures on a touchscreen and apply them until it goes away.
+ */
+pub fn forward_gestures(
+    device: &mut Device,
+    touches: &mut Touches,
+    gestures: &mut Gestures,
+    viewport: &Viewport,
+    input: &InputSender,
+) -> Result<(), std::io::Error> {
+    loop {
+        let ready = device.wait(gestures.timeout(Instant::now()))?;
+        let mut recognised = gestures.tick(Instant::now());
+        if ready {
+            for event in device.read_events()? {
+                if let Some(contacts) = touches.handle(&event, &viewport.view()) {
+                    recognised.extend(gestures.update(&contacts, Instant::now()));
+                }
+            }
+        }
+        for gesture in recognised {
+            let mut result = Ok(());
+            viewport.change(|view| result = gesture::apply(gesture, view, input))?;
+            result?;
+        }
+    }
+}
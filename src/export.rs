@@ -0,0 +1,337 @@
+/*
+ * Export of a session or a recording as video frames at a fixed frame rate.
+ *
+ * Frames go either into a YUV4MPEG2 stream, which encoders such as ffmpeg
+ * read from a pipe, or into a numbered image per frame. Y4M frames are
+ * 4:2:0 in BT.601 studio range, the format encoders expect by default; the
+ * stream cannot change size, so every frame has the size of the first one,
+ * cropped or padded with black if the desktop was resized since.
+ *
+ * Recordings are exported as fast as they decode, taking a frame whenever
+ * the recording reaches the time of the next one. Live sessions are
+ * sampled by the wall clock, repeating frames when writing falls behind so
+ * that the video keeps the length of the session.
+ */
+
+use crate::canvas::Canvas;
+use crate::display::{Display, ImageDisplay};
+use crate::image::ImageFormat;
+use crate::player::Player;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_FPS: u32 = 25;
+pub const MAX_FPS: u32 = 240;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExportSpec {
+    /** A Y4M file, or standard output for "-". */
+    Y4m(PathBuf),
+    Images(PathBuf, ImageFormat),
+}
+
+enum Sink {
+    Y4m {
+        out: Box<dyn Write + Send>,
+        size: Option<(u16, u16)>,
+    },
+    Images(ImageDisplay),
+}
+
+/**
+ * Writes frames to where an `ExportSpec` points, keeping count of them.
+ */
+pub struct Exporter {
+    sink: Sink,
+    fps: u32,
+    frames: u64,
+}
+
+impl ExportSpec {
+    /**
+     * `y4m:FILE` or `y4m:-`, `png:DIR` or `ppm:DIR`.
+     */
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec.split_once(':') {
+            Some(("y4m", path)) if !path.is_empty() => Ok(ExportSpec::Y4m(path.into())),
+            Some(("png", dir)) if !dir.is_empty() => {
+                Ok(ExportSpec::Images(dir.into(), ImageFormat::Png))
+            }
+            Some(("ppm", dir)) if !dir.is_empty() => {
+                Ok(ExportSpec::Images(dir.into(), ImageFormat::Ppm))
+            }
+            _ => Err(format!("invalid export {}", spec)),
+        }
+    }
+
+    /**
+     * Whether frames go to standard output.
+     */
+    pub fn to_stdout(&self) -> bool {
+        matches!(self, ExportSpec::Y4m(path) if path.as_os_str() == "-")
+    }
+}
+
+impl Exporter {
+    pub fn open(spec: &ExportSpec, fps: u32) -> Result<Self, std::io::Error> {
+        Ok(match spec {
+            ExportSpec::Y4m(_) if spec.to_stdout() => {
+                Self::y4m(Box::new(std::io::BufWriter::new(std::io::stdout())), fps)
+            }
+            ExportSpec::Y4m(path) => {
+                let file = std::fs::File::create(path)?;
+                Self::y4m(Box::new(std::io::BufWriter::new(file)), fps)
+            }
+            ExportSpec::Images(dir, format) => Self {
+                sink: Sink::Images(ImageDisplay::new(dir.clone(), *format)?),
+                fps,
+                frames: 0,
+            },
+        })
+    }
+
+    /**
+     * Write a Y4M stream to `out`.
+     */
+    pub fn y4m(out: Box<dyn Write + Send>, fps: u32) -> Self {
+        Self {
+            sink: Sink::Y4m { out, size: None },
+            fps,
+            frames: 0,
+        }
+    }
+
+    /**
+     * Number of frames written so far.
+     */
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /**
+     * Time of a frame from the start of the video.
+     */
+    pub fn frame_time(&self, frame: u64) -> Duration {
+        Duration::from_millis(frame * 1000 / self.fps as u64)
+    }
+
+    /**
+     * Add the canvas as the next frame.
+     */
+    pub fn write(&mut self, canvas: &Canvas) -> Result<(), std::io::Error> {
+        match &mut self.sink {
+            Sink::Y4m { out, size } => {
+                let (width, height) = *size.get_or_insert((canvas.width(), canvas.height()));
+                if self.frames == 0 {
+                    writeln!(
+                        out,
+                        "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg",
+                        width, height, self.fps
+                    )?;
+                }
+                out.write_all(b"FRAME\n")?;
+                out.write_all(&yuv420(canvas, width, height))?;
+                out.flush()?;
+            }
+            Sink::Images(display) => display.update(canvas, &[])?,
+        }
+        self.frames += 1;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Exporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Exporter")
+            .field("fps", &self.fps)
+            .field("frames", &self.frames)
+            .finish()
+    }
+}
+
+/**
+ * The Y, Cb and Cr planes of a `width` x `height` frame of the canvas, with
+ * chroma averaged over 2x2 pixels.
+ */
+fn yuv420(canvas: &Canvas, width: u16, height: u16) -> Vec<u8> {
+    let rgb = |x: u16, y: u16| match x < canvas.width() && y < canvas.height() {
+        true => {
+            let pixel = canvas.pixel(x, y);
+            [(pixel >> 16) & 0xff, (pixel >> 8) & 0xff, pixel & 0xff].map(|c| c as i32)
+        }
+        false => [0; 3],
+    };
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+    let mut frame = Vec::with_capacity(
+        width as usize * height as usize + 2 * chroma_width as usize * chroma_height as usize,
+    );
+    for y in 0..height {
+        for x in 0..width {
+            let [r, g, b] = rgb(x, y);
+            frame.push((((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8);
+        }
+    }
+    let mut cr = Vec::with_capacity(chroma_width as usize * chroma_height as usize);
+    for y in 0..chroma_height {
+        for x in 0..chroma_width {
+            let (mut sum, mut count) = ([0; 3], 0);
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let (px, py) = (x * 2 + dx, y * 2 + dy);
+                if px < width && py < height {
+                    let pixel = rgb(px, py);
+                    (0..3).for_each(|i| sum[i] += pixel[i]);
+                    count += 1;
+                }
+            }
+            let [r, g, b] = sum.map(|c| c / count);
+            frame.push((((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8);
+            cr.push((((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8);
+        }
+    }
+    frame.extend_from_slice(&cr);
+    frame
+}
+
+/**
+ * Export the whole of a recording that lasts `duration` milliseconds, up to
+ * the first frame that shows its end.
+ */
+pub fn export_recording(
+    player: &mut Player,
+    duration: u32,
+    exporter: &mut Exporter,
+) -> Result<(), std::io::Error> {
+    let canvas = player.canvas();
+    loop {
+        let time = exporter.frame_time(exporter.frames()).as_millis();
+        player.advance(time.min(duration as u128) as u32)?;
+        exporter.write(&canvas.lock().unwrap())?;
+        if time >= duration as u128 {
+            return Ok(());
+        }
+    }
+}
+
+/**
+ * Export the canvas of a live session until `stop` is sent to or dropped.
+ */
+pub fn export_live(
+    canvas: &Mutex<Canvas>,
+    exporter: &mut Exporter,
+    stop: &Receiver<()>,
+) -> Result<(), std::io::Error> {
+    let started = Instant::now();
+    loop {
+        let due = started + exporter.frame_time(exporter.frames());
+        match stop.recv_timeout(due.saturating_duration_since(Instant::now())) {
+            // Copied so that a slow pipe does not hold up the session.
+            Err(RecvTimeoutError::Timeout) => {
+                let frame = canvas.lock().unwrap().clone();
+                exporter.write(&frame)?;
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fbs;
+    use crate::player::Recording;
+    use crate::session::Session;
+    use crate::test_util::SharedBuffer;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            ExportSpec::parse("y4m:out.y4m"),
+            Ok(ExportSpec::Y4m("out.y4m".into()))
+        );
+        assert!(ExportSpec::parse("y4m:-").unwrap().to_stdout());
+        assert!(!ExportSpec::parse("y4m:out.y4m").unwrap().to_stdout());
+        assert_eq!(
+            ExportSpec::parse("png:/tmp/frames"),
+            Ok(ExportSpec::Images("/tmp/frames".into(), ImageFormat::Png))
+        );
+        assert!(ExportSpec::parse("y4m:").is_err());
+        assert!(ExportSpec::parse("mp4:out.mp4").is_err());
+    }
+
+    #[test]
+    fn test_y4m() {
+        let buffer = SharedBuffer::default();
+        let mut exporter = Exporter::y4m(Box::new(buffer.clone()), 30);
+        let mut canvas = Canvas::new(3, 1);
+        canvas
+            .pixels_mut()
+            .copy_from_slice(&[0xffffff, 0xffffff, 0xff0000]);
+        exporter.write(&canvas).unwrap();
+        // The stream keeps its size after the desktop grows.
+        canvas.resize(4, 2);
+        canvas.pixels_mut().fill(0);
+        exporter.write(&canvas).unwrap();
+        assert_eq!(exporter.frames(), 2);
+
+        let header = b"YUV4MPEG2 W3 H1 F30:1 Ip A1:1 C420jpeg\n";
+        let white_red = [235, 235, 82, 128, 90, 128, 240];
+        let black = [16, 16, 16, 128, 128, 128, 128];
+        let expected = [&header[..], b"FRAME\n", &white_red, b"FRAME\n", &black].concat();
+        assert_eq!(buffer.contents(), expected);
+    }
+
+    #[test]
+    fn test_export_recording() {
+        let mut data = fbs::SIGNATURE.to_vec();
+        let prelude = fbs::prelude(1, 1, &Session::PREFERRED_PIXEL_FORMAT, "rec");
+        fbs::write_block(&mut data, 0, &prelude).unwrap();
+        let update = [
+            0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0,
+        ];
+        fbs::write_block(&mut data, 250, &update).unwrap();
+        let recording = Recording::parse(&data).unwrap();
+        let duration = recording.duration();
+        let mut player = Player::new(recording);
+
+        let dir = std::env::temp_dir().join(format!("vncvwr-export-{}", std::process::id()));
+        let mut exporter =
+            Exporter::open(&ExportSpec::Images(dir.clone(), ImageFormat::Ppm), 10).unwrap();
+        export_recording(&mut player, duration, &mut exporter).unwrap();
+        // Frames at 0, 100 and 200 ms are black, at 300 ms the update shows.
+        assert_eq!(exporter.frames(), 4);
+        assert_eq!(
+            std::fs::read(dir.join("frame-000003.ppm")).unwrap(),
+            b"P6\n1 1\n255\n\0\0\0"
+        );
+        assert_eq!(
+            std::fs::read(dir.join("frame-000004.ppm")).unwrap(),
+            b"P6\n1 1\n255\n\xff\xff\xff"
+        );
+        assert!(!dir.join("frame-000005.ppm").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_live() {
+        let canvas = Mutex::new(Canvas::new(2, 2));
+        let buffer = SharedBuffer::default();
+        let mut exporter = Exporter::y4m(Box::new(buffer.clone()), 100);
+        let (stop, receiver) = mpsc::channel();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(stop);
+        });
+        export_live(&canvas, &mut exporter, &receiver).unwrap();
+        stopper.join().unwrap();
+        assert!(exporter.frames() >= 2);
+        let frame = b"FRAME\n".len() + 4 + 2;
+        assert_eq!(
+            buffer.contents().len(),
+            b"YUV4MPEG2 W2 H2 F100:1 Ip A1:1 C420jpeg\n".len() + frame * exporter.frames() as usize
+        );
+    }
+}
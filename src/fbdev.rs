@@ -0,0 +1,561 @@
+/*
+ * Display on a Linux framebuffer device (/dev/fb*).
+ *
+ * The device memory is mapped by the framebuffer crate. Damaged areas of
+ * the canvas are converted to the pixel layout of the device, as described
+ * by its variable screen info, and written to the visible part of the
+ * mapping, which starts at (xoffset, yoffset) and has rows of line_length
+ * bytes.
+ *
+ * fbcon rotation does not apply to the mapping, so rotated panels are
+ * handled here: the display reports the rotated size, and every pixel is
+ * put at its rotated position while blitting.
+ *
+ * Updates are blitted into a shadow buffer in memory first. If the virtual
+ * resolution has room for two screens, the damaged areas are copied to the
+ * hidden one, which is then shown with FBIOPAN_DISPLAY, optionally after
+ * waiting for vertical sync. Otherwise they are copied to the visible
+ * screen, which keeps the time it shows a half drawn update short.
+ */
+
+use crate::calibrate::Calibration;
+use crate::canvas::{Canvas, Rect};
+use crate::display::Display;
+use framebuffer::{FixScreeninfo, Framebuffer, FramebufferError, VarScreeninfo};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+pub const DEFAULT_DEVICE: &str = "/dev/fb0";
+
+const FBIO_WAITFORVSYNC: u32 = 0x4004_4620;
+
+/**
+ * Layout of the visible screen in the mapped memory. Channels are given
+ * as (offset, length) in bits.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FbFormat {
+    pub width: u16,
+    pub height: u16,
+    pub bytes_per_pixel: usize,
+    pub line_length: usize,
+    pub xoffset: usize,
+    pub yoffset: usize,
+    pub red: (u32, u32),
+    pub green: (u32, u32),
+    pub blue: (u32, u32),
+}
+
+/**
+ * Clockwise rotation of the picture on the panel.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    R0,
+    R90,
+    R180,
+    R270,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FbSettings {
+    pub rotation: Rotation,
+    /** Wait for vertical sync before showing an update. */
+    pub vsync: bool,
+}
+
+pub struct FramebufferDisplay {
+    fb: Framebuffer,
+    /** Layout of the first screen of the mapping. */
+    format: FbFormat,
+    settings: FbSettings,
+    shadow: Vec<u8>,
+    shadow_format: FbFormat,
+    pages: usize,
+    page: usize,
+    /**
+     * Areas of the last update, still missing on the hidden screen. At
+     * first the whole screen, to replace what was shown before.
+     */
+    previous: Vec<Rect>,
+}
+
+fn io_error(err: FramebufferError) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+/**
+ * Scale an 8 bit colour channel to `length` bits.
+ */
+fn channel(value: u32, length: u32) -> u32 {
+    if length >= 8 {
+        value << (length - 8)
+    } else {
+        value >> (8 - length)
+    }
+}
+
+impl FbFormat {
+    pub fn new(var: &VarScreeninfo, fix: &FixScreeninfo) -> Result<Self, std::io::Error> {
+        if !matches!(var.bits_per_pixel, 16 | 24 | 32) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("{} bits per pixel not supported", var.bits_per_pixel),
+            ));
+        }
+        Ok(Self {
+            width: var.xres.min(u16::MAX as u32) as u16,
+            height: var.yres.min(u16::MAX as u32) as u16,
+            bytes_per_pixel: var.bits_per_pixel as usize / 8,
+            line_length: fix.line_length as usize,
+            xoffset: var.xoffset as usize,
+            yoffset: var.yoffset as usize,
+            red: (var.red.offset, var.red.length),
+            green: (var.green.offset, var.green.length),
+            blue: (var.blue.offset, var.blue.length),
+        })
+    }
+
+    /**
+     * Device pixel value of a 0x00RRGGBB canvas pixel.
+     */
+    pub fn encode(&self, rgb: u32) -> u32 {
+        channel((rgb >> 16) & 0xff, self.red.1) << self.red.0
+            | channel((rgb >> 8) & 0xff, self.green.1) << self.green.0
+            | channel(rgb & 0xff, self.blue.1) << self.blue.0
+    }
+
+    /**
+     * Byte offset of the pixel at (x, y) of the screen.
+     */
+    pub fn offset(&self, x: usize, y: usize) -> usize {
+        (self.yoffset + y) * self.line_length + (self.xoffset + x) * self.bytes_per_pixel
+    }
+}
+
+impl Rotation {
+    pub fn parse(degrees: &str) -> Result<Self, String> {
+        match degrees {
+            "0" => Ok(Rotation::R0),
+            "90" => Ok(Rotation::R90),
+            "180" => Ok(Rotation::R180),
+            "270" => Ok(Rotation::R270),
+            _ => Err(format!("invalid rotation {}", degrees)),
+        }
+    }
+
+    /**
+     * Size of the rotated picture on a `width` x `height` screen.
+     */
+    pub fn size(&self, width: u16, height: u16) -> (u16, u16) {
+        match self {
+            Rotation::R0 | Rotation::R180 => (width, height),
+            Rotation::R90 | Rotation::R270 => (height, width),
+        }
+    }
+
+    /**
+     * Position on a `width` x `height` screen of the picture's pixel
+     * (`x`, `y`).
+     */
+    pub fn screen_position(&self, x: usize, y: usize, width: u16, height: u16) -> (usize, usize) {
+        let (width, height) = (width as usize, height as usize);
+        match self {
+            Rotation::R0 => (x, y),
+            Rotation::R90 => (width - 1 - y, x),
+            Rotation::R180 => (width - 1 - x, height - 1 - y),
+            Rotation::R270 => (y, height - 1 - x),
+        }
+    }
+
+    /**
+     * Area on a `width` x `height` screen covered by the picture's `area`,
+     * which must lie within the picture.
+     */
+    pub fn screen_area(&self, area: Rect, width: u16, height: u16) -> Rect {
+        if area.is_empty() {
+            return area;
+        }
+        let (x0, y0) = self.screen_position(area.x as usize, area.y as usize, width, height);
+        let (x1, y1) = self.screen_position(
+            area.right() as usize - 1,
+            area.bottom() as usize - 1,
+            width,
+            height,
+        );
+        Rect::new(
+            x0.min(x1) as u16,
+            y0.min(y1) as u16,
+            (x0.abs_diff(x1) + 1) as u16,
+            (y0.abs_diff(y1) + 1) as u16,
+        )
+    }
+
+    /**
+     * The inverse rotation on normalised coordinates, from a touchscreen on
+     * the panel to the picture.
+     */
+    pub fn calibration(&self) -> Calibration {
+        match self {
+            Rotation::R0 => Calibration::IDENTITY,
+            Rotation::R90 => Calibration([0.0, 1.0, 0.0, -1.0, 0.0, 1.0]),
+            Rotation::R180 => Calibration([-1.0, 0.0, 1.0, 0.0, -1.0, 1.0]),
+            Rotation::R270 => Calibration([0.0, -1.0, 1.0, 1.0, 0.0, 0.0]),
+        }
+    }
+}
+
+/**
+ * Copy an area of the canvas to the same position of the rotated picture
+ * on the screen. Parts outside of either are skipped.
+ */
+pub fn blit(canvas: &Canvas, area: Rect, format: &FbFormat, rotation: Rotation, frame: &mut [u8]) {
+    let (width, height) = rotation.size(format.width, format.height);
+    let area = area
+        .clip(canvas.width(), canvas.height())
+        .clip(width, height);
+    let bpp = format.bytes_per_pixel;
+    if rotation != Rotation::R0 {
+        for y in area.y as usize..area.bottom() as usize {
+            for x in area.x as usize..area.right() as usize {
+                let (screen_x, screen_y) =
+                    rotation.screen_position(x, y, format.width, format.height);
+                let start = format.offset(screen_x, screen_y);
+                if let Some(dst) = frame.get_mut(start..start + bpp) {
+                    let rgb = canvas.pixel(x as u16, y as u16);
+                    dst.copy_from_slice(&format.encode(rgb).to_le_bytes()[..bpp]);
+                }
+            }
+        }
+        return;
+    }
+    let pixels = canvas.pixels();
+    for y in area.y as usize..area.bottom() as usize {
+        let src = &pixels[y * canvas.width() as usize..][area.x as usize..area.right() as usize];
+        let start = format.offset(area.x as usize, y);
+        let Some(dst) = frame.get_mut(start..start + src.len() * bpp) else {
+            break;
+        };
+        for (dst, &rgb) in dst.chunks_exact_mut(bpp).zip(src) {
+            dst.copy_from_slice(&format.encode(rgb).to_le_bytes()[..bpp]);
+        }
+    }
+}
+
+/**
+ * Copy an area between two buffers with the same pixel layout.
+ */
+pub fn copy_area(
+    src: &[u8],
+    src_format: &FbFormat,
+    dst: &mut [u8],
+    dst_format: &FbFormat,
+    area: Rect,
+) {
+    let area = area.clip(src_format.width, src_format.height);
+    let len = area.width as usize * src_format.bytes_per_pixel;
+    for y in area.y as usize..area.bottom() as usize {
+        let from = src_format.offset(area.x as usize, y);
+        let to = dst_format.offset(area.x as usize, y);
+        let (Some(src), Some(dst)) = (src.get(from..from + len), dst.get_mut(to..to + len)) else {
+            break;
+        };
+        dst.copy_from_slice(src);
+    }
+}
+
+impl FramebufferDisplay {
+    pub fn open<P: AsRef<Path>>(path: P, settings: FbSettings) -> Result<Self, std::io::Error> {
+        let fb = Framebuffer::new(path).map_err(io_error)?;
+        let var = &fb.var_screen_info;
+        let fix = &fb.fix_screen_info;
+        let mut format = FbFormat::new(var, fix)?;
+        let shadow_format = FbFormat {
+            xoffset: 0,
+            yoffset: 0,
+            ..format.clone()
+        };
+        let shadow = vec![0; format.line_length * format.height as usize];
+        let (yres, ypanstep) = (var.yres, fix.ypanstep as u32);
+        let pages = if ypanstep != 0
+            && yres % ypanstep == 0
+            && var.yres_virtual >= 2 * yres
+            && (var.yoffset == 0 || var.yoffset == yres)
+            && fb.frame.len() >= format.line_length * 2 * yres as usize
+        {
+            2
+        } else {
+            1
+        };
+        let page = format.yoffset / format.height.max(1) as usize;
+        if pages == 2 {
+            format.yoffset = 0;
+        }
+        let screen = Rect::new(0, 0, format.width, format.height);
+        Ok(Self {
+            fb,
+            format,
+            settings,
+            shadow,
+            shadow_format,
+            pages,
+            page,
+            previous: vec![screen],
+        })
+    }
+
+    /**
+     * Width of the screen, not taking rotation into account.
+     */
+    pub fn width(&self) -> u16 {
+        self.format.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.format.height
+    }
+
+    /**
+     * Fill the screens with black.
+     */
+    pub fn clear(&mut self) {
+        self.shadow.fill(0);
+        for page in 0..self.pages {
+            let format = self.page_format(page);
+            for y in 0..format.height as usize {
+                let start = format.offset(0, y);
+                let len = format.width as usize * format.bytes_per_pixel;
+                if let Some(row) = self.fb.frame.get_mut(start..start + len) {
+                    row.fill(0);
+                }
+            }
+        }
+    }
+
+    fn page_format(&self, page: usize) -> FbFormat {
+        FbFormat {
+            yoffset: self.format.yoffset + page * self.format.height as usize,
+            ..self.format.clone()
+        }
+    }
+
+    fn wait_for_vsync(&mut self) {
+        let mut screen: u32 = 0;
+        let fd = self.fb.device.as_raw_fd();
+        if unsafe { libc::ioctl(fd, FBIO_WAITFORVSYNC as _, &mut screen) } < 0 {
+            // Not every driver supports it, don't try again.
+            self.settings.vsync = false;
+        }
+    }
+
+    /**
+     * Bring the shadow buffer's `areas` to the screen.
+     */
+    fn present(&mut self, areas: &[Rect]) -> Result<(), std::io::Error> {
+        if areas.is_empty() {
+            return Ok(());
+        }
+        let page = (self.page + 1) % self.pages;
+        let format = self.page_format(page);
+        // With a single page the copy is what shows, so it starts at the
+        // blank. With two it goes to the hidden page and the pan waits.
+        if self.settings.vsync && self.pages == 1 {
+            self.wait_for_vsync();
+        }
+        for area in areas.iter().chain(&self.previous) {
+            copy_area(
+                &self.shadow,
+                &self.shadow_format,
+                &mut self.fb.frame,
+                &format,
+                *area,
+            );
+        }
+        if self.settings.vsync && self.pages > 1 {
+            self.wait_for_vsync();
+        }
+        if self.pages > 1 {
+            let mut var = self.fb.var_screen_info.clone();
+            var.yoffset = format.yoffset as u32;
+            Framebuffer::pan_display(&self.fb.device, &var).map_err(io_error)?;
+            self.fb.var_screen_info = var;
+            self.page = page;
+        }
+        self.previous = if self.pages > 1 {
+            areas.to_vec()
+        } else {
+            Vec::new()
+        };
+        Ok(())
+    }
+}
+
+impl Display for FramebufferDisplay {
+    fn size(&self) -> Option<(u16, u16)> {
+        Some(
+            self.settings
+                .rotation
+                .size(self.format.width, self.format.height),
+        )
+    }
+
+    fn update(&mut self, canvas: &Canvas, damage: &[Rect]) -> Result<(), std::io::Error> {
+        let (width, height) = (self.format.width, self.format.height);
+        let rotation = self.settings.rotation;
+        let (picture_width, picture_height) = rotation.size(width, height);
+        let mut areas = Vec::new();
+        for &area in damage {
+            let area = area
+                .clip(canvas.width(), canvas.height())
+                .clip(picture_width, picture_height);
+            if area.is_empty() {
+                continue;
+            }
+            blit(
+                canvas,
+                area,
+                &self.shadow_format,
+                rotation,
+                &mut self.shadow,
+            );
+            areas.push(rotation.screen_area(area, width, height));
+        }
+        self.present(&areas)
+    }
+
+    fn resized(&mut self, canvas: &Canvas) -> Result<(), std::io::Error> {
+        self.clear();
+        self.update(canvas, &[Rect::new(0, 0, canvas.width(), canvas.height())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RGB565: FbFormat = FbFormat {
+        width: 3,
+        height: 2,
+        bytes_per_pixel: 2,
+        line_length: 10,
+        xoffset: 1,
+        yoffset: 1,
+        red: (11, 5),
+        green: (5, 6),
+        blue: (0, 5),
+    };
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(RGB565.encode(0xff0000), 0xf800);
+        assert_eq!(RGB565.encode(0x00ff00), 0x07e0);
+        assert_eq!(RGB565.encode(0x0000ff), 0x001f);
+        let bgrx = FbFormat {
+            bytes_per_pixel: 4,
+            red: (16, 8),
+            green: (8, 8),
+            blue: (0, 8),
+            ..RGB565
+        };
+        assert_eq!(bgrx.encode(0x123456), 0x123456);
+        let rgb101010 = FbFormat {
+            red: (20, 10),
+            green: (10, 10),
+            blue: (0, 10),
+            ..bgrx
+        };
+        assert_eq!(rgb101010.encode(0xff0001), 0x3fc0_0004);
+    }
+
+    #[test]
+    fn test_blit() {
+        let mut canvas = Canvas::new(4, 1);
+        canvas.fill_rect(Rect::new(0, 0, 4, 1), 0xff0000);
+        canvas.fill_rect(Rect::new(1, 0, 1, 1), 0x0000ff);
+        let mut frame = vec![0xaa; 30];
+        blit(
+            &canvas,
+            Rect::new(1, 0, 10, 10),
+            &RGB565,
+            Rotation::R0,
+            &mut frame,
+        );
+        assert_eq!(
+            frame,
+            [
+                [0xaa; 10],
+                [0xaa, 0xaa, 0xaa, 0xaa, 0x1f, 0x00, 0x00, 0xf8, 0xaa, 0xaa],
+                [0xaa; 10],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_rotation() {
+        // A 2x3 picture on the 3x2 screen, red with a blue top left corner.
+        let mut canvas = Canvas::new(2, 3);
+        canvas.fill_rect(Rect::new(0, 0, 2, 3), 0xff0000);
+        canvas.fill_rect(Rect::new(0, 0, 1, 1), 0x0000ff);
+        assert_eq!(Rotation::R90.size(3, 2), (2, 3));
+        let mut frame = vec![0xaa; 30];
+        blit(
+            &canvas,
+            Rect::new(0, 0, 2, 3),
+            &RGB565,
+            Rotation::R90,
+            &mut frame,
+        );
+        assert_eq!(
+            frame[10..],
+            [
+                [0xaa, 0xaa, 0x00, 0xf8, 0x00, 0xf8, 0x1f, 0x00, 0xaa, 0xaa],
+                [0xaa, 0xaa, 0x00, 0xf8, 0x00, 0xf8, 0x00, 0xf8, 0xaa, 0xaa],
+            ]
+            .concat()
+        );
+
+        assert_eq!(
+            Rotation::R90.screen_area(Rect::new(0, 1, 2, 2), 3, 2),
+            Rect::new(0, 0, 2, 2)
+        );
+        assert_eq!(
+            Rotation::R270.screen_area(Rect::new(1, 0, 1, 3), 3, 2),
+            Rect::new(0, 0, 3, 1)
+        );
+
+        for rotation in [Rotation::R90, Rotation::R180, Rotation::R270] {
+            let (x, y) = rotation.screen_position(0, 0, 3, 2);
+            let touch = (x as f64 / 2.0, y as f64 / 1.0);
+            assert_eq!(rotation.calibration().apply(touch.0, touch.1), (0.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn test_copy_area() {
+        let shadow_format = FbFormat {
+            xoffset: 0,
+            yoffset: 0,
+            ..RGB565
+        };
+        let shadow: Vec<u8> = (0..20).collect();
+        let mut frame = vec![0xaa; 30];
+        copy_area(
+            &shadow,
+            &shadow_format,
+            &mut frame,
+            &RGB565,
+            Rect::new(1, 1, 5, 1),
+        );
+        assert_eq!(
+            frame,
+            [
+                [0xaa; 10],
+                [0xaa; 10],
+                [0xaa, 0xaa, 0xaa, 0xaa, 12, 13, 14, 15, 0xaa, 0xaa],
+            ]
+            .concat()
+        );
+    }
+}
@@ -0,0 +1,182 @@
+/*
+ * Session recordings in the FBS 001.000 format of rfbproxy and vncrec.
+ *
+ * An FBS file is the "FBS 001.000\n" signature followed by blocks of what
+ * the server sent: the length of the data as u32, the data padded to a
+ * multiple of 4 bytes, and the milliseconds since the recording started as
+ * u32, all big endian. The stream starts like an RFB 3.3 session without
+ * authentication, with a ServerInit describing the pixel format the
+ * following messages use, so a recording can be started at any point after
+ * the handshake. `player` reads them back.
+ */
+
+use crate::rfb::PixelFormat;
+use crate::session::Session;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+pub const SIGNATURE: &[u8; 12] = b"FBS 001.000\n";
+const RFB_VERSION: &[u8; 12] = b"RFB 003.003\n";
+const SECURITY_NONE: u32 = 1;
+
+/**
+ * Writes the bytes received from the server to an FBS file, one block per
+ * read, each flushed so the recording survives a crash.
+ */
+pub struct Recorder {
+    out: Box<dyn Write + Send>,
+    started: Instant,
+}
+
+/**
+ * The blocks of an FBS file as (milliseconds, data). A block cut short at
+ * the end, as left by a recorder that was killed, is dropped, and so is
+ * everything from a block claiming more data than the file has.
+ */
+pub fn blocks(data: &[u8]) -> Result<Vec<(u32, &[u8])>, std::io::Error> {
+    if !data.starts_with(SIGNATURE) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "not an FBS 001.000 file",
+        ));
+    }
+    let u32_at = |pos: usize| u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap());
+    let mut blocks = Vec::new();
+    let mut pos = SIGNATURE.len();
+    while pos + 4 <= data.len() {
+        let len = u32_at(pos) as usize;
+        let end = len
+            .div_ceil(4)
+            .checked_mul(4)
+            .and_then(|padded| (pos + 4).checked_add(padded));
+        let Some(end) = end.filter(|&end| end <= data.len() - 4) else {
+            break;
+        };
+        blocks.push((u32_at(end), &data[pos + 4..pos + 4 + len]));
+        pos = end + 4;
+    }
+    Ok(blocks)
+}
+
+/**
+ * Bytes a server sends up to and including ServerInit, for RFB 3.3 and no
+ * authentication.
+ */
+pub fn prelude(width: u16, height: u16, format: &PixelFormat, name: &str) -> Vec<u8> {
+    let mut data = RFB_VERSION.to_vec();
+    data.extend_from_slice(&SECURITY_NONE.to_be_bytes());
+    data.extend_from_slice(&width.to_be_bytes());
+    data.extend_from_slice(&height.to_be_bytes());
+    data.extend_from_slice(&<[u8; 16]>::from(format));
+    data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    data.extend_from_slice(name.as_bytes());
+    data
+}
+
+/**
+ * Append a block of server data received `time` milliseconds into the
+ * recording.
+ */
+pub fn write_block(out: &mut dyn Write, time: u32, data: &[u8]) -> Result<(), std::io::Error> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(data)?;
+    out.write_all(&[0; 3][..(4 - data.len() % 4) % 4])?;
+    out.write_all(&time.to_be_bytes())
+}
+
+impl Recorder {
+    /**
+     * Start a recording of what follows the ServerInit given as `prelude`.
+     */
+    pub fn new(mut out: Box<dyn Write + Send>, prelude: &[u8]) -> Result<Self, std::io::Error> {
+        out.write_all(SIGNATURE)?;
+        let mut recorder = Self {
+            out,
+            started: Instant::now(),
+        };
+        recorder.write(prelude)?;
+        Ok(recorder)
+    }
+
+    /**
+     * Record to a new file, from the current state of a session that
+     * completed its handshake.
+     */
+    pub fn create<P: AsRef<Path>>(path: P, session: &Session) -> Result<Self, std::io::Error> {
+        let format = session.pixel_format().ok_or(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no handshake yet",
+        ))?;
+        let prelude = prelude(
+            session.screen_w(),
+            session.screen_h(),
+            format,
+            session.name(),
+        );
+        let file = std::fs::File::create(path)?;
+        Self::new(Box::new(std::io::BufWriter::new(file)), &prelude)
+    }
+
+    /**
+     * Append a block of server data with the current time.
+     */
+    pub fn write(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        let timestamp = self.started.elapsed().as_millis().min(u32::MAX as u128) as u32;
+        write_block(&mut self.out, timestamp, data)?;
+        self.out.flush()
+    }
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Recorder")
+            .field("started", &self.started)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockServer, Script};
+
+    #[test]
+    fn test_recorder() {
+        let server = MockServer::start(
+            Script::new()
+                .handshake(2, 1, &Session::PREFERRED_PIXEL_FORMAT, "desk")
+                .send(&[2]),
+        );
+        let mut session = Session::new("127.0.0.1", server.port()).unwrap();
+        session.handshake().unwrap();
+        let path = std::env::temp_dir().join(format!("vncvwr-fbs-{}.fbs", std::process::id()));
+        session.record(Recorder::create(&path, &session).unwrap());
+        session.read_message().unwrap();
+        server.finish();
+        drop(session);
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let prelude = prelude(2, 1, &Session::PREFERRED_PIXEL_FORMAT, "desk");
+        assert_eq!(prelude.len(), 12 + 4 + 24 + 4);
+        assert_eq!(&data[..12], SIGNATURE);
+        assert_eq!(&data[12..16], (prelude.len() as u32).to_be_bytes());
+        assert_eq!(&data[16..16 + prelude.len()], prelude);
+        let bell = 16 + prelude.len() + 4;
+        assert_eq!(&data[bell..bell + 8], [0, 0, 0, 1, 2, 0, 0, 0]);
+        assert_eq!(data.len(), bell + 12);
+
+        let read = blocks(&data).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0], (0, &prelude[..]));
+        assert_eq!(read[1].1, [2]);
+        assert_eq!(blocks(&data[..data.len() - 1]).unwrap().len(), 1);
+        assert!(blocks(b"RFB 003.008\n").is_err());
+
+        let mut corrupt = data[..bell].to_vec();
+        write_block(&mut corrupt, 0, &[2]).unwrap();
+        corrupt[bell..bell + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(blocks(&corrupt).unwrap(), [(0, &prelude[..])]);
+    }
+}
@@ -0,0 +1,464 @@
+/*
+ * Touch gestures for panels without mouse or keyboard.
+ *
+ * `Touches` follows the contacts of a touchscreen, using the multi-touch
+ * slot protocol if the device has it and BTN_TOUCH otherwise. `Gestures`
+ * turns the contacts into gestures:
+ *
+ *   tap                  left click
+ *   long press           right click
+ *   drag                 pan the local view
+ *   two finger drag      wheel scrolling
+ *   pinch                zoom the local view
+ *
+ * All positions are in local display coordinates. `apply` sends clicks and
+ * scrolling to the server and changes the view for panning and zooming.
+ */
+
+use crate::evdev::{self, InputEvent};
+use crate::pointer::AbsAxes;
+use crate::rfb::ButtonMask;
+use crate::runtime::InputSender;
+use crate::view::ViewTransform;
+use std::time::{Duration, Instant};
+
+pub const TAP_TIME: Duration = Duration::from_millis(300);
+pub const LONG_PRESS_TIME: Duration = Duration::from_millis(600);
+/**
+ * How far a finger can move, in pixels, and still count as holding still.
+ */
+pub const SLOP: f64 = 12.0;
+/**
+ * Finger movement for a single wheel click.
+ */
+pub const SCROLL_STEP: f64 = 30.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gesture {
+    Click(f64, f64),
+    RightClick(f64, f64),
+    Scroll(ButtonMask, f64, f64),
+    Pan(f64, f64),
+    Zoom(f64, f64, f64),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Slot {
+    tracking_id: i32,
+    x: i32,
+    y: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Touches {
+    axes: AbsAxes,
+    multi_touch: bool,
+    slot: usize,
+    slots: Vec<Slot>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    Pressed {
+        x: f64,
+        y: f64,
+        since: Instant,
+    },
+    Panning {
+        x: f64,
+        y: f64,
+    },
+    TwoFingers {
+        x: f64,
+        y: f64,
+        distance: f64,
+    },
+    Scrolling {
+        x: f64,
+        y: f64,
+    },
+    Pinching {
+        distance: f64,
+    },
+    /**
+     * The gesture is over, ignore contacts until all are lifted.
+     */
+    Done,
+}
+
+#[derive(Clone, Debug)]
+pub struct Gestures {
+    state: State,
+}
+
+impl Touches {
+    /**
+     * `axes` are ABS_MT_POSITION_X/Y for multi-touch devices and ABS_X/Y
+     * otherwise.
+     */
+    pub fn new(axes: AbsAxes, multi_touch: bool) -> Self {
+        Self {
+            axes,
+            multi_touch,
+            slot: 0,
+            slots: vec![
+                Slot {
+                    tracking_id: -1,
+                    ..Default::default()
+                };
+                1
+            ],
+        }
+    }
+
+    /**
+     * Feed an event. On SYN_REPORT, returns the current contacts.
+     */
+    pub fn handle(&mut self, event: &InputEvent, view: &ViewTransform) -> Option<Vec<(f64, f64)>> {
+        let multi_touch = self.multi_touch;
+        match (event.event_type, event.code) {
+            (evdev::EV_ABS, evdev::ABS_MT_SLOT) if multi_touch => {
+                self.slot = event.value.max(0) as usize;
+                if self.slot >= self.slots.len() {
+                    self.slots.resize(
+                        self.slot + 1,
+                        Slot {
+                            tracking_id: -1,
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+            (evdev::EV_ABS, evdev::ABS_MT_TRACKING_ID) if multi_touch => {
+                self.slots[self.slot].tracking_id = event.value
+            }
+            (evdev::EV_ABS, evdev::ABS_MT_POSITION_X) if multi_touch => {
+                self.slots[self.slot].x = event.value
+            }
+            (evdev::EV_ABS, evdev::ABS_MT_POSITION_Y) if multi_touch => {
+                self.slots[self.slot].y = event.value
+            }
+            (evdev::EV_ABS, evdev::ABS_X) if !multi_touch => self.slots[0].x = event.value,
+            (evdev::EV_ABS, evdev::ABS_Y) if !multi_touch => self.slots[0].y = event.value,
+            (evdev::EV_KEY, evdev::BTN_TOUCH) if !multi_touch => {
+                self.slots[0].tracking_id = if event.value != 0 { 0 } else { -1 }
+            }
+            (evdev::EV_SYN, evdev::SYN_REPORT) => {
+                return Some(
+                    self.slots
+                        .iter()
+                        .filter(|slot| slot.tracking_id >= 0)
+                        .map(|slot| self.axes.local_position(slot.x, slot.y, view))
+                        .collect(),
+                );
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+impl Gestures {
+    pub fn new() -> Self {
+        Self { state: State::Idle }
+    }
+
+    /**
+     * Feed the contacts after a change.
+     */
+    pub fn update(&mut self, contacts: &[(f64, f64)], now: Instant) -> Vec<Gesture> {
+        let mut gestures = Vec::new();
+        self.state = match (self.state, contacts) {
+            (State::Pressed { x, y, since }, []) => {
+                if now.duration_since(since) < TAP_TIME {
+                    gestures.push(Gesture::Click(x, y));
+                }
+                State::Idle
+            }
+            (_, []) => State::Idle,
+            (State::Idle, &[(x, y)]) => State::Pressed { x, y, since: now },
+            (State::Pressed { x, y, since }, &[pos]) => {
+                if distance((x, y), pos) > SLOP {
+                    gestures.push(Gesture::Pan(pos.0 - x, pos.1 - y));
+                    State::Panning { x: pos.0, y: pos.1 }
+                } else {
+                    State::Pressed { x, y, since }
+                }
+            }
+            (State::Panning { x, y }, &[pos]) => {
+                gestures.push(Gesture::Pan(pos.0 - x, pos.1 - y));
+                State::Panning { x: pos.0, y: pos.1 }
+            }
+            (State::Idle | State::Pressed { .. } | State::Panning { .. }, &[a, b, ..]) => {
+                State::TwoFingers {
+                    x: (a.0 + b.0) / 2.0,
+                    y: (a.1 + b.1) / 2.0,
+                    distance: distance(a, b),
+                }
+            }
+            (
+                State::TwoFingers {
+                    x,
+                    y,
+                    distance: start,
+                },
+                &[a, b, ..],
+            ) => {
+                let centre = ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0);
+                let current = distance(a, b);
+                if (current - start).abs() > SLOP {
+                    if start > 0.0 {
+                        gestures.push(Gesture::Zoom(current / start, centre.0, centre.1));
+                    }
+                    State::Pinching { distance: current }
+                } else if distance((x, y), centre) > SLOP {
+                    State::Scrolling { x, y }
+                } else {
+                    State::TwoFingers {
+                        x,
+                        y,
+                        distance: start,
+                    }
+                }
+            }
+            (State::Scrolling { x, y }, &[a, b, ..]) => {
+                let centre = ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0);
+                // Content follows the fingers, like scrolling a page by
+                // dragging it: moving up scrolls down.
+                let steps = [
+                    (
+                        (y - centre.1) / SCROLL_STEP,
+                        ButtonMask::WHEEL_DOWN,
+                        ButtonMask::WHEEL_UP,
+                    ),
+                    (
+                        (x - centre.0) / SCROLL_STEP,
+                        ButtonMask::WHEEL_RIGHT,
+                        ButtonMask::WHEEL_LEFT,
+                    ),
+                ];
+                let mut next = (x, y);
+                for (i, (steps, positive, negative)) in steps.into_iter().enumerate() {
+                    let clicks = steps.trunc() as i32;
+                    let wheel = if clicks > 0 { positive } else { negative };
+                    for _ in 0..clicks.abs() {
+                        gestures.push(Gesture::Scroll(wheel, centre.0, centre.1));
+                    }
+                    let moved = clicks as f64 * SCROLL_STEP;
+                    if i == 0 {
+                        next.1 -= moved;
+                    } else {
+                        next.0 -= moved;
+                    }
+                }
+                State::Scrolling {
+                    x: next.0,
+                    y: next.1,
+                }
+            }
+            (State::Pinching { distance: previous }, &[a, b, ..]) => {
+                let current = distance(a, b);
+                if previous > 0.0 && current > 0.0 {
+                    gestures.push(Gesture::Zoom(
+                        current / previous,
+                        (a.0 + b.0) / 2.0,
+                        (a.1 + b.1) / 2.0,
+                    ));
+                }
+                State::Pinching { distance: current }
+            }
+            _ => State::Done,
+        };
+        gestures
+    }
+
+    /**
+     * Call when `timeout` expires, to recognise a long press.
+     */
+    pub fn tick(&mut self, now: Instant) -> Vec<Gesture> {
+        match self.state {
+            State::Pressed { x, y, since } if now.duration_since(since) >= LONG_PRESS_TIME => {
+                self.state = State::Done;
+                vec![Gesture::RightClick(x, y)]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /**
+     * Time until `tick` needs to be called, if a finger is held down.
+     */
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        match self.state {
+            State::Pressed { since, .. } => {
+                Some((since + LONG_PRESS_TIME).saturating_duration_since(now))
+            }
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Send a gesture to the server, or apply it to the view.
+ */
+pub fn apply(
+    gesture: Gesture,
+    view: &mut ViewTransform,
+    input: &InputSender,
+) -> Result<(), std::io::Error> {
+    match gesture {
+        Gesture::Click(x, y) | Gesture::RightClick(x, y) => {
+            let button = match gesture {
+                Gesture::Click(..) => ButtonMask::LEFT,
+                _ => ButtonMask::RIGHT,
+            };
+            let (x, y) = view.to_remote(x, y);
+            input.pointer_event(ButtonMask::NONE, x, y)?;
+            input.pointer_event(button, x, y)?;
+            input.pointer_event(ButtonMask::NONE, x, y)?;
+        }
+        Gesture::Scroll(wheel, x, y) => {
+            let (x, y) = view.to_remote(x, y);
+            input.scroll(wheel, ButtonMask::NONE, x, y)?;
+        }
+        Gesture::Pan(dx, dy) => view.pan(dx, dy),
+        Gesture::Zoom(factor, x, y) => view.zoom(factor, x, y),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibrate::Calibration;
+    use crate::pointer::AbsRange;
+
+    #[test]
+    fn test_touches() {
+        let view = ViewTransform::identity(101, 51);
+        let range = AbsRange { min: 0, max: 1000 };
+        let axes = AbsAxes {
+            x: range,
+            y: range,
+            calibration: Calibration::IDENTITY,
+        };
+        let mut touches = Touches::new(axes, true);
+        let events = [
+            (evdev::ABS_MT_SLOT, 0),
+            (evdev::ABS_MT_TRACKING_ID, 5),
+            (evdev::ABS_MT_POSITION_X, 100),
+            (evdev::ABS_MT_POSITION_Y, 200),
+            (evdev::ABS_MT_SLOT, 1),
+            (evdev::ABS_MT_TRACKING_ID, 6),
+            (evdev::ABS_MT_POSITION_X, 1000),
+            (evdev::ABS_MT_POSITION_Y, 0),
+        ];
+        for (code, value) in events {
+            let event = InputEvent {
+                event_type: evdev::EV_ABS,
+                code,
+                value,
+            };
+            assert_eq!(touches.handle(&event, &view), None);
+        }
+        let syn = InputEvent {
+            event_type: evdev::EV_SYN,
+            code: evdev::SYN_REPORT,
+            value: 0,
+        };
+        assert_eq!(
+            touches.handle(&syn, &view),
+            Some(vec![(10.0, 10.0), (100.0, 0.0)])
+        );
+        let lift = InputEvent {
+            event_type: evdev::EV_ABS,
+            code: evdev::ABS_MT_TRACKING_ID,
+            value: -1,
+        };
+        touches.handle(&lift, &view);
+        assert_eq!(touches.handle(&syn, &view), Some(vec![(10.0, 10.0)]));
+    }
+
+    #[test]
+    fn test_tap_and_long_press() {
+        let start = Instant::now();
+        let mut gestures = Gestures::new();
+        assert_eq!(gestures.update(&[(10.0, 20.0)], start), []);
+        assert_eq!(gestures.timeout(start), Some(LONG_PRESS_TIME));
+        assert_eq!(gestures.update(&[(12.0, 21.0)], start), []);
+        assert_eq!(
+            gestures.update(&[], start + Duration::from_millis(100)),
+            [Gesture::Click(10.0, 20.0)]
+        );
+
+        assert_eq!(gestures.update(&[(10.0, 20.0)], start), []);
+        assert_eq!(gestures.tick(start + Duration::from_millis(100)), []);
+        assert_eq!(
+            gestures.tick(start + LONG_PRESS_TIME),
+            [Gesture::RightClick(10.0, 20.0)]
+        );
+        assert_eq!(gestures.timeout(start + LONG_PRESS_TIME), None);
+        assert_eq!(gestures.update(&[], start + LONG_PRESS_TIME), []);
+
+        assert_eq!(gestures.update(&[(10.0, 20.0)], start), []);
+        assert_eq!(gestures.update(&[], start + Duration::from_millis(400)), []);
+    }
+
+    #[test]
+    fn test_pan() {
+        let start = Instant::now();
+        let mut gestures = Gestures::new();
+        gestures.update(&[(10.0, 20.0)], start);
+        assert_eq!(
+            gestures.update(&[(30.0, 20.0)], start),
+            [Gesture::Pan(20.0, 0.0)]
+        );
+        assert_eq!(
+            gestures.update(&[(31.0, 25.0)], start),
+            [Gesture::Pan(1.0, 5.0)]
+        );
+        assert_eq!(gestures.update(&[], start), []);
+    }
+
+    #[test]
+    fn test_two_finger_scroll() {
+        let start = Instant::now();
+        let mut gestures = Gestures::new();
+        gestures.update(&[(10.0, 100.0)], start);
+        assert_eq!(gestures.update(&[(10.0, 100.0), (50.0, 100.0)], start), []);
+        assert_eq!(gestures.update(&[(10.0, 80.0), (50.0, 80.0)], start), []);
+        assert_eq!(
+            gestures.update(&[(10.0, 35.0), (50.0, 35.0)], start),
+            [
+                Gesture::Scroll(ButtonMask::WHEEL_DOWN, 30.0, 35.0),
+                Gesture::Scroll(ButtonMask::WHEEL_DOWN, 30.0, 35.0),
+            ]
+        );
+        assert_eq!(gestures.update(&[(10.0, 20.0), (50.0, 20.0)], start), []);
+        assert_eq!(gestures.update(&[(10.0, 20.0)], start), []);
+        assert_eq!(gestures.update(&[], start), []);
+    }
+
+    #[test]
+    fn test_pinch() {
+        let start = Instant::now();
+        let mut gestures = Gestures::new();
+        gestures.update(&[(40.0, 50.0), (60.0, 50.0)], start);
+        assert_eq!(gestures.update(&[(45.0, 50.0), (60.0, 50.0)], start), []);
+        assert_eq!(
+            gestures.update(&[(30.0, 50.0), (70.0, 50.0)], start),
+            [Gesture::Zoom(2.0, 50.0, 50.0)]
+        );
+        assert_eq!(
+            gestures.update(&[(20.0, 50.0), (100.0, 50.0)], start),
+            [Gesture::Zoom(2.0, 60.0, 50.0)]
+        );
+        assert_eq!(gestures.update(&[(20.0, 50.0)], start), []);
+        assert_eq!(gestures.update(&[], start), []);
+    }
+}
@@ -0,0 +1,229 @@
+/*
+ * Sans-IO client side of the RFB 3.8 handshake, shared by `Session` and
+ * `AsyncSession`.
+ *
+ * The handshake is driven by calling `step` with the bytes it asked for.
+ * Every call may queue bytes for the server in `out`, which the caller
+ * writes before reading the exact number of bytes the returned
+ * `Step::Read` asks for. The first call takes no input. When the server
+ * insists on VncAuth, `Step::Password` asks the caller for the password,
+ * which goes to `password` instead.
+ */
+
+use crate::rfb::{self, PixelFormat, RfbVersion, SecurityType};
+use crate::session::HandshakeError;
+
+const MAX_REASON_LEN: usize = 1000;
+const MAX_NAME_LEN: usize = 1000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    Read(usize),
+    Password,
+    Done(ServerInit),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerInit {
+    pub width: u16,
+    pub height: u16,
+    pub pixel_format: PixelFormat,
+    pub name: String,
+}
+
+#[derive(Debug)]
+enum State {
+    Start,
+    Version,
+    SecurityCount,
+    ReasonLength,
+    Reason,
+    SecurityTypes,
+    Challenge,
+    Password([u8; 16]),
+    SecurityResult,
+    ServerInit,
+    Name(u16, u16, PixelFormat),
+    Finished,
+}
+
+#[derive(Debug)]
+pub struct Handshake {
+    state: State,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handshake {
+    pub fn new() -> Self {
+        Self {
+            state: State::Start,
+        }
+    }
+
+    /**
+     * Take the bytes asked for by the previous step and say what comes next.
+     */
+    pub fn step(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<Step, HandshakeError> {
+        let u32_at = |pos: usize| u32::from_be_bytes(input[pos..pos + 4].try_into().unwrap());
+        let (state, step) = match std::mem::replace(&mut self.state, State::Finished) {
+            State::Start => (State::Version, Step::Read(12)),
+            State::Version => {
+                if rfb::parse_offered_version(input) != RfbVersion::Rfb38 {
+                    return Err(HandshakeError::UnsupportedRfbVersion);
+                }
+                out.extend_from_slice(b"RFB 003.008\n");
+                (State::SecurityCount, Step::Read(1))
+            }
+            State::SecurityCount if input[0] == 0 => (State::ReasonLength, Step::Read(4)),
+            State::SecurityCount => (State::SecurityTypes, Step::Read(input[0] as usize)),
+            State::ReasonLength => {
+                let len = (u32_at(0) as usize).min(MAX_REASON_LEN);
+                (State::Reason, Step::Read(len))
+            }
+            State::Reason => {
+                return Err(HandshakeError::UnsupportedSecurity(
+                    String::from_utf8_lossy(input).into_owned(),
+                ))
+            }
+            State::SecurityTypes => match rfb::preferred_security_type(input) {
+                SecurityType::None => {
+                    out.push(SecurityType::None as u8);
+                    (State::SecurityResult, Step::Read(4))
+                }
+                SecurityType::VncAuth => {
+                    out.push(SecurityType::VncAuth as u8);
+                    (State::Challenge, Step::Read(16))
+                }
+                SecurityType::Invalid => {
+                    return Err(HandshakeError::UnsupportedSecurity(
+                        "No suitable security".to_string(),
+                    ))
+                }
+            },
+            State::Challenge => (State::Password(input.try_into().unwrap()), Step::Password),
+            State::SecurityResult => match u32_at(0) {
+                0 => {
+                    // ClientInit, not sharing the desktop.
+                    out.push(0);
+                    (State::ServerInit, Step::Read(24))
+                }
+                result => {
+                    return Err(HandshakeError::UnsupportedSecurity(format!(
+                        "Security type not accepted by server ({})",
+                        result
+                    )))
+                }
+            },
+            State::ServerInit => {
+                let width = u16::from_be_bytes([input[0], input[1]]);
+                let height = u16::from_be_bytes([input[2], input[3]]);
+                let format = <[u8; 16]>::try_from(&input[4..20]).unwrap().into();
+                let name_len = u32_at(20) as usize;
+                if name_len > MAX_NAME_LEN {
+                    return Err(HandshakeError::UnsupportedServerSettings(
+                        "Too long name".to_string(),
+                    ));
+                }
+                (State::Name(width, height, format), Step::Read(name_len))
+            }
+            State::Name(width, height, pixel_format) => (
+                State::Finished,
+                Step::Done(ServerInit {
+                    width,
+                    height,
+                    pixel_format,
+                    name: String::from_utf8_lossy(input).into_owned(),
+                }),
+            ),
+            State::Password(_) | State::Finished => panic!("handshake step out of order"),
+        };
+        self.state = state;
+        Ok(step)
+    }
+
+    /**
+     * Answer the VncAuth challenge, after `Step::Password`.
+     */
+    pub fn password(&mut self, password: &str, out: &mut Vec<u8>) -> Step {
+        let State::Password(challenge) = self.state else {
+            panic!("no password asked for");
+        };
+        out.extend_from_slice(&rfb::vnc_auth_response(password, &challenge));
+        self.state = State::SecurityResult;
+        Step::Read(4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+
+    #[test]
+    fn test_vnc_auth() {
+        let mut handshake = Handshake::new();
+        let mut out = Vec::new();
+        assert_eq!(handshake.step(&[], &mut out).unwrap(), Step::Read(12));
+        assert_eq!(
+            handshake.step(b"RFB 003.008\n", &mut out).unwrap(),
+            Step::Read(1)
+        );
+        assert_eq!(handshake.step(&[2], &mut out).unwrap(), Step::Read(2));
+        assert_eq!(handshake.step(&[16, 2], &mut out).unwrap(), Step::Read(16));
+        assert_eq!(handshake.step(&[7; 16], &mut out).unwrap(), Step::Password);
+        assert_eq!(handshake.password("secret", &mut out), Step::Read(4));
+        assert_eq!(handshake.step(&[0; 4], &mut out).unwrap(), Step::Read(24));
+
+        let mut server_init = vec![0, 4, 0, 2];
+        server_init.extend_from_slice(&<[u8; 16]>::from(&Session::PREFERRED_PIXEL_FORMAT));
+        server_init.extend_from_slice(&[0, 0, 0, 3]);
+        assert_eq!(
+            handshake.step(&server_init, &mut out).unwrap(),
+            Step::Read(3)
+        );
+        assert_eq!(
+            handshake.step(b"vnc", &mut out).unwrap(),
+            Step::Done(ServerInit {
+                width: 4,
+                height: 2,
+                pixel_format: Session::PREFERRED_PIXEL_FORMAT,
+                name: "vnc".to_string(),
+            })
+        );
+
+        let mut expected = b"RFB 003.008\n\x02".to_vec();
+        // DES of the challenge with the bit reversed password as key.
+        expected.extend_from_slice(&[0x02, 0xb0, 0x48, 0xd0, 0x34, 0x8e, 0x64, 0x76].repeat(2));
+        expected.push(0);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut handshake = Handshake::new();
+        let mut out = Vec::new();
+        handshake.step(&[], &mut out).unwrap();
+        assert!(matches!(
+            handshake.step(b"RFB 003.003\n", &mut out),
+            Err(HandshakeError::UnsupportedRfbVersion)
+        ));
+
+        let mut handshake = Handshake::new();
+        handshake.step(&[], &mut out).unwrap();
+        handshake.step(b"RFB 003.008\n", &mut out).unwrap();
+        assert_eq!(handshake.step(&[0], &mut out).unwrap(), Step::Read(4));
+        assert_eq!(
+            handshake.step(&[0, 0, 0x10, 0], &mut out).unwrap(),
+            Step::Read(MAX_REASON_LEN)
+        );
+        match handshake.step(b"busy", &mut out) {
+            Err(HandshakeError::UnsupportedSecurity(reason)) => assert_eq!(reason, "busy"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
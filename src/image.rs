@@ -0,0 +1,400 @@
+/*
+ * Writing the canvas as PPM or PNG image.
+ *
+ * PNG images are compressed with a single deflate block using the fixed
+ * Huffman codes, with matches found through short hash chains. That keeps
+ * the encoder small and fast, and desktops with their large areas of one
+ * colour still shrink a lot. Use an external tool to recompress them if
+ * size matters.
+ */
+
+use crate::canvas::Canvas;
+use std::io::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Ppm,
+    Png,
+}
+
+impl ImageFormat {
+    /**
+     * PPM for a file name ending in ".ppm", PNG otherwise.
+     */
+    pub fn for_path(path: &std::path::Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("ppm") => ImageFormat::Ppm,
+            _ => ImageFormat::Png,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Ppm => "ppm",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/** Deflate window, the farthest a match may reach back. */
+const WINDOW: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+/** Candidates tried for each match, trading size for speed. */
+const MAX_CHAIN: usize = 32;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/**
+ * Bits packed least significant first, as deflate stores them.
+ */
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+fn rgb_rows(canvas: &Canvas) -> impl Iterator<Item = Vec<u8>> + '_ {
+    canvas
+        .pixels()
+        .chunks(canvas.width().max(1) as usize)
+        .map(|row| {
+            row.iter()
+                .flat_map(|&rgb| [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8])
+                .collect()
+        })
+}
+
+pub fn write<W: Write>(canvas: &Canvas, format: ImageFormat, out: W) -> Result<(), std::io::Error> {
+    match format {
+        ImageFormat::Ppm => write_ppm(canvas, out),
+        ImageFormat::Png => write_png(canvas, out),
+    }
+}
+
+/**
+ * Binary PPM (P6) with 8 bits per channel.
+ */
+pub fn write_ppm<W: Write>(canvas: &Canvas, mut out: W) -> Result<(), std::io::Error> {
+    write!(out, "P6\n{} {}\n255\n", canvas.width(), canvas.height())?;
+    if canvas.width() > 0 {
+        for row in rgb_rows(canvas) {
+            out.write_all(&row)?;
+        }
+    }
+    Ok(())
+}
+
+/**
+ * 8 bit RGB PNG.
+ */
+pub fn write_png<W: Write>(canvas: &Canvas, mut out: W) -> Result<(), std::io::Error> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(canvas.width() as u32).to_be_bytes());
+    header.extend_from_slice(&(canvas.height() as u32).to_be_bytes());
+    // Bit depth 8, colour type RGB, deflate, adaptive filtering, no interlace.
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut raw = Vec::with_capacity(canvas.height() as usize * (1 + canvas.width() as usize * 3));
+    if canvas.width() > 0 {
+        for row in rgb_rows(canvas) {
+            raw.push(0);
+            raw.extend_from_slice(&row);
+        }
+    }
+
+    out.write_all(&PNG_SIGNATURE)?;
+    write_chunk(&mut out, b"IHDR", &header)?;
+    write_chunk(&mut out, b"IDAT", &zlib(&raw))?;
+    write_chunk(&mut out, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> Result<(), std::io::Error> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(crc32(!0, kind), data);
+    out.write_all(&(!crc).to_be_bytes())
+}
+
+/**
+ * zlib stream of the data compressed in one fixed Huffman deflate block.
+ */
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    bits.out.extend_from_slice(&[0x78, 0x01]);
+    // Final block, fixed Huffman codes.
+    bits.put(0b011, 3);
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW];
+    let mut pos = 0;
+    while pos < data.len() {
+        let (len, distance) = longest_match(data, pos, &head, &prev);
+        if len >= MIN_MATCH {
+            bits.put_length(len);
+            bits.put_distance(distance);
+            for p in pos..pos + len {
+                insert(data, p, &mut head, &mut prev);
+            }
+            pos += len;
+        } else {
+            bits.put_literal(data[pos] as u16);
+            insert(data, pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+    bits.put_literal(256);
+    let mut out = bits.finish();
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let hash = ((bytes[0] as usize) << 10) ^ ((bytes[1] as usize) << 5) ^ bytes[2] as usize;
+    hash & ((1 << HASH_BITS) - 1)
+}
+
+/**
+ * Chain the data at `pos` into the positions with the same hash.
+ */
+fn insert(data: &[u8], pos: usize, head: &mut [usize], prev: &mut [usize]) {
+    if pos + MIN_MATCH <= data.len() {
+        let hash = hash(&data[pos..]);
+        prev[pos % WINDOW] = head[hash];
+        head[hash] = pos;
+    }
+}
+
+/**
+ * Length and distance of the longest earlier match for the data at `pos`,
+ * following the chain of positions with the same hash.
+ */
+fn longest_match(data: &[u8], pos: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    let max = (data.len() - pos).min(MAX_MATCH);
+    if max < MIN_MATCH {
+        return (0, 0);
+    }
+    let (mut best_len, mut best_distance) = (0, 0);
+    let mut candidate = head[hash(&data[pos..])];
+    for _ in 0..MAX_CHAIN {
+        if candidate == usize::MAX || pos - candidate > WINDOW {
+            break;
+        }
+        let len = data[candidate..]
+            .iter()
+            .zip(&data[pos..pos + max])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if len > best_len {
+            (best_len, best_distance) = (len, pos - candidate);
+            if len == max {
+                break;
+            }
+        }
+        candidate = prev[candidate % WINDOW];
+    }
+    (best_len, best_distance)
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, len: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += len;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /**
+     * Huffman codes go most significant bit first.
+     */
+    fn put_code(&mut self, code: u32, len: u32) {
+        self.put(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn put_literal(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.put_code(0x30 + symbol, 8),
+            144..=255 => self.put_code(0x190 + symbol - 144, 9),
+            256..=279 => self.put_code(symbol - 256, 7),
+            _ => self.put_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn put_length(&mut self, len: usize) {
+        let i = LENGTH_BASE.partition_point(|&base| base as usize <= len) - 1;
+        self.put_literal(257 + i as u16);
+        self.put(
+            (len - LENGTH_BASE[i] as usize) as u32,
+            LENGTH_EXTRA[i] as u32,
+        );
+    }
+
+    fn put_distance(&mut self, distance: usize) {
+        let i = DISTANCE_BASE.partition_point(|&base| base as usize <= distance) - 1;
+        self.put_code(i as u32, 5);
+        self.put(
+            (distance - DISTANCE_BASE[i] as usize) as u32,
+            DISTANCE_EXTRA[i] as u32,
+        );
+    }
+
+    /**
+     * The bytes written, with the last one padded.
+     */
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::Rect;
+
+    #[test]
+    fn test_ppm() {
+        let mut canvas = Canvas::new(2, 1);
+        canvas.fill_rect(Rect::new(1, 0, 1, 1), 0x123456);
+        let mut out = Vec::new();
+        write_ppm(&canvas, &mut out).unwrap();
+        assert_eq!(out, b"P6\n2 1\n255\n\x00\x00\x00\x12\x34\x56");
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(!crc32(!0, b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    /**
+     * Inflate a zlib stream of one fixed Huffman block, all that `zlib`
+     * writes.
+     */
+    fn inflate(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!(zlib[..2], [0x78, 0x01]);
+        let mut pos = 16;
+        let mut bit = || {
+            let bit = (zlib[pos / 8] >> (pos % 8)) & 1;
+            pos += 1;
+            bit as u32
+        };
+        let bits = |n: u8, bit: &mut dyn FnMut() -> u32| (0..n).fold(0, |v, i| v | bit() << i);
+        let code = |n: u32, start: u32, bit: &mut dyn FnMut() -> u32| {
+            (0..n).fold(start, |code, _| code << 1 | bit())
+        };
+        assert_eq!(bits(3, &mut bit), 0b011);
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let mut symbol = code(7, 0, &mut bit);
+            symbol = match symbol {
+                0..=0x17 => symbol + 256,
+                _ => match code(1, symbol, &mut bit) {
+                    c @ 0x30..=0xbf => c - 0x30,
+                    c @ 0xc0..=0xc7 => c - 0xc0 + 280,
+                    c => code(1, c, &mut bit) - 0x190 + 144,
+                },
+            };
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => break,
+                _ => {
+                    let i = symbol as usize - 257;
+                    let len = LENGTH_BASE[i] as u32 + bits(LENGTH_EXTRA[i], &mut bit);
+                    let d = code(5, 0, &mut bit) as usize;
+                    let distance = DISTANCE_BASE[d] as u32 + bits(DISTANCE_EXTRA[d], &mut bit);
+                    for _ in 0..len {
+                        out.push(out[out.len() - distance as usize]);
+                    }
+                }
+            }
+        }
+        let end = pos.div_ceil(8);
+        assert_eq!(zlib[end..], adler32(&out).to_be_bytes());
+        out
+    }
+
+    #[test]
+    fn test_png() {
+        let mut canvas = Canvas::new(1, 1);
+        canvas.fill_rect(Rect::new(0, 0, 1, 1), 0xff0000);
+        let mut out = Vec::new();
+        write_png(&canvas, &mut out).unwrap();
+        assert_eq!(out[..8], PNG_SIGNATURE);
+        assert_eq!(out[8..16], [0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(out[16..29], [0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+        let len = u32::from_be_bytes(out[33..37].try_into().unwrap()) as usize;
+        assert_eq!(out[37..41], *b"IDAT");
+        assert_eq!(inflate(&out[41..41 + len]), [0, 0xff, 0, 0]);
+        assert_eq!(
+            out[out.len() - 12..],
+            [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]
+        );
+    }
+
+    #[test]
+    fn test_compression() {
+        for data in [
+            Vec::new(),
+            b"abcabcabcabd".to_vec(),
+            vec![7; 100_000],
+            (0..200_000u32)
+                .map(|i| (i.wrapping_mul(i) / 7 % 251) as u8)
+                .collect(),
+        ] {
+            assert_eq!(inflate(&zlib(&data)), data);
+        }
+        assert!(zlib(&vec![7; 100_000]).len() < 1000);
+    }
+}
@@ -0,0 +1,513 @@
+/*
+ * Translation of Linux keycodes to X11 keysyms.
+ *
+ * A layout lists the keysyms of every key by shift level. Level 1 is the
+ * plain key, level 2 is with Shift. `translate` picks the level from the
+ * modifier state and is a pure function, `Keyboard` wraps it with the
+ * modifier tracking needed for a stream of press and release events.
+ *
+ * Layout files have one key per line: the Linux keycode followed by the
+ * keysym names of its levels, e.g. "16 q Q". Lines starting with '#' are
+ * comments. Compiled XKB keymaps can be loaded as well, see `xkb`.
+ */
+
+use crate::compose;
+use crate::keysym;
+use crate::xkb;
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Layout {
+    keys: HashMap<u16, Vec<u32>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub meta: bool,
+    pub level3: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+}
+
+#[derive(Debug)]
+pub struct Keyboard {
+    layout: Layout,
+    modifiers: Modifiers,
+    pressed: HashMap<u16, u32>,
+    pending_dead: Option<u32>,
+}
+
+const US: &[(u16, &str, &str)] = &[
+    (1, "Escape", ""),
+    (2, "1", "exclam"),
+    (3, "2", "at"),
+    (4, "3", "numbersign"),
+    (5, "4", "dollar"),
+    (6, "5", "percent"),
+    (7, "6", "asciicircum"),
+    (8, "7", "ampersand"),
+    (9, "8", "asterisk"),
+    (10, "9", "parenleft"),
+    (11, "0", "parenright"),
+    (12, "minus", "underscore"),
+    (13, "equal", "plus"),
+    (14, "BackSpace", ""),
+    (15, "Tab", "ISO_Left_Tab"),
+    (16, "q", "Q"),
+    (17, "w", "W"),
+    (18, "e", "E"),
+    (19, "r", "R"),
+    (20, "t", "T"),
+    (21, "y", "Y"),
+    (22, "u", "U"),
+    (23, "i", "I"),
+    (24, "o", "O"),
+    (25, "p", "P"),
+    (26, "bracketleft", "braceleft"),
+    (27, "bracketright", "braceright"),
+    (28, "Return", ""),
+    (29, "Control_L", ""),
+    (30, "a", "A"),
+    (31, "s", "S"),
+    (32, "d", "D"),
+    (33, "f", "F"),
+    (34, "g", "G"),
+    (35, "h", "H"),
+    (36, "j", "J"),
+    (37, "k", "K"),
+    (38, "l", "L"),
+    (39, "semicolon", "colon"),
+    (40, "apostrophe", "quotedbl"),
+    (41, "grave", "asciitilde"),
+    (42, "Shift_L", ""),
+    (43, "backslash", "bar"),
+    (44, "z", "Z"),
+    (45, "x", "X"),
+    (46, "c", "C"),
+    (47, "v", "V"),
+    (48, "b", "B"),
+    (49, "n", "N"),
+    (50, "m", "M"),
+    (51, "comma", "less"),
+    (52, "period", "greater"),
+    (53, "slash", "question"),
+    (54, "Shift_R", ""),
+    (55, "KP_Multiply", ""),
+    (56, "Alt_L", ""),
+    (57, "space", ""),
+    (58, "Caps_Lock", ""),
+    (59, "F1", ""),
+    (60, "F2", ""),
+    (61, "F3", ""),
+    (62, "F4", ""),
+    (63, "F5", ""),
+    (64, "F6", ""),
+    (65, "F7", ""),
+    (66, "F8", ""),
+    (67, "F9", ""),
+    (68, "F10", ""),
+    (69, "Num_Lock", ""),
+    (70, "Scroll_Lock", ""),
+    (71, "KP_Home", "KP_7"),
+    (72, "KP_Up", "KP_8"),
+    (73, "KP_Prior", "KP_9"),
+    (74, "KP_Subtract", ""),
+    (75, "KP_Left", "KP_4"),
+    (76, "KP_Begin", "KP_5"),
+    (77, "KP_Right", "KP_6"),
+    (78, "KP_Add", ""),
+    (79, "KP_End", "KP_1"),
+    (80, "KP_Down", "KP_2"),
+    (81, "KP_Next", "KP_3"),
+    (82, "KP_Insert", "KP_0"),
+    (83, "KP_Delete", "KP_Decimal"),
+    (86, "less", "greater"),
+    (87, "F11", ""),
+    (88, "F12", ""),
+    (96, "KP_Enter", ""),
+    (97, "Control_R", ""),
+    (98, "KP_Divide", ""),
+    (99, "Print", "Sys_Req"),
+    (100, "Alt_R", ""),
+    (102, "Home", ""),
+    (103, "Up", ""),
+    (104, "Prior", ""),
+    (105, "Left", ""),
+    (106, "Right", ""),
+    (107, "End", ""),
+    (108, "Down", ""),
+    (109, "Next", ""),
+    (110, "Insert", ""),
+    (111, "Delete", ""),
+    (117, "KP_Equal", ""),
+    (119, "Pause", "Break"),
+    (125, "Super_L", ""),
+    (126, "Super_R", ""),
+    (127, "Menu", ""),
+];
+
+impl Layout {
+    /**
+     * The US (pc105) layout.
+     */
+    pub fn us() -> Self {
+        let mut layout = Layout::default();
+        for &(keycode, base, shifted) in US {
+            let levels = [base, shifted]
+                .iter()
+                .filter(|name| !name.is_empty())
+                .map(|name| keysym::from_name(name).expect("keysym in US layout"))
+                .collect();
+            layout.keys.insert(keycode, levels);
+        }
+        layout
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut layout = Layout::default();
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let keycode = fields
+                .next()
+                .and_then(|field| field.parse().ok())
+                .ok_or(format!("line {}: invalid keycode", line_no + 1))?;
+            let levels = fields
+                .map(|name| {
+                    keysym::from_name(name).ok_or(format!(
+                        "line {}: unknown keysym {}",
+                        line_no + 1,
+                        name
+                    ))
+                })
+                .collect::<Result<Vec<u32>, String>>()?;
+            layout.keys.insert(keycode, levels);
+        }
+        Ok(layout)
+    }
+
+    /**
+     * Load a layout file or a compiled XKB keymap.
+     */
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        let text = std::fs::read_to_string(path)?;
+        let layout = if xkb::is_keymap(&text) {
+            xkb::parse(&text)
+        } else {
+            Self::parse(&text)
+        };
+        layout.map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn levels(&self, keycode: u16) -> Option<&[u32]> {
+        self.keys.get(&keycode).map(|levels| levels.as_slice())
+    }
+
+    pub fn set_levels(&mut self, keycode: u16, levels: Vec<u32>) {
+        self.keys.insert(keycode, levels);
+    }
+}
+
+fn is_keypad(keysym: u32) -> bool {
+    (0xff80..=0xffbd).contains(&keysym)
+}
+
+fn is_modifier(keysym: u32) -> bool {
+    matches!(
+        keysym,
+        keysym::SHIFT_L
+            | keysym::SHIFT_R
+            | keysym::CONTROL_L
+            | keysym::CONTROL_R
+            | keysym::CAPS_LOCK
+            | keysym::META_L
+            | keysym::META_R
+            | keysym::ALT_L
+            | keysym::ALT_R
+            | keysym::SUPER_L
+            | keysym::SUPER_R
+            | keysym::ISO_LEVEL3_SHIFT
+            | keysym::MODE_SWITCH
+            | keysym::NUM_LOCK
+    )
+}
+
+fn is_cased_letter(keysym: u32) -> bool {
+    keysym::to_char(keysym).is_some_and(|c| c.is_lowercase())
+}
+
+/**
+ * Keysym produced by a key with the given modifiers held down.
+ */
+pub fn translate(layout: &Layout, keycode: u16, modifiers: &Modifiers) -> Option<u32> {
+    let levels = layout.levels(keycode)?;
+    let base = *levels.first()?;
+    let shifted = match levels.get(1) {
+        Some(&keysym) if is_keypad(keysym) => modifiers.shift ^ modifiers.num_lock,
+        _ if is_cased_letter(base) => modifiers.shift ^ modifiers.caps_lock,
+        _ => modifiers.shift,
+    };
+    let level = shifted as usize + 2 * modifiers.level3 as usize;
+    [level, level & 1, 0]
+        .iter()
+        .filter_map(|&level| levels.get(level).copied())
+        .find(|&keysym| keysym != 0)
+}
+
+impl Keyboard {
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            layout,
+            modifiers: Modifiers::default(),
+            pressed: HashMap::new(),
+            pending_dead: None,
+        }
+    }
+
+    pub fn modifiers(&self) -> &Modifiers {
+        &self.modifiers
+    }
+
+    /**
+     * Handle an EV_KEY event. `value` is 1 for press, 2 for autorepeat and 0
+     * for release. Returns the KeyEvents to send. A release always sends the
+     * keysym of the matching press, even if the modifiers changed in between.
+     *
+     * Dead keys are composed locally: they send nothing themselves, and the
+     * next key sends the accented character instead.
+     */
+    pub fn handle_key(&mut self, keycode: u16, value: i32) -> Vec<(bool, u32)> {
+        match value {
+            0 => {
+                let Some(keysym) = self.pressed.remove(&keycode) else {
+                    return Vec::new();
+                };
+                self.update_modifiers(keysym, false);
+                if compose::is_dead_key(keysym) {
+                    return Vec::new();
+                }
+                vec![(false, keysym)]
+            }
+            1 => {
+                let Some(keysym) = translate(&self.layout, keycode, &self.modifiers) else {
+                    return Vec::new();
+                };
+                let mut events = Vec::new();
+                if let Some(dead) = self.pending_dead.filter(|_| !is_modifier(keysym)) {
+                    self.pending_dead = None;
+                    if let Some(composed) = compose::compose(dead, keysym) {
+                        self.pressed.insert(keycode, composed);
+                        return vec![(true, composed)];
+                    }
+                    if let Some(accent) = compose::spacing_accent(dead) {
+                        events.push((true, accent));
+                        events.push((false, accent));
+                    }
+                }
+                self.pressed.insert(keycode, keysym);
+                self.update_modifiers(keysym, true);
+                if compose::is_dead_key(keysym) {
+                    self.pending_dead = Some(keysym);
+                } else {
+                    events.push((true, keysym));
+                }
+                events
+            }
+            2 => match self.pressed.get(&keycode) {
+                Some(&keysym) if !compose::is_dead_key(keysym) => vec![(true, keysym)],
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /**
+     * Like `handle_key`, but without composing dead keys, for servers that
+     * get scancodes and translate them with their own layout. Keys without a
+     * keysym in the layout are ignored, as a KeyEvent needs a keysym.
+     */
+    pub fn handle_raw_key(&mut self, keycode: u16, value: i32) -> Option<(bool, u32)> {
+        match value {
+            0 => {
+                let keysym = self.pressed.remove(&keycode)?;
+                self.update_modifiers(keysym, false);
+                Some((false, keysym))
+            }
+            1 => {
+                let keysym = translate(&self.layout, keycode, &self.modifiers)?;
+                self.pending_dead = None;
+                self.pressed.insert(keycode, keysym);
+                self.update_modifiers(keysym, true);
+                Some((true, keysym))
+            }
+            2 => self.pressed.get(&keycode).map(|&keysym| (true, keysym)),
+            _ => None,
+        }
+    }
+
+    /**
+     * Update the modifiers after `keysym` was pressed or released, once
+     * `pressed` reflects it. A modifier stays held while any key producing
+     * it is down, e.g. one Shift after the other was released.
+     */
+    fn update_modifiers(&mut self, keysym: u32, down: bool) {
+        let modifiers = &mut self.modifiers;
+        match keysym {
+            keysym::CAPS_LOCK if down => modifiers.caps_lock = !modifiers.caps_lock,
+            keysym::NUM_LOCK if down => modifiers.num_lock = !modifiers.num_lock,
+            _ => {}
+        }
+        let held = |keysyms: &[u32]| self.pressed.values().any(|k| keysyms.contains(k));
+        modifiers.shift = held(&[keysym::SHIFT_L, keysym::SHIFT_R]);
+        modifiers.ctrl = held(&[keysym::CONTROL_L, keysym::CONTROL_R]);
+        modifiers.alt = held(&[keysym::ALT_L, keysym::ALT_R]);
+        modifiers.meta = held(&[
+            keysym::META_L,
+            keysym::META_R,
+            keysym::SUPER_L,
+            keysym::SUPER_R,
+        ]);
+        modifiers.level3 = held(&[keysym::ISO_LEVEL3_SHIFT, keysym::MODE_SWITCH]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_1: u16 = 2;
+    const KEY_Q: u16 = 16;
+    const KEY_LEFTSHIFT: u16 = 42;
+    const KEY_RIGHTSHIFT: u16 = 54;
+    const KEY_CAPSLOCK: u16 = 58;
+    const KEY_KP1: u16 = 79;
+
+    #[test]
+    fn test_translate() {
+        let layout = Layout::us();
+        let mut modifiers = Modifiers::default();
+        assert_eq!(translate(&layout, KEY_Q, &modifiers), Some('q' as u32));
+        assert_eq!(translate(&layout, KEY_1, &modifiers), Some('1' as u32));
+        assert_eq!(
+            translate(&layout, KEY_KP1, &modifiers),
+            keysym::from_name("KP_End")
+        );
+        assert_eq!(translate(&layout, 28, &modifiers), Some(keysym::RETURN));
+        assert_eq!(translate(&layout, 0x1ff, &modifiers), None);
+
+        modifiers.shift = true;
+        assert_eq!(translate(&layout, KEY_Q, &modifiers), Some('Q' as u32));
+        assert_eq!(translate(&layout, KEY_1, &modifiers), Some('!' as u32));
+        assert_eq!(translate(&layout, 28, &modifiers), Some(keysym::RETURN));
+
+        modifiers.shift = false;
+        modifiers.caps_lock = true;
+        modifiers.num_lock = true;
+        assert_eq!(translate(&layout, KEY_Q, &modifiers), Some('Q' as u32));
+        assert_eq!(translate(&layout, KEY_1, &modifiers), Some('1' as u32));
+        assert_eq!(
+            translate(&layout, KEY_KP1, &modifiers),
+            keysym::from_name("KP_1")
+        );
+
+        modifiers.shift = true;
+        assert_eq!(translate(&layout, KEY_Q, &modifiers), Some('q' as u32));
+    }
+
+    #[test]
+    fn test_keyboard_state() {
+        let mut keyboard = Keyboard::new(Layout::us());
+        assert_eq!(
+            keyboard.handle_key(KEY_LEFTSHIFT, 1),
+            [(true, keysym::SHIFT_L)]
+        );
+        assert_eq!(keyboard.handle_key(KEY_Q, 1), [(true, 'Q' as u32)]);
+        assert_eq!(keyboard.handle_key(KEY_Q, 2), [(true, 'Q' as u32)]);
+        assert_eq!(
+            keyboard.handle_key(KEY_LEFTSHIFT, 0),
+            [(false, keysym::SHIFT_L)]
+        );
+        assert_eq!(keyboard.handle_key(KEY_Q, 0), [(false, 'Q' as u32)]);
+        assert_eq!(keyboard.handle_key(KEY_Q, 0), []);
+
+        keyboard.handle_key(KEY_CAPSLOCK, 1);
+        keyboard.handle_key(KEY_CAPSLOCK, 0);
+        assert!(keyboard.modifiers().caps_lock);
+        assert_eq!(keyboard.handle_key(KEY_Q, 1), [(true, 'Q' as u32)]);
+    }
+
+    #[test]
+    fn test_both_shift_keys() {
+        let mut keyboard = Keyboard::new(Layout::us());
+        keyboard.handle_key(KEY_LEFTSHIFT, 1);
+        keyboard.handle_key(KEY_RIGHTSHIFT, 1);
+        keyboard.handle_key(KEY_LEFTSHIFT, 0);
+        assert!(keyboard.modifiers().shift);
+        assert_eq!(keyboard.handle_key(KEY_Q, 1), [(true, 'Q' as u32)]);
+        keyboard.handle_key(KEY_Q, 0);
+        keyboard.handle_key(KEY_RIGHTSHIFT, 0);
+        assert!(!keyboard.modifiers().shift);
+        assert_eq!(keyboard.handle_key(KEY_Q, 1), [(true, 'q' as u32)]);
+    }
+
+    #[test]
+    fn test_dead_keys() {
+        let dead_acute = keysym::from_name("dead_acute").unwrap();
+        let layout = Layout::parse("13 dead_acute dead_grave\n18 e E\n42 Shift_L\n16 q Q").unwrap();
+        let mut keyboard = Keyboard::new(layout);
+        assert_eq!(keyboard.handle_key(13, 1), []);
+        assert_eq!(keyboard.handle_key(13, 2), []);
+        assert_eq!(keyboard.handle_key(13, 0), []);
+        assert_eq!(keyboard.handle_key(18, 1), [(true, 0xe9)]);
+        assert_eq!(keyboard.handle_key(18, 0), [(false, 0xe9)]);
+
+        keyboard.handle_key(13, 1);
+        keyboard.handle_key(13, 0);
+        assert_eq!(keyboard.handle_key(42, 1), [(true, keysym::SHIFT_L)]);
+        assert_eq!(keyboard.handle_key(18, 1), [(true, 0xc9)]);
+        keyboard.handle_key(18, 0);
+        keyboard.handle_key(42, 0);
+
+        keyboard.handle_key(13, 1);
+        assert_eq!(
+            keyboard.handle_key(16, 1),
+            [(true, 0xb4), (false, 0xb4), (true, 'q' as u32)]
+        );
+        keyboard.handle_key(16, 0);
+        assert_eq!(compose::compose(dead_acute, ' ' as u32), Some(0xb4));
+        assert_eq!(compose::compose(dead_acute, 0x01a0), None);
+    }
+
+    #[test]
+    fn test_raw_keys() {
+        let layout = Layout::parse("13 dead_acute dead_grave\n18 e E").unwrap();
+        let mut keyboard = Keyboard::new(layout);
+        let dead_acute = keysym::from_name("dead_acute").unwrap();
+        assert_eq!(keyboard.handle_raw_key(13, 1), Some((true, dead_acute)));
+        assert_eq!(keyboard.handle_raw_key(13, 0), Some((false, dead_acute)));
+        assert_eq!(keyboard.handle_raw_key(18, 1), Some((true, 'e' as u32)));
+        assert_eq!(keyboard.handle_raw_key(18, 2), Some((true, 'e' as u32)));
+        assert_eq!(keyboard.handle_raw_key(18, 0), Some((false, 'e' as u32)));
+        assert_eq!(keyboard.handle_raw_key(30, 1), None);
+        assert_eq!(keyboard.handle_raw_key(30, 0), None);
+    }
+
+    #[test]
+    fn test_parse_layout() {
+        let layout = Layout::parse("# test\n16 a A\n17 Cyrillic_ze Cyrillic_ZE\n").unwrap();
+        assert_eq!(layout.levels(16), Some(&['a' as u32, 'A' as u32][..]));
+        assert_eq!(
+            translate(&layout, 17, &Modifiers::default()),
+            keysym::from_name("Cyrillic_ze")
+        );
+        assert!(Layout::parse("16 nosuchkeysym").is_err());
+        assert!(Layout::parse("q a").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+/*
+ * X11 keysyms, as used by the KeyEvent message.
+ */
+
+use crate::keysymdef;
+
+pub const BACKSPACE: u32 = 0xff08;
+pub const TAB: u32 = 0xff09;
+pub const RETURN: u32 = 0xff0d;
+pub const ESCAPE: u32 = 0xff1b;
+pub const INSERT: u32 = 0xff63;
+pub const DELETE: u32 = 0xffff;
+pub const HOME: u32 = 0xff50;
+pub const END: u32 = 0xff57;
+pub const PAGE_UP: u32 = 0xff55;
+pub const PAGE_DOWN: u32 = 0xff56;
+pub const LEFT: u32 = 0xff51;
+pub const UP: u32 = 0xff52;
+pub const RIGHT: u32 = 0xff53;
+pub const DOWN: u32 = 0xff54;
+pub const F1: u32 = 0xffbe;
+pub const F2: u32 = 0xffbf;
+pub const F3: u32 = 0xffc0;
+pub const F4: u32 = 0xffc1;
+pub const F5: u32 = 0xffc2;
+pub const F6: u32 = 0xffc3;
+pub const F7: u32 = 0xffc4;
+pub const F8: u32 = 0xffc5;
+pub const F9: u32 = 0xffc6;
+pub const F10: u32 = 0xffc7;
+pub const F11: u32 = 0xffc8;
+pub const F12: u32 = 0xffc9;
+pub const MODE_SWITCH: u32 = 0xff7e;
+pub const NUM_LOCK: u32 = 0xff7f;
+pub const SHIFT_L: u32 = 0xffe1;
+pub const SHIFT_R: u32 = 0xffe2;
+pub const CONTROL_L: u32 = 0xffe3;
+pub const CONTROL_R: u32 = 0xffe4;
+pub const CAPS_LOCK: u32 = 0xffe5;
+pub const META_L: u32 = 0xffe7;
+pub const META_R: u32 = 0xffe8;
+pub const ALT_L: u32 = 0xffe9;
+pub const ALT_R: u32 = 0xffea;
+pub const SUPER_L: u32 = 0xffeb;
+pub const SUPER_R: u32 = 0xffec;
+pub const ISO_LEVEL3_SHIFT: u32 = 0xfe03;
+
+/**
+ * Keysyms 0x01000100 to 0x0110ffff directly encode a Unicode code point.
+ */
+const UNICODE_OFFSET: u32 = 0x01000000;
+
+/**
+ * Look up a keysym by name, without the XK_ prefix. Also accepts the
+ * "U20AC" and "0x20ac" forms used by xkbcommon.
+ */
+pub fn from_name(name: &str) -> Option<u32> {
+    if let Ok(i) = keysymdef::NAMES.binary_search_by(|(n, _)| n.cmp(&name)) {
+        return Some(keysymdef::NAMES[i].1);
+    }
+    if let Some(hex) = name.strip_prefix("0x") {
+        return u32::from_str_radix(hex, 16).ok();
+    }
+    if let Some(hex) = name.strip_prefix('U') {
+        let code = u32::from_str_radix(hex, 16).ok()?;
+        return char::from_u32(code).and_then(from_char);
+    }
+    None
+}
+
+/**
+ * Keysym to type the character `c`. Legacy keysyms are preferred, so
+ * servers without Unicode keysym support still get what they know. Control
+ * characters other than those of a key have none.
+ */
+pub fn from_char(c: char) -> Option<u32> {
+    match c {
+        '\n' | '\r' => return Some(RETURN),
+        '\t' => return Some(TAB),
+        '\u{8}' => return Some(BACKSPACE),
+        '\u{1b}' => return Some(ESCAPE),
+        '\u{7f}' => return Some(DELETE),
+        c if c.is_control() => return None,
+        _ => {}
+    }
+    let code = c as u32;
+    if is_latin1(code) {
+        return Some(code);
+    }
+    let keysym = match keysymdef::BY_CODE_POINT.binary_search_by_key(&code, |&(u, _)| u) {
+        Ok(i) => keysymdef::BY_CODE_POINT[i].1,
+        Err(_) => UNICODE_OFFSET + code,
+    };
+    Some(keysym)
+}
+
+/**
+ * Character produced by a keysym, if it produces one at all.
+ */
+pub fn to_char(keysym: u32) -> Option<char> {
+    if is_latin1(keysym) {
+        return char::from_u32(keysym);
+    }
+    if (UNICODE_OFFSET + 0x100..=UNICODE_OFFSET + 0x10ffff).contains(&keysym) {
+        return char::from_u32(keysym - UNICODE_OFFSET);
+    }
+    keysymdef::UNICODE
+        .binary_search_by_key(&keysym, |&(k, _)| k)
+        .ok()
+        .and_then(|i| char::from_u32(keysymdef::UNICODE[i].1))
+}
+
+fn is_latin1(code: u32) -> bool {
+    matches!(code, 0x20..=0x7e | 0xa0..=0xff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(from_name("Return"), Some(RETURN));
+        assert_eq!(from_name("a"), Some(0x61));
+        assert_eq!(from_name("EuroSign"), Some(0x20ac));
+        assert_eq!(from_name("dead_acute"), Some(0xfe51));
+        assert_eq!(from_name("U20AC"), Some(0x20ac));
+        assert_eq!(from_name("U0416"), Some(0x6f6));
+        assert_eq!(from_name("U1F600"), Some(0x0101f600));
+        assert_eq!(from_name("0xff0d"), Some(RETURN));
+        assert_eq!(from_name("NoSuchKey"), None);
+        assert!(keysymdef::NAMES.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(keysymdef::BY_CODE_POINT.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn test_unicode() {
+        assert_eq!(from_char('a'), Some(0x61));
+        assert_eq!(from_char('\u{e9}'), Some(0xe9));
+        assert_eq!(from_char('\u{20ac}'), Some(0x20ac));
+        assert_eq!(from_char('\u{416}'), Some(0x6f6));
+        assert_eq!(from_char('\u{5d0}'), Some(0xce0));
+        assert_eq!(from_char('\u{4e2d}'), Some(0x01004e2d));
+        assert_eq!(from_char('\n'), Some(RETURN));
+        assert_eq!(from_char('\u{1}'), None);
+        assert_eq!(from_char('\u{85}'), None);
+        assert_eq!(to_char(0x6f6), Some('\u{416}'));
+        assert_eq!(to_char(0x01004e2d), Some('\u{4e2d}'));
+        assert_eq!(to_char(0xe9), Some('\u{e9}'));
+        assert_eq!(to_char(RETURN), None);
+        for text in ["Grüße", "Ζεύς", "日本語", "\u{1f600}"] {
+            let typed: String = text
+                .chars()
+                .map(|c| to_char(from_char(c).unwrap()).unwrap())
+                .collect();
+            assert_eq!(typed, text);
+        }
+    }
+}
@@ -0,0 +1,16 @@
+/*
+ * The RFB protocol side of vncvwr: wire types, the message codec, the
+ * handshake state machine and the blocking and async client sessions,
+ * without any of the display or input code of the viewer.
+ */
+
+#[cfg(feature = "async")]
+pub mod async_session;
+pub mod codec;
+mod d3des;
+pub mod fbs;
+pub mod handshake;
+#[cfg(test)]
+mod mock_server;
+pub mod rfb;
+pub mod session;
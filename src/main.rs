@@ -1,13 +1,782 @@
-mod d3des;
-mod rfb;
-mod session;
+mod calibrate;
+mod canvas;
+mod compose;
+mod display;
+mod evdev;
+mod export;
+mod fbdev;
+mod gesture;
+mod image;
+mod keymap;
+mod keysym;
+mod keysymdef;
+// Also compiled into the library tests, which use parts the tests here do not.
+#[cfg(test)]
+#[allow(dead_code)]
+mod mock_server;
+mod player;
+mod pointer;
+mod runtime;
+mod scale;
+mod scancode;
+mod screenshot;
+mod terminal;
+#[cfg(test)]
+mod test_util;
+mod tty;
+mod view;
+mod viewport;
+mod vt;
+mod xkb;
+
+use calibrate::Calibration;
+use display::Display;
+use std::sync::{Arc, Mutex};
+use vncvwr::{codec, fbs, rfb, session};
+
+const USAGE: &str = "usage: vncvwr [options] [host[:display] | host::port]
+
+options:
+  --display SPEC      where to show the desktop: 'headless' (default), 'fb' or
+                      'fb:DEVICE' for a framebuffer device, 'ppm:DIR' or
+                      'png:DIR' to write an image per update, 'term' for the
+                      terminal, with 'term:blocks' or 'term:sixel' to choose
+                      between half blocks and sixel graphics; keys and mouse
+                      in the terminal are forwarded, Ctrl+] quits
+  --rotate DEGREES    rotate the picture on the framebuffer clockwise by 90,
+                      180 or 270 degrees, with touch input to match
+  --vsync             wait for vertical sync before showing an update on the
+                      framebuffer
+  --scale MODE        how to fit the desktop on a framebuffer or terminal:
+                      'none' (default on a framebuffer), 'fit' keeping the
+                      aspect ratio (default in a terminal), 'stretch' or
+                      'integer' for whole multiples
+  --filter FILTER     'nearest' (default), 'bilinear', or 'box' for smooth
+                      downscaling
+  --edge-scroll       scroll the view while the mouse pointer is at an edge;
+                      Ctrl+Alt+Shift and the arrow keys always scroll it
+  --follow-cursor     scroll the view to keep the remote cursor visible
+  --record FILE       record the session to an FBS file
+  --play FILE         play an FBS recording instead of connecting: space
+                      pauses, left and right arrow seek by 10 seconds, page
+                      up and down by a minute, home restarts, up and down
+                      arrow change the speed, q or escape quit
+  --speed FACTOR      playback speed, 1 by default
+  --export SPEC       write the desktop as video frames: 'y4m:FILE' for a
+                      YUV4MPEG2 stream to pipe into an encoder, 'png:DIR' or
+                      'ppm:DIR' for an image per frame; with --play the
+                      recording is exported as fast as it decodes instead of
+                      being shown, and 'y4m:-' writes to standard output
+  --fps RATE          frames per second to export, 25 by default
+  --screenshot FILE   save one screenshot of the desktop as PNG, or as PPM if
+                      FILE ends in .ppm, instead of staying connected
+  --timeout SECONDS   give up on the screenshot after this long, 10 by default
+  --password-file FILE
+                      read the VNC password from the first line of FILE;
+                      VNC_PASSWORD in the environment works too, otherwise
+                      the password is asked for on the terminal
+  --calibrate DEVICE  calibrate a touchscreen on the framebuffer and store the
+                      result instead of connecting
+  --keyboard DEVICE   forward key presses from an evdev device, or 'auto'
+  --pointer DEVICE    forward a mouse, touchscreen or tablet, or 'auto' for
+                      all of them; may be given more than once
+  --gestures          use touch gestures on touchscreens: tap to click, long
+                      press to right click, drag to pan, two fingers to
+                      scroll and pinch to zoom
+  --calibration FILE  stored touchscreen calibrations, by default
+                      ~/.config/vncvwr/calibration
+  --swap-axes         swap the X and Y axes of absolute pointers
+  --invert-x          invert the X axis of absolute pointers
+  --invert-y          invert the Y axis of absolute pointers; axis options
+                      apply after the stored calibration, in the order given
+  --layout FILE       layout file or compiled XKB keymap, US layout if not given
+  --clipboard TEXT    put TEXT on the clipboard of the server once connected
+  --type TEXT         type TEXT on the desktop once connected, a key press
+                      per character";
+
+#[derive(Debug, Default)]
+struct Options {
+    host: String,
+    port: u16,
+    keyboard: Option<String>,
+    layout: Option<String>,
+    display: display::DisplaySpec,
+    fb: fbdev::FbSettings,
+    scale: Option<scale::ScaleMode>,
+    filter: scale::Filter,
+    edge_scroll: bool,
+    follow_cursor: bool,
+    calibrate: Option<String>,
+    screenshot: Option<std::path::PathBuf>,
+    record: Option<std::path::PathBuf>,
+    play: Option<std::path::PathBuf>,
+    speed: Option<f64>,
+    export: Option<export::ExportSpec>,
+    fps: Option<u32>,
+    timeout: Option<std::time::Duration>,
+    password_file: Option<std::path::PathBuf>,
+    clipboard: Option<String>,
+    type_text: Option<String>,
+    pointers: Vec<String>,
+    gestures: bool,
+    calibration: Option<String>,
+    axes: Calibration,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            host: "127.0.0.1".to_string(),
+            port: 5901,
+            ..Default::default()
+        };
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+            match arg.as_str() {
+                "--keyboard" => options.keyboard = Some(value(&arg)?),
+                "--layout" => options.layout = Some(value(&arg)?),
+                "--display" => options.display = display::DisplaySpec::parse(&value(&arg)?)?,
+                "--rotate" => options.fb.rotation = fbdev::Rotation::parse(&value(&arg)?)?,
+                "--vsync" => options.fb.vsync = true,
+                "--scale" => options.scale = Some(scale::ScaleMode::parse(&value(&arg)?)?),
+                "--filter" => options.filter = scale::Filter::parse(&value(&arg)?)?,
+                "--edge-scroll" => options.edge_scroll = true,
+                "--follow-cursor" => options.follow_cursor = true,
+                "--calibrate" => options.calibrate = Some(value(&arg)?),
+                "--screenshot" => options.screenshot = Some(value(&arg)?.into()),
+                "--record" => options.record = Some(value(&arg)?.into()),
+                "--play" => options.play = Some(value(&arg)?.into()),
+                "--speed" => options.speed = Some(Self::parse_speed(&value(&arg)?)?),
+                "--export" => options.export = Some(export::ExportSpec::parse(&value(&arg)?)?),
+                "--fps" => options.fps = Some(Self::parse_fps(&value(&arg)?)?),
+                "--timeout" => options.timeout = Some(Self::parse_timeout(&value(&arg)?)?),
+                "--password-file" => options.password_file = Some(value(&arg)?.into()),
+                "--clipboard" => options.clipboard = Some(value(&arg)?),
+                "--type" => options.type_text = Some(value(&arg)?),
+                "--pointer" => options.pointers.push(value(&arg)?),
+                "--gestures" => options.gestures = true,
+                "--calibration" => options.calibration = Some(value(&arg)?),
+                "--swap-axes" => options.axes = options.axes.then(&Calibration::SWAP_AXES),
+                "--invert-x" => options.axes = options.axes.then(&Calibration::INVERT_X),
+                "--invert-y" => options.axes = options.axes.then(&Calibration::INVERT_Y),
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+                _ => (options.host, options.port) = Self::parse_address(&arg)?,
+            }
+        }
+        if options.export.as_ref().is_some_and(|spec| spec.to_stdout())
+            && options.display.uses_terminal()
+        {
+            return Err("y4m:- and --display term both need standard output".to_string());
+        }
+        Ok(options)
+    }
+
+    fn calibration_path(&self) -> Option<std::path::PathBuf> {
+        self.calibration
+            .as_ref()
+            .map(std::path::PathBuf::from)
+            .or_else(calibrate::default_path)
+    }
+
+    /**
+     * The password from --password-file or else VNC_PASSWORD, if any.
+     */
+    fn password(&self) -> Result<Option<String>, std::io::Error> {
+        match &self.password_file {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)?;
+                Ok(Some(text.lines().next().unwrap_or_default().to_string()))
+            }
+            None => Ok(std::env::var("VNC_PASSWORD").ok()),
+        }
+    }
+
+    fn parse_timeout(seconds: &str) -> Result<std::time::Duration, String> {
+        seconds
+            .parse()
+            .ok()
+            .and_then(|seconds| std::time::Duration::try_from_secs_f64(seconds).ok())
+            .ok_or(format!("invalid timeout {}", seconds))
+    }
+
+    fn parse_speed(factor: &str) -> Result<f64, String> {
+        factor
+            .parse()
+            .ok()
+            .filter(|factor: &f64| factor.is_finite() && *factor > 0.0)
+            .ok_or(format!("invalid speed {}", factor))
+    }
+
+    fn parse_fps(rate: &str) -> Result<u32, String> {
+        rate.parse()
+            .ok()
+            .filter(|rate| (1..=export::MAX_FPS).contains(rate))
+            .ok_or(format!("invalid frame rate {}", rate))
+    }
+
+    fn parse_address(address: &str) -> Result<(String, u16), String> {
+        let invalid = || format!("invalid address {}", address);
+        if let Some((host, port)) = address.split_once("::") {
+            return Ok((host.to_string(), port.parse().map_err(|_| invalid())?));
+        }
+        if let Some((host, display)) = address.split_once(':') {
+            let display: u16 = display.parse().map_err(|_| invalid())?;
+            let port = 5900u16.checked_add(display).ok_or_else(invalid)?;
+            return Ok((host.to_string(), port));
+        }
+        Ok((address.to_string(), 5900))
+    }
+}
+
+/**
+ * Where status lines go while connected: to standard error, or nowhere when
+ * the desktop is shown in the terminal, as they would scribble over it.
+ */
+#[derive(Clone, Copy)]
+struct Status {
+    quiet: bool,
+}
+
+impl Status {
+    fn new(options: &Options) -> Self {
+        Self {
+            quiet: options.display.uses_terminal(),
+        }
+    }
+
+    fn print(self, line: std::fmt::Arguments) {
+        if !self.quiet {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+/**
+ * The keyboard given with --keyboard, grabbed, and its layout.
+ */
+fn open_keyboard(
+    options: &Options,
+    path: &str,
+) -> Result<(evdev::Device, keymap::Layout), std::io::Error> {
+    let layout = match &options.layout {
+        Some(layout) => keymap::Layout::load(layout)?,
+        None => keymap::Layout::us(),
+    };
+    let device = match path {
+        "auto" => evdev::Device::find_keyboard()?.ok_or(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no keyboard found",
+        ))?,
+        path => evdev::Device::open(path)?,
+    };
+    device.grab(true)?;
+    let name = device.name()?;
+    Status::new(options).print(format_args!(
+        "Keyboard: {} ({})",
+        name,
+        device.path().display()
+    ));
+    Ok((device, layout))
+}
+
+fn start_keyboard(
+    options: &Options,
+    viewport: &viewport::Viewport,
+    input: &runtime::InputSender,
+) -> Result<(), std::io::Error> {
+    let Some(path) = &options.keyboard else {
+        return Ok(());
+    };
+    let (mut device, layout) = open_keyboard(options, path)?;
+    let viewport = viewport.clone();
+    let input = input.clone();
+    let status = Status::new(options);
+    std::thread::spawn(move || {
+        let mut keyboard = keymap::Keyboard::new(layout);
+        let result = evdev::forward_keyboard(&mut device, &mut keyboard, &viewport, &input);
+        status.print(format_args!("Keyboard stopped: {:?}", result));
+    });
+    Ok(())
+}
+
+fn start_pointers(
+    options: &Options,
+    viewport: &viewport::Viewport,
+    input: &runtime::InputSender,
+) -> Result<(), std::io::Error> {
+    let mut devices = Vec::new();
+    for path in &options.pointers {
+        match path.as_str() {
+            "auto" => devices.extend(evdev::Device::find_pointers()?),
+            path => devices.push(evdev::Device::open(path)?),
+        }
+    }
+    let calibrations = options.calibration_path();
+    let status = Status::new(options);
+    for mut device in devices {
+        device.grab(true)?;
+        let name = device.name()?;
+        status.print(format_args!(
+            "Pointer: {} ({})",
+            name,
+            device.path().display()
+        ));
+        let stored = match &calibrations {
+            Some(path) => calibrate::load(path, &name)?,
+            None => None,
+        };
+        let calibration = stored
+            .unwrap_or_default()
+            .then(&options.axes)
+            .then(&options.fb.rotation.calibration());
+        let viewport = viewport.clone();
+        let input = input.clone();
+        if options.gestures && device.has_key(evdev::BTN_TOUCH)? {
+            let mut touches = device.touches(calibration)?;
+            std::thread::spawn(move || {
+                let mut gestures = gesture::Gestures::new();
+                let result = evdev::forward_gestures(
+                    &mut device,
+                    &mut touches,
+                    &mut gestures,
+                    &viewport,
+                    &input,
+                );
+                status.print(format_args!("Pointer stopped: {:?}", result));
+            });
+        } else {
+            let mut pointer = device.pointer(calibration)?;
+            std::thread::spawn(move || {
+                let result = evdev::forward_pointer(&mut device, &mut pointer, &viewport, &input);
+                status.print(format_args!("Pointer stopped: {:?}", result));
+            });
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Send the text given with --clipboard and --type.
+ */
+fn send_text(options: &Options, input: &runtime::InputSender) -> Result<(), std::io::Error> {
+    if let Some(text) = &options.clipboard {
+        input.client_cut_text(text)?;
+    }
+    if let Some(text) = &options.type_text {
+        input.type_text(text)?;
+    }
+    Ok(())
+}
+
+/**
+ * Show calibration targets on the framebuffer until all were touched, then
+ * store the calibration of the touchscreen. The VT is taken over meanwhile,
+ * like for a session on the framebuffer.
+ */
+fn run_calibration(options: &Options, path: &str) -> Result<(), std::io::Error> {
+    let fb_device = match &options.display {
+        display::DisplaySpec::Framebuffer(device) => device,
+        _ => fbdev::DEFAULT_DEVICE,
+    };
+    let fb = fbdev::FramebufferDisplay::open(fb_device, fbdev::FbSettings::default())?;
+    let (width, height) = (fb.width(), fb.height());
+    let mut device = evdev::Device::open(path)?;
+    device.grab(true)?;
+    let name = device.name()?;
+    let mut calibrator = calibrate::Calibrator::new(
+        device.abs_range(evdev::ABS_X)?,
+        device.abs_range(evdev::ABS_Y)?,
+    );
+
+    let console = acquire_console()?;
+    let mut display: Box<dyn Display> = Box::new(fb);
+    if let Some(console) = &console {
+        display = Box::new(vt::VtDisplay::new(display, console.active()));
+    }
+    let canvas = Arc::new(Mutex::new(canvas::Canvas::new(width, height)));
+    let display = Arc::new(Mutex::new(display));
+    if let Some(console) = &console {
+        console.handle_signals(runtime::Renderer::new(canvas.clone(), display.clone()));
+    }
+    let screen = canvas::Rect::new(0, 0, width, height);
+    let show = |calibrator: &calibrate::Calibrator| {
+        let mut canvas = canvas.lock().unwrap();
+        calibrator.render(&mut canvas);
+        display.lock().unwrap().update(&canvas, &[screen])
+    };
+    show(&calibrator)?;
+    while calibrator.target().is_some() {
+        for event in device.read_events()? {
+            if calibrator.handle(&event) {
+                show(&calibrator)?;
+            }
+        }
+    }
+    display
+        .lock()
+        .unwrap()
+        .resized(&canvas::Canvas::new(width, height))?;
+    drop(console);
+    let calibration = calibrator.result().ok_or(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "touches do not match the targets",
+    ))?;
+    let file = options.calibration_path().ok_or(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "no calibration file, use --calibration",
+    ))?;
+    calibrate::save(&file, &name, &calibration)?;
+    eprintln!("Calibration of {} saved to {}", name, file.display());
+    Ok(())
+}
+
+/**
+ * Save a single screenshot, exiting with status 1 if none could be taken.
+ */
+fn run_screenshot(options: &Options, path: &std::path::Path) -> Result<(), std::io::Error> {
+    let timeout = options.timeout.unwrap_or(screenshot::DEFAULT_TIMEOUT);
+    let password = options.password()?;
+    let result = screenshot::capture(&options.host, options.port, password.as_deref(), timeout);
+    let canvas = match result {
+        Ok(canvas) => canvas,
+        Err(err) => {
+            eprintln!(
+                "Screenshot of {}:{} failed: {}",
+                options.host, options.port, err
+            );
+            std::process::exit(1);
+        }
+    };
+    let file = std::fs::File::create(path)?;
+    let format = image::ImageFormat::for_path(path);
+    image::write(&canvas, format, std::io::BufWriter::new(file))
+}
+
+/**
+ * The display given on the command line, as opened by `open_display`.
+ */
+struct Output {
+    display: Box<dyn Display>,
+    console: Option<vt::Vt>,
+    /** Size of a character cell, in a terminal. */
+    cell_size: Option<(u16, u16)>,
+}
+
+/**
+ * Open the display given on the command line, taking over the VT if it is
+ * a framebuffer.
+ */
+fn open_display(options: &Options) -> Result<Output, std::io::Error> {
+    let (display, cell_size): (Box<dyn Display>, _) = match &options.display {
+        display::DisplaySpec::Terminal(mode) => {
+            let display = terminal::TerminalDisplay::open(*mode)?;
+            let cell_size = display.cell_size();
+            (Box::new(display), Some(cell_size))
+        }
+        spec => (spec.open(options.fb)?, None),
+    };
+    let console = match &options.display {
+        display::DisplaySpec::Framebuffer(_) => acquire_console()?,
+        _ => None,
+    };
+    Ok(Output {
+        display,
+        console,
+        cell_size,
+    })
+}
+
+/**
+ * Take over the VT in the foreground, unless there is none to take over.
+ */
+fn acquire_console() -> Result<Option<vt::Vt>, std::io::Error> {
+    let console = match vt::Vt::open_active() {
+        Ok(console) => console,
+        Err(err) => {
+            eprintln!("Console left alone: {}", err);
+            return Ok(None);
+        }
+    };
+    console.acquire()?;
+    eprintln!("Console: tty{}", console.number());
+    Ok(Some(console))
+}
+
+/**
+ * Scale a desktop of `width` x `height` to the display if it has a size of
+ * its own, and stop drawing while the VT is switched away.
+ */
+fn fit_display(
+    options: &Options,
+    mut display: Box<dyn Display>,
+    console: Option<&vt::Vt>,
+    width: u16,
+    height: u16,
+) -> (Box<dyn Display>, Arc<Mutex<view::ViewTransform>>) {
+    let default_scale = match options.display.uses_terminal() {
+        true => scale::ScaleMode::Fit,
+        false => scale::ScaleMode::None,
+    };
+    let local_size = display.size();
+    let view = Arc::new(Mutex::new(match local_size {
+        Some((local_width, local_height)) => {
+            let mut view = view::ViewTransform::new(local_width, local_height, width, height);
+            view.set_mode(options.scale.unwrap_or(default_scale));
+            view
+        }
+        None => view::ViewTransform::identity(width, height),
+    }));
+    if local_size.is_some() {
+        display = Box::new(scale::ScaledDisplay::new(
+            display,
+            view.clone(),
+            options.filter,
+        ));
+    }
+    if let Some(console) = console {
+        display = Box::new(vt::VtDisplay::new(display, console.active()));
+    }
+    (display, view)
+}
+
+/**
+ * Play a recording on the display, controlled from the keyboard or the
+ * terminal, until it ends or the user quits.
+ */
+fn run_playback(options: &Options, path: &std::path::Path) -> Result<(), std::io::Error> {
+    let recording = player::Recording::open(path)?;
+    eprintln!(
+        "Playing {} ({} s)",
+        recording.name,
+        recording.duration() / 1000
+    );
+    let Output {
+        display,
+        console,
+        cell_size,
+    } = open_display(options)?;
+    let (width, height) = (recording.width, recording.height);
+    let (display, _view) = fit_display(options, display, console.as_ref(), width, height);
+    let mut player = player::Player::new(recording);
+    let display = Arc::new(Mutex::new(display));
+    if let Some(console) = &console {
+        console.handle_signals(runtime::Renderer::new(player.canvas(), display.clone()));
+    }
+
+    let (controls, receiver) = std::sync::mpsc::channel();
+    if let Some(path) = &options.keyboard {
+        let (mut device, layout) = open_keyboard(options, path)?;
+        let controls = controls.clone();
+        std::thread::spawn(move || {
+            let mut keyboard = keymap::Keyboard::new(layout);
+            let _ = evdev::forward_controls(&mut device, &mut keyboard, &controls);
+        });
+    }
+    let _tty = match cell_size {
+        Some(_) => {
+            let controls = controls.clone();
+            Some(tty::TtyInput::start(move |fd| {
+                let _ = tty::read(fd, |event| {
+                    if let tty::TtyEvent::Key(keysym, _) = event {
+                        if let Some(control) = player::control(keysym) {
+                            let _ = controls.send(control);
+                        }
+                    }
+                    Ok(())
+                });
+                let _ = controls.send(player::Control::Quit);
+            })?)
+        }
+        None => None,
+    };
+    drop(controls);
+
+    let mut clock = player::Clock::new(options.speed.unwrap_or(1.0));
+    player::play(&mut player, &mut clock, &display, &receiver)
+}
+
+/**
+ * Export a recording to video frames without showing it.
+ */
+fn run_export(
+    options: &Options,
+    path: &std::path::Path,
+    spec: &export::ExportSpec,
+) -> Result<(), std::io::Error> {
+    let recording = player::Recording::open(path)?;
+    let duration = recording.duration();
+    let mut exporter = export::Exporter::open(spec, options.fps.unwrap_or(export::DEFAULT_FPS))?;
+    let mut player = player::Player::new(recording);
+    export::export_recording(&mut player, duration, &mut exporter)?;
+    eprintln!("Exported {} frames", exporter.frames());
+    Ok(())
+}
 
 fn main() -> Result<(), std::io::Error> {
-    let mut session = session::Session::new("127.0.0.1", 5901)?;
-    let handshake_result = session.handshake();
-    println!("Handshake result: {:?}", handshake_result);
-    println!("{:#?}", session);
-    println!("{:?}", session.set_pixel_format(&session::Session::PREFERRED_PIXEL_FORMAT));
-    println!("{:?}", session.set_encodings(&[rfb::Encoding::Raw]));
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(2);
+        }
+    };
+    if let Some(path) = &options.calibrate {
+        return run_calibration(&options, path);
+    }
+    if let Some(path) = &options.screenshot {
+        return run_screenshot(&options, path);
+    }
+    if let (Some(path), Some(spec)) = (&options.play, &options.export) {
+        return run_export(&options, path, spec);
+    }
+    if let Some(path) = &options.play {
+        return run_playback(&options, path);
+    }
+    let mut session = session::Session::new(&options.host, options.port)?;
+    if let Some(password) = options.password()? {
+        session.set_password(&password);
+    }
+    if let Err(err) = session.handshake() {
+        eprintln!(
+            "Handshake with {}:{} failed: {}",
+            options.host, options.port, err
+        );
+        std::process::exit(1);
+    }
+    eprintln!("{:#?}", session);
+    session.set_pixel_format(&session::Session::PREFERRED_PIXEL_FORMAT)?;
+    session.set_encodings(&[
+        rfb::Encoding::Hextile,
+        rfb::Encoding::RRE,
+        rfb::Encoding::CopyRect,
+        rfb::Encoding::Raw,
+        rfb::Encoding::DesktopSizePseudo,
+        rfb::Encoding::PointerPosPseudo,
+        rfb::Encoding::QemuExtendedKeyEventPseudo,
+    ])?;
+    let Output {
+        display,
+        console,
+        cell_size,
+    } = open_display(&options)?;
+    let local_size = display.size();
+    if let Some(path) = &options.record {
+        session.record(fbs::Recorder::create(path, &session)?);
+        eprintln!("Recording to {}", path.display());
+    }
+    let (width, height) = (session.screen_w(), session.screen_h());
+    let (display, view) = fit_display(&options, display, console.as_ref(), width, height);
+    let runtime = runtime::Runtime::start(session, display)?;
+    if let Some(console) = &console {
+        console.handle_signals(runtime.renderer());
+    }
+    let (stop_export, stop) = std::sync::mpsc::channel();
+    let exporting = match &options.export {
+        Some(spec) => {
+            let fps = options.fps.unwrap_or(export::DEFAULT_FPS);
+            let mut exporter = export::Exporter::open(spec, fps)?;
+            let canvas = runtime.canvas();
+            eprintln!("Exporting at {} fps", fps);
+            Some(std::thread::spawn(move || {
+                export::export_live(&canvas, &mut exporter, &stop)
+            }))
+        }
+        None => None,
+    };
+    let status = Status::new(&options);
+    let mut viewport = viewport::Viewport::new(view.clone(), runtime.renderer());
+    viewport.edge_scrolling = options.edge_scroll;
+    viewport.follow_cursor = options.follow_cursor;
+    start_keyboard(&options, &viewport, &runtime.input())?;
+    start_pointers(&options, &viewport, &runtime.input())?;
+    send_text(&options, &runtime.input())?;
+    let _tty = match cell_size {
+        Some(cell_size) => {
+            let view = view.clone();
+            let input = runtime.input();
+            Some(tty::TtyInput::start(move |fd| {
+                let _ = tty::forward(fd, &view, cell_size, &input);
+                input.shutdown();
+            })?)
+        }
+        None => None,
+    };
+    for event in runtime.events() {
+        match (&event, local_size) {
+            // Displays of fixed size follow the resize through their view.
+            (runtime::Event::DesktopResized(..), Some(_)) => {}
+            (runtime::Event::DesktopResized(width, height), None) => {
+                *view.lock().unwrap() = view::ViewTransform::identity(*width, *height)
+            }
+            (runtime::Event::CursorMoved(x, y), _) => viewport.cursor_moved(*x, *y)?,
+            _ => status.print(format_args!("{:?}", event)),
+        }
+        if event == runtime::Event::Disconnected {
+            break;
+        }
+    }
+    drop(stop_export);
+    if let Some(exporting) = exporting {
+        if let Err(err) = exporting.join().unwrap() {
+            eprintln!("Export failed: {}", err);
+        }
+    }
+    let result = runtime.wait();
+    status.print(format_args!("{:?}", result));
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockServer, Script};
+    use crate::test_util::MockDisplay;
+    use std::time::Duration;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_export_to_stdout() {
+        let options = parse(&["--export", "y4m:-", "localhost:1"]).unwrap();
+        assert!(options.export.unwrap().to_stdout());
+        assert!(options.play.is_none());
+        assert!(parse(&["--export", "y4m:-", "--display", "term"]).is_err());
+    }
+
+    #[test]
+    fn test_status_in_terminal() {
+        assert!(Status::new(&parse(&["--display", "term"]).unwrap()).quiet);
+        assert!(!Status::new(&parse(&["--display", "headless"]).unwrap()).quiet);
+    }
+
+    #[test]
+    fn test_send_text() {
+        let server = MockServer::start(
+            Script::new()
+                .handshake(2, 1, &session::Session::PREFERRED_PIXEL_FORMAT, "")
+                .expect(&[3, 0, 0, 0, 0, 0, 0, 2, 0, 1])
+                .send(&[2])
+                .expect(&[6, 0, 0, 0, 0, 0, 0, 2, b'h', 0xe9])
+                .expect(&[4, 1, 0, 0, 0, 0, 0, b'o'])
+                .expect(&[4, 0, 0, 0, 0, 0, 0, b'o'])
+                .expect(&[4, 1, 0, 0, 0, 0, 0, b'k'])
+                .expect(&[4, 0, 0, 0, 0, 0, 0, b'k']),
+        );
+        let options = parse(&["--clipboard", "h\u{e9}", "--type", "ok"]).unwrap();
+        let mut session = session::Session::new("127.0.0.1", server.port()).unwrap();
+        session.handshake().unwrap();
+        let runtime = runtime::Runtime::start(session, Box::new(MockDisplay::new(2, 1))).unwrap();
+        // The bell comes after the first update request, which keeps that
+        // ahead of the text.
+        assert_eq!(
+            runtime.events().recv_timeout(Duration::from_secs(5)),
+            Ok(runtime::Event::Bell)
+        );
+        send_text(&options, &runtime.input()).unwrap();
+        server.finish();
+        assert!(runtime.wait().is_ok());
+    }
+}
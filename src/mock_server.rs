@@ -0,0 +1,194 @@
+/*
+ * Scripted server side of the RFB protocol, used to drive `Session` in tests.
+ *
+ * A script is a list of steps that are played back in order on a loopback
+ * socket: bytes the server sends, and bytes the server expects to receive
+ * from the client. Any mismatch makes the server thread panic, which is
+ * reported by `MockServer::finish`.
+ */
+
+use crate::rfb::PixelFormat;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+ * A FramebufferUpdate rectangle as `(x, y, w, h, encoding, payload)`.
+ */
+pub type UpdateRect<'a> = (u16, u16, u16, u16, i32, &'a [u8]);
+
+#[derive(Debug)]
+enum Step {
+    Send(Vec<u8>),
+    Expect(Vec<u8>),
+    Skip(usize),
+}
+
+#[derive(Debug, Default)]
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+pub struct MockServer {
+    port: u16,
+    handle: JoinHandle<()>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Send raw bytes to the client.
+     */
+    pub fn send(mut self, data: &[u8]) -> Self {
+        self.steps.push(Step::Send(data.to_vec()));
+        self
+    }
+
+    /**
+     * Expect the client to send exactly these bytes.
+     */
+    pub fn expect(mut self, data: &[u8]) -> Self {
+        self.steps.push(Step::Expect(data.to_vec()));
+        self
+    }
+
+    /**
+     * Read and discard `len` bytes from the client.
+     */
+    pub fn skip(mut self, len: usize) -> Self {
+        self.steps.push(Step::Skip(len));
+        self
+    }
+
+    /**
+     * Offer RFB 3.8 and expect the client to accept it.
+     */
+    pub fn version(self) -> Self {
+        self.send(b"RFB 003.008\n").expect(b"RFB 003.008\n")
+    }
+
+    /**
+     * Offer the given security types and expect the client to choose `chosen`.
+     */
+    pub fn security(self, offered: &[u8], chosen: u8) -> Self {
+        let mut data = vec![offered.len() as u8];
+        data.extend_from_slice(offered);
+        self.send(&data).expect(&[chosen])
+    }
+
+    /**
+     * Offer no security types at all, failing the handshake with `reason`.
+     */
+    pub fn security_failure(self, reason: &str) -> Self {
+        let mut data = vec![0u8];
+        data.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+        data.extend_from_slice(reason.as_bytes());
+        self.send(&data)
+    }
+
+    pub fn security_result(self, result: u32) -> Self {
+        self.send(&result.to_be_bytes())
+    }
+
+    /**
+     * Expect the ClientInit message with the given shared flag.
+     */
+    pub fn client_init(self, shared: bool) -> Self {
+        self.expect(&[shared as u8])
+    }
+
+    pub fn server_init(self, width: u16, height: u16, format: &PixelFormat, name: &str) -> Self {
+        let mut data = Vec::new();
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(&<[u8; 16]>::from(format));
+        data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        data.extend_from_slice(name.as_bytes());
+        self.send(&data)
+    }
+
+    /**
+     * Complete handshake with security type None.
+     */
+    pub fn handshake(self, width: u16, height: u16, format: &PixelFormat, name: &str) -> Self {
+        self.version()
+            .security(&[1], 1)
+            .security_result(0)
+            .client_init(false)
+            .server_init(width, height, format, name)
+    }
+
+    /**
+     * Send a FramebufferUpdate made of the given rectangles.
+     */
+    pub fn framebuffer_update(self, rects: &[UpdateRect]) -> Self {
+        let mut data = vec![0u8, 0];
+        data.extend_from_slice(&(rects.len() as u16).to_be_bytes());
+        for &(x, y, w, h, encoding, payload) in rects {
+            data.extend_from_slice(&x.to_be_bytes());
+            data.extend_from_slice(&y.to_be_bytes());
+            data.extend_from_slice(&w.to_be_bytes());
+            data.extend_from_slice(&h.to_be_bytes());
+            data.extend_from_slice(&encoding.to_be_bytes());
+            data.extend_from_slice(payload);
+        }
+        self.send(&data)
+    }
+}
+
+impl MockServer {
+    pub fn start(script: Script) -> Self {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).expect("bind mock server");
+        let port = listener.local_addr().unwrap().port();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("accept client");
+            Self::play(stream, script);
+        });
+        Self { port, handle }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /**
+     * Wait for the script to finish, failing the test if the client deviated
+     * from it.
+     */
+    pub fn finish(self) {
+        if let Err(err) = self.handle.join() {
+            let msg = err
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| err.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_default();
+            panic!("mock server failed: {}", msg);
+        }
+    }
+
+    fn play(mut stream: TcpStream, script: Script) {
+        stream.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+        for (i, step) in script.steps.into_iter().enumerate() {
+            match step {
+                Step::Send(data) => stream.write_all(&data).expect("send to client"),
+                Step::Expect(expected) => {
+                    let mut actual = vec![0u8; expected.len()];
+                    if let Err(err) = stream.read_exact(&mut actual) {
+                        panic!("step {}: expected {:x?}, got {}", i, expected, err);
+                    }
+                    assert_eq!(actual, expected, "step {}: unexpected client data", i);
+                }
+                Step::Skip(len) => {
+                    let mut buf = vec![0u8; len];
+                    stream.read_exact(&mut buf).expect("read from client");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,490 @@
+/*
+ * Playback of FBS recordings through the same decoders and displays as a
+ * live session.
+ *
+ * A recording is split into server messages when it is loaded, each with
+ * the time of the block its last byte arrived in. Playing applies them to a
+ * canvas as the clock passes their time; the clock runs at any speed and
+ * can be paused. Seeking backwards decodes again from the closest keyframe,
+ * a copy of the canvas taken whenever playback enters the next of at most
+ * `MAX_KEYFRAMES` equal slices of the recording, or from the start.
+ */
+
+use crate::canvas::{Canvas, Change, Rect};
+use crate::codec::{DecodeError, ServerMessage};
+use crate::display::Display;
+use crate::fbs;
+use crate::keysym;
+use crate::rfb::{self, PixelFormat, RfbVersion};
+use std::path::Path;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const MAX_KEYFRAMES: u32 = 32;
+/** Shortest time between keyframes in milliseconds. */
+pub const MIN_KEYFRAME_INTERVAL: u32 = 10_000;
+/** Milliseconds to seek by with the arrow keys and page keys. */
+pub const SEEK_STEP: i64 = 10_000;
+pub const SEEK_PAGE: i64 = 60_000;
+pub const MIN_SPEED: f64 = 1.0 / 8.0;
+pub const MAX_SPEED: f64 = 64.0;
+
+#[derive(Debug)]
+pub struct Recording {
+    pub width: u16,
+    pub height: u16,
+    pub format: PixelFormat,
+    pub name: String,
+    /** Messages with the milliseconds at which they were complete. */
+    messages: Vec<(u32, ServerMessage)>,
+}
+
+/**
+ * What playing up to some time changed.
+ */
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub damage: Vec<Rect>,
+    pub resized: bool,
+}
+
+#[derive(Debug)]
+pub struct Player {
+    recording: Recording,
+    canvas: Arc<Mutex<Canvas>>,
+    /** Index of the next message to apply. */
+    next: usize,
+    /** Canvas before the first message of each slice reached so far. */
+    keyframes: Vec<(usize, Canvas)>,
+    interval: u32,
+}
+
+/**
+ * Playback time in milliseconds, running at `speed` unless paused.
+ */
+#[derive(Debug)]
+pub struct Clock {
+    speed: f64,
+    paused: bool,
+    position: f64,
+    since: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Control {
+    TogglePause,
+    /** Seek by milliseconds, backwards if negative. */
+    Seek(i64),
+    Restart,
+    Faster,
+    Slower,
+    Quit,
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn decode_error(err: DecodeError) -> std::io::Error {
+    invalid(&format!("recording cannot be decoded: {}", err))
+}
+
+/**
+ * Playback control for a key, as keysym.
+ */
+pub fn control(keysym: u32) -> Option<Control> {
+    Some(match keysym {
+        0x20 => Control::TogglePause,
+        keysym::LEFT => Control::Seek(-SEEK_STEP),
+        keysym::RIGHT => Control::Seek(SEEK_STEP),
+        keysym::PAGE_UP => Control::Seek(-SEEK_PAGE),
+        keysym::PAGE_DOWN => Control::Seek(SEEK_PAGE),
+        keysym::HOME => Control::Restart,
+        keysym::UP => Control::Faster,
+        keysym::DOWN => Control::Slower,
+        keysym::ESCAPE | 0x71 => Control::Quit,
+        _ => return None,
+    })
+}
+
+impl Recording {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    /**
+     * Split an FBS file into messages. A message cut short at the end is
+     * dropped.
+     */
+    pub fn parse(data: &[u8]) -> Result<Self, std::io::Error> {
+        let mut stream = Vec::new();
+        let mut ends = Vec::new();
+        for (time, data) in fbs::blocks(data)? {
+            stream.extend_from_slice(data);
+            ends.push((stream.len(), time));
+        }
+
+        if stream.len() < 16 || rfb::parse_offered_version(&stream[..12]) != RfbVersion::Rfb33 {
+            return Err(invalid("recording does not start with RFB 3.3"));
+        }
+        if stream[12..16] != 1u32.to_be_bytes() {
+            return Err(invalid("recording uses authentication"));
+        }
+        let server_init = stream
+            .get(16..40)
+            .ok_or(invalid("recording has no ServerInit"))?;
+        let width = u16::from_be_bytes([server_init[0], server_init[1]]);
+        let height = u16::from_be_bytes([server_init[2], server_init[3]]);
+        let format: PixelFormat = <[u8; 16]>::try_from(&server_init[4..20]).unwrap().into();
+        if format.true_color_flag == 0 || !format.is_supported() {
+            return Err(invalid("recording uses an unsupported pixel format"));
+        }
+        let name_len = u32::from_be_bytes(server_init[20..24].try_into().unwrap()) as usize;
+        let name = stream
+            .get(40..40 + name_len)
+            .ok_or(invalid("recording has no ServerInit"))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        let mut messages = Vec::new();
+        let mut pos = 40 + name_len;
+        loop {
+            match ServerMessage::decode(&stream[pos..], &format) {
+                Ok((msg, len)) => {
+                    pos += len;
+                    let block = ends.partition_point(|&(end, _)| end < pos);
+                    messages.push((ends[block].1, msg));
+                }
+                Err(DecodeError::Incomplete(_)) => break,
+                Err(err) => return Err(decode_error(err)),
+            }
+        }
+        Ok(Self {
+            width,
+            height,
+            format,
+            name,
+            messages,
+        })
+    }
+
+    /**
+     * Time of the last message in milliseconds.
+     */
+    pub fn duration(&self) -> u32 {
+        self.messages.last().map_or(0, |&(time, _)| time)
+    }
+}
+
+impl Player {
+    pub fn new(recording: Recording) -> Self {
+        let canvas = Canvas::new(recording.width, recording.height);
+        let interval = (recording.duration() / MAX_KEYFRAMES).max(MIN_KEYFRAME_INTERVAL);
+        Self {
+            keyframes: vec![(0, canvas.clone())],
+            canvas: Arc::new(Mutex::new(canvas)),
+            recording,
+            next: 0,
+            interval,
+        }
+    }
+
+    pub fn canvas(&self) -> Arc<Mutex<Canvas>> {
+        self.canvas.clone()
+    }
+
+    /**
+     * Whether every message was applied.
+     */
+    pub fn finished(&self) -> bool {
+        self.next == self.recording.messages.len()
+    }
+
+    /**
+     * Time of the next message to apply.
+     */
+    pub fn next_time(&self) -> Option<u32> {
+        self.recording
+            .messages
+            .get(self.next)
+            .map(|&(time, _)| time)
+    }
+
+    /**
+     * Apply the messages up to and including `time`.
+     */
+    pub fn advance(&mut self, time: u32) -> Result<Changes, std::io::Error> {
+        let mut changes = Changes::default();
+        let mut canvas = self.canvas.lock().unwrap();
+        while let Some((at, msg)) = self.recording.messages.get(self.next) {
+            if *at > time {
+                break;
+            }
+            if *at as u64 >= self.keyframes.len() as u64 * self.interval as u64 {
+                self.keyframes.push((self.next, canvas.clone()));
+                continue;
+            }
+            match msg {
+                ServerMessage::FramebufferUpdate(rects) => {
+                    for rect in rects {
+                        match canvas
+                            .apply(rect, &self.recording.format)
+                            .map_err(decode_error)?
+                        {
+                            Change::Damaged(area) if !area.is_empty() => changes.damage.push(area),
+                            Change::Resized(..) => changes.resized = true,
+                            _ => {}
+                        }
+                    }
+                }
+                ServerMessage::SetColourMapEntries {
+                    first_colour,
+                    colours,
+                } => canvas.set_colour_map_entries(*first_colour, colours),
+                ServerMessage::Bell | ServerMessage::ServerCutText(_) => {}
+            }
+            self.next += 1;
+        }
+        Ok(changes)
+    }
+
+    /**
+     * Show the canvas as it was at `time`, going back to a keyframe if
+     * that is in the past or closer than the current position.
+     */
+    pub fn seek(&mut self, time: u32) -> Result<(), std::io::Error> {
+        let slice = ((time / self.interval) as usize).min(self.keyframes.len() - 1);
+        let (index, keyframe) = &self.keyframes[slice];
+        let behind = self.next_time().is_none_or(|next| next > time);
+        if behind || *index > self.next {
+            *self.canvas.lock().unwrap() = keyframe.clone();
+            self.next = *index;
+        }
+        self.advance(time)?;
+        Ok(())
+    }
+}
+
+impl Clock {
+    pub fn new(speed: f64) -> Self {
+        Self {
+            speed,
+            paused: false,
+            position: 0.0,
+            since: Instant::now(),
+        }
+    }
+
+    pub fn position(&self) -> u32 {
+        match self.paused {
+            true => self.position as u32,
+            false => (self.position + self.since.elapsed().as_secs_f64() * 1000.0 * self.speed)
+                .min(u32::MAX as f64) as u32,
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_position(&mut self, position: u32) {
+        self.position = position as f64;
+        self.since = Instant::now();
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.set_position(self.position());
+        self.paused = paused;
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.set_position(self.position());
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    }
+
+    /**
+     * Real time until the clock reaches `time`, None while paused.
+     */
+    pub fn until(&self, time: u32) -> Option<Duration> {
+        let ms = time.saturating_sub(self.position()) as f64 / self.speed;
+        (!self.paused).then(|| Duration::from_secs_f64(ms / 1000.0))
+    }
+}
+
+/**
+ * Play the recording on the display, following `controls`. At the end
+ * playback pauses; it returns when told to quit or, once paused, when there
+ * is nobody left to send controls.
+ */
+pub fn play(
+    player: &mut Player,
+    clock: &mut Clock,
+    display: &Mutex<Box<dyn Display>>,
+    controls: &Receiver<Control>,
+) -> Result<(), std::io::Error> {
+    let mut redraw = true;
+    loop {
+        let changes = player.advance(clock.position())?;
+        {
+            let canvas = player.canvas.lock().unwrap();
+            let mut display = display.lock().unwrap();
+            if redraw || changes.resized {
+                display.resized(&canvas)?;
+            } else if !changes.damage.is_empty() {
+                display.update(&canvas, &changes.damage)?;
+            }
+        }
+        redraw = false;
+        if player.finished() && !clock.paused() {
+            clock.set_paused(true);
+            clock.set_position(player.recording.duration());
+        }
+
+        let wait = player.next_time().and_then(|time| clock.until(time));
+        let control = match wait {
+            Some(wait) => match controls.recv_timeout(wait) {
+                Ok(control) => control,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    std::thread::sleep(wait);
+                    continue;
+                }
+            },
+            None => match controls.recv() {
+                Ok(control) => control,
+                Err(_) => return Ok(()),
+            },
+        };
+        let target = match control {
+            Control::TogglePause => {
+                clock.set_paused(!clock.paused());
+                continue;
+            }
+            Control::Faster => {
+                clock.set_speed(clock.speed * 2.0);
+                continue;
+            }
+            Control::Slower => {
+                clock.set_speed(clock.speed / 2.0);
+                continue;
+            }
+            Control::Quit => return Ok(()),
+            Control::Restart => 0,
+            Control::Seek(delta) => (clock.position() as i64 + delta)
+                .clamp(0, player.recording.duration() as i64)
+                as u32,
+        };
+        player.seek(target)?;
+        clock.set_position(target);
+        redraw = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+    use std::sync::mpsc;
+
+    /**
+     * An FBS file of a 2x1 desktop with a raw update of the left pixel at
+     * each of the given times, in the given colours.
+     */
+    fn fbs_file(updates: &[(u32, u32)]) -> Vec<u8> {
+        let mut data = fbs::SIGNATURE.to_vec();
+        let mut block = |time: u32, bytes: &[u8]| fbs::write_block(&mut data, time, bytes).unwrap();
+        block(
+            0,
+            &fbs::prelude(2, 1, &Session::PREFERRED_PIXEL_FORMAT, "rec"),
+        );
+        for &(time, colour) in updates {
+            let mut msg = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0];
+            msg.extend_from_slice(&colour.to_le_bytes());
+            // Split a message over two blocks, it is complete with the second.
+            block(time.saturating_sub(5), &msg[..6]);
+            block(time, &msg[6..]);
+        }
+        data
+    }
+
+    fn left_pixel(player: &Player) -> u32 {
+        player.canvas.lock().unwrap().pixel(0, 0)
+    }
+
+    #[test]
+    fn test_recording() {
+        let recording = Recording::parse(&fbs_file(&[(100, 0x112233), (250, 0x445566)])).unwrap();
+        assert_eq!((recording.width, recording.height), (2, 1));
+        assert_eq!(recording.name, "rec");
+        assert_eq!(recording.duration(), 250);
+        assert_eq!(recording.messages.len(), 2);
+        assert_eq!(recording.messages[0].0, 100);
+
+        // Security type VNC authentication instead of None.
+        let mut data = fbs_file(&[]);
+        data[fbs::SIGNATURE.len() + 4 + 12 + 3] = 2;
+        assert!(Recording::parse(&data).is_err());
+
+        // A pixel format of 0 bits per pixel.
+        let mut data = fbs_file(&[(100, 0x112233)]);
+        data[fbs::SIGNATURE.len() + 4 + 12 + 4 + 4] = 0;
+        let err = Recording::parse(&data).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        data.truncate(fbs::SIGNATURE.len());
+        assert!(Recording::parse(&data).is_err());
+    }
+
+    #[test]
+    fn test_seek() {
+        let updates: Vec<(u32, u32)> = (1..=40).map(|i| (i * 1000, i)).collect();
+        let mut player = Player::new(Recording::parse(&fbs_file(&updates)).unwrap());
+        assert_eq!(player.interval, MIN_KEYFRAME_INTERVAL);
+
+        let changes = player.advance(2500).unwrap();
+        assert_eq!(changes.damage, [Rect::new(0, 0, 1, 1); 2]);
+        assert_eq!(left_pixel(&player), 2);
+        assert_eq!(player.next_time(), Some(3000));
+
+        player.seek(25_000).unwrap();
+        assert_eq!(left_pixel(&player), 25);
+        assert_eq!(player.keyframes.len(), 3);
+        player.seek(12_000).unwrap();
+        assert_eq!(left_pixel(&player), 12);
+        assert_eq!(player.next, 12);
+        player.seek(500).unwrap();
+        assert_eq!(left_pixel(&player), 0);
+        player.seek(40_000).unwrap();
+        assert!(player.finished());
+        assert_eq!(left_pixel(&player), 40);
+    }
+
+    #[test]
+    fn test_last_keyframe() {
+        let mut player = Player::new(Recording::parse(&fbs_file(&[(u32::MAX, 7)])).unwrap());
+        player.advance(u32::MAX).unwrap();
+        assert!(player.finished());
+        assert_eq!(left_pixel(&player), 7);
+        assert_eq!(player.keyframes.len(), MAX_KEYFRAMES as usize + 1);
+    }
+
+    #[test]
+    fn test_play() {
+        let mut player = Player::new(Recording::parse(&fbs_file(&[(1000, 0xff)])).unwrap());
+        let mut clock = Clock::new(MAX_SPEED);
+        let dir = std::env::temp_dir().join(format!("vncvwr-player-{}", std::process::id()));
+        let display =
+            crate::display::ImageDisplay::new(dir.clone(), crate::image::ImageFormat::Ppm);
+        let display: Mutex<Box<dyn Display>> = Mutex::new(Box::new(display.unwrap()));
+        let (controls, receiver) = mpsc::channel();
+        controls.send(Control::Seek(-SEEK_STEP)).unwrap();
+        drop(controls);
+
+        play(&mut player, &mut clock, &display, &receiver).unwrap();
+        assert!(player.finished() && clock.paused());
+        assert_eq!(clock.position(), 1000);
+        // The start, the same again after seeking, and the update.
+        assert!(dir.join("frame-000003.ppm").exists());
+        assert!(!dir.join("frame-000004.ppm").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+/*
+ * Translation of evdev pointer events to RFB PointerEvents.
+ *
+ * Relative devices (mice) move a local pointer position, absolute devices
+ * (touchscreens, tablets) report it directly within the range of their
+ * axes, corrected by the calibration of the device. Either way the
+ * position is in local display coordinates and goes through the view
+ * transform. Events are collected until SYN_REPORT, so a touch and its
+ * coordinates end up in the same PointerEvent.
+ */
+
+use crate::calibrate::Calibration;
+use crate::evdev::{self, InputEvent};
+use crate::rfb::ButtonMask;
+use crate::view::ViewTransform;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AbsRange {
+    pub min: i32,
+    pub max: i32,
+}
+
+/**
+ * Absolute position axes of a device.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AbsAxes {
+    pub x: AbsRange,
+    pub y: AbsRange,
+    pub calibration: Calibration,
+}
+
+#[derive(Clone, Debug)]
+pub struct Pointer {
+    axes: Option<AbsAxes>,
+    x: f64,
+    y: f64,
+    abs_x: Option<i32>,
+    abs_y: Option<i32>,
+    buttons: ButtonMask,
+    changed: bool,
+    wheel: i32,
+    hwheel: i32,
+}
+
+impl AbsRange {
+    /**
+     * Position of `value` within the range, from 0 to 1.
+     */
+    pub fn normalise(&self, value: i32) -> f64 {
+        let span = (self.max - self.min).max(1) as f64;
+        (value - self.min) as f64 / span
+    }
+}
+
+impl AbsAxes {
+    /**
+     * Local display position of a raw position.
+     */
+    pub fn local_position(&self, x: i32, y: i32, view: &ViewTransform) -> (f64, f64) {
+        let (x, y) = self
+            .calibration
+            .apply(self.x.normalise(x), self.y.normalise(y));
+        (
+            x * view.local_width.saturating_sub(1) as f64,
+            y * view.local_height.saturating_sub(1) as f64,
+        )
+    }
+}
+
+impl Pointer {
+    pub fn relative() -> Self {
+        Self::new(None)
+    }
+
+    pub fn absolute(axes: AbsAxes) -> Self {
+        Self::new(Some(axes))
+    }
+
+    fn new(axes: Option<AbsAxes>) -> Self {
+        Self {
+            axes,
+            x: -1.0,
+            y: -1.0,
+            abs_x: None,
+            abs_y: None,
+            buttons: ButtonMask::NONE,
+            changed: false,
+            wheel: 0,
+            hwheel: 0,
+        }
+    }
+
+    pub fn buttons(&self) -> ButtonMask {
+        self.buttons
+    }
+
+    /**
+     * Local position of a relative pointer once it moved, None for
+     * absolute pointers.
+     */
+    pub fn relative_position(&self) -> Option<(f64, f64)> {
+        (self.axes.is_none() && self.x >= 0.0).then_some((self.x, self.y))
+    }
+
+    /**
+     * Feed an event. On SYN_REPORT, returns the PointerEvents to send as
+     * button mask and remote position.
+     */
+    pub fn handle(
+        &mut self,
+        event: &InputEvent,
+        view: &ViewTransform,
+    ) -> Vec<(ButtonMask, u16, u16)> {
+        if self.x < 0.0 {
+            self.x = view.local_width as f64 / 2.0;
+            self.y = view.local_height as f64 / 2.0;
+        }
+        match (event.event_type, event.code) {
+            (evdev::EV_REL, evdev::REL_X) => self.move_by(event.value as f64, 0.0, view),
+            (evdev::EV_REL, evdev::REL_Y) => self.move_by(0.0, event.value as f64, view),
+            (evdev::EV_REL, evdev::REL_WHEEL) => self.wheel += event.value,
+            (evdev::EV_REL, evdev::REL_HWHEEL) => self.hwheel += event.value,
+            (evdev::EV_ABS, evdev::ABS_X) => {
+                self.abs_x = Some(event.value);
+                self.changed = true;
+            }
+            (evdev::EV_ABS, evdev::ABS_Y) => {
+                self.abs_y = Some(event.value);
+                self.changed = true;
+            }
+            (evdev::EV_KEY, code) if event.value != 2 => {
+                if let Some(button) = button(code) {
+                    self.buttons = self.buttons.with(button, event.value != 0);
+                    self.changed = true;
+                }
+            }
+            (evdev::EV_SYN, evdev::SYN_REPORT) => return self.report(view),
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    fn move_by(&mut self, dx: f64, dy: f64, view: &ViewTransform) {
+        self.x = (self.x + dx).clamp(0.0, view.local_width.saturating_sub(1) as f64);
+        self.y = (self.y + dy).clamp(0.0, view.local_height.saturating_sub(1) as f64);
+        self.changed = true;
+    }
+
+    fn report(&mut self, view: &ViewTransform) -> Vec<(ButtonMask, u16, u16)> {
+        if let (Some(axes), Some(x), Some(y)) = (self.axes, self.abs_x, self.abs_y) {
+            (self.x, self.y) = axes.local_position(x, y, view);
+        }
+        let (x, y) = view.to_remote(self.x, self.y);
+        let mut events = Vec::new();
+        if std::mem::take(&mut self.changed) {
+            events.push((self.buttons, x, y));
+        }
+        let wheels = [
+            (
+                std::mem::take(&mut self.wheel),
+                ButtonMask::WHEEL_UP,
+                ButtonMask::WHEEL_DOWN,
+            ),
+            (
+                std::mem::take(&mut self.hwheel),
+                ButtonMask::WHEEL_RIGHT,
+                ButtonMask::WHEEL_LEFT,
+            ),
+        ];
+        for (clicks, positive, negative) in wheels {
+            let wheel = if clicks > 0 { positive } else { negative };
+            for _ in 0..clicks.abs() {
+                events.push((self.buttons | wheel, x, y));
+                events.push((self.buttons, x, y));
+            }
+        }
+        events
+    }
+}
+
+fn button(code: u16) -> Option<ButtonMask> {
+    match code {
+        evdev::BTN_LEFT | evdev::BTN_TOUCH => Some(ButtonMask::LEFT),
+        evdev::BTN_RIGHT | evdev::BTN_STYLUS => Some(ButtonMask::RIGHT),
+        evdev::BTN_MIDDLE | evdev::BTN_STYLUS2 => Some(ButtonMask::MIDDLE),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_type: u16, code: u16, value: i32) -> InputEvent {
+        InputEvent {
+            event_type,
+            code,
+            value,
+        }
+    }
+
+    const SYN: InputEvent = InputEvent {
+        event_type: evdev::EV_SYN,
+        code: evdev::SYN_REPORT,
+        value: 0,
+    };
+
+    #[test]
+    fn test_relative() {
+        let view = ViewTransform::identity(100, 50);
+        let mut pointer = Pointer::relative();
+        assert_eq!(
+            pointer.handle(&event(evdev::EV_REL, evdev::REL_X, 10), &view),
+            []
+        );
+        pointer.handle(&event(evdev::EV_REL, evdev::REL_Y, -100), &view);
+        assert_eq!(pointer.handle(&SYN, &view), [(ButtonMask::NONE, 60, 0)]);
+
+        pointer.handle(&event(evdev::EV_KEY, evdev::BTN_LEFT, 1), &view);
+        assert_eq!(pointer.handle(&SYN, &view), [(ButtonMask::LEFT, 60, 0)]);
+        assert_eq!(pointer.handle(&SYN, &view), []);
+
+        pointer.handle(&event(evdev::EV_REL, evdev::REL_WHEEL, -2), &view);
+        assert_eq!(
+            pointer.handle(&SYN, &view),
+            [
+                (ButtonMask::LEFT | ButtonMask::WHEEL_DOWN, 60, 0),
+                (ButtonMask::LEFT, 60, 0),
+                (ButtonMask::LEFT | ButtonMask::WHEEL_DOWN, 60, 0),
+                (ButtonMask::LEFT, 60, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_absolute() {
+        let mut view = ViewTransform::identity(1000, 500);
+        (view.scale_x, view.scale_y) = (0.5, 0.5);
+        let mut pointer = Pointer::absolute(AbsAxes {
+            x: AbsRange { min: 0, max: 4095 },
+            y: AbsRange { min: 0, max: 4095 },
+            calibration: Calibration::IDENTITY,
+        });
+        pointer.handle(&event(evdev::EV_ABS, evdev::ABS_X, 4095), &view);
+        pointer.handle(&event(evdev::EV_ABS, evdev::ABS_Y, 2048), &view);
+        pointer.handle(&event(evdev::EV_KEY, evdev::BTN_TOUCH, 1), &view);
+        assert_eq!(pointer.handle(&SYN, &view), [(ButtonMask::LEFT, 999, 499)]);
+
+        pointer.handle(&event(evdev::EV_ABS, evdev::ABS_X, 0), &view);
+        assert_eq!(pointer.handle(&SYN, &view), [(ButtonMask::LEFT, 0, 499)]);
+        pointer.handle(&event(evdev::EV_KEY, evdev::BTN_TOUCH, 0), &view);
+        assert_eq!(pointer.handle(&SYN, &view), [(ButtonMask::NONE, 0, 499)]);
+        assert_eq!(pointer.buttons(), ButtonMask::NONE);
+    }
+
+    #[test]
+    fn test_calibrated() {
+        let view = ViewTransform::identity(101, 51);
+        let mut pointer = Pointer::absolute(AbsAxes {
+            x: AbsRange {
+                min: 100,
+                max: 1100,
+            },
+            y: AbsRange { min: 0, max: 1000 },
+            calibration: Calibration::SWAP_AXES.then(&Calibration::INVERT_Y),
+        });
+        pointer.handle(&event(evdev::EV_ABS, evdev::ABS_X, 300), &view);
+        pointer.handle(&event(evdev::EV_ABS, evdev::ABS_Y, 600), &view);
+        assert_eq!(pointer.handle(&SYN, &view), [(ButtonMask::NONE, 60, 40)]);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::d3des::{Des, Direction};
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SecurityType {
     Invalid = 0,
@@ -23,6 +25,110 @@ pub enum Encoding {
     ZRLE = 16,
     CursorPseudo = -239,
     DesktopSizePseudo = -223,
+    PointerPosPseudo = -232,
+    QemuExtendedKeyEventPseudo = -258,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub bits_per_pixel: u8,
+    pub depth: u8,
+    pub big_endian_flag: u8,
+    pub true_color_flag: u8,
+    pub red_max: u16,
+    pub green_max: u16,
+    pub blue_max: u16,
+    pub red_shift: u8,
+    pub green_shift: u8,
+    pub blue_shift: u8,
+}
+
+impl From<[u8; 16]> for PixelFormat {
+    fn from(src: [u8; 16]) -> Self {
+        PixelFormat {
+            bits_per_pixel: src[0],
+            depth: src[1],
+            big_endian_flag: src[2],
+            true_color_flag: src[3],
+            red_max: u16::from_be_bytes([src[4], src[5]]),
+            green_max: u16::from_be_bytes([src[6], src[7]]),
+            blue_max: u16::from_be_bytes([src[8], src[9]]),
+            red_shift: src[10],
+            green_shift: src[11],
+            blue_shift: src[12],
+        }
+    }
+}
+
+impl From<&PixelFormat> for [u8; 16] {
+    fn from(src: &PixelFormat) -> Self {
+        [
+            src.bits_per_pixel,
+            src.depth,
+            src.big_endian_flag,
+            src.true_color_flag,
+            (src.red_max >> 8) as u8,
+            src.red_max as u8,
+            (src.green_max >> 8) as u8,
+            src.green_max as u8,
+            (src.blue_max >> 8) as u8,
+            src.blue_max as u8,
+            src.red_shift,
+            src.green_shift,
+            src.blue_shift,
+            0u8,
+            0u8,
+            0u8,
+        ]
+    }
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bits_per_pixel as usize / 8
+    }
+
+    /**
+     * Whether pixels in this format can be decoded: 8, 16 or 32 bits per
+     * pixel, with the colour channels inside the pixel value.
+     */
+    pub fn is_supported(&self) -> bool {
+        let shifts = [self.red_shift, self.green_shift, self.blue_shift];
+        matches!(self.bits_per_pixel, 8 | 16 | 32)
+            && (self.true_color_flag == 0 || shifts.iter().all(|&shift| shift < 32))
+    }
+
+    /**
+     * Read a single pixel value as sent on the wire. `data` must hold at
+     * least `bytes_per_pixel` bytes.
+     */
+    pub fn read_pixel(&self, data: &[u8]) -> u32 {
+        let big_endian = self.big_endian_flag != 0;
+        match self.bytes_per_pixel() {
+            1 => data[0] as u32,
+            2 if big_endian => u16::from_be_bytes([data[0], data[1]]) as u32,
+            2 => u16::from_le_bytes([data[0], data[1]]) as u32,
+            4 if big_endian => u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+            4 => u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            _ => 0,
+        }
+    }
+
+    /**
+     * Convert a true colour pixel value to 0x00RRGGBB.
+     */
+    pub fn to_rgb(&self, value: u32) -> u32 {
+        let channel = |shift: u8, max: u16| -> u32 {
+            if max == 0 {
+                return 0;
+            }
+            let v = value.checked_shr(shift as u32).unwrap_or(0) & max as u32;
+            v * 255 / max as u32
+        };
+        (channel(self.red_shift, self.red_max) << 16)
+            | (channel(self.green_shift, self.green_max) << 8)
+            | channel(self.blue_shift, self.blue_max)
+    }
 }
 
 fn as_digit(ascii_char: u8) -> u8 {
@@ -48,3 +154,82 @@ pub fn parse_offered_version(data: &[u8]) -> RfbVersion {
         _ => RfbVersion::Unsupported,
     }
 }
+
+/**
+ * Pick the security type to use from the list offered by the server.
+ * None is preferred over VncAuth, anything else is not supported.
+ */
+pub fn preferred_security_type(offered: &[u8]) -> SecurityType {
+    offered
+        .iter()
+        .fold(SecurityType::Invalid, |acc, &x| match (acc, x) {
+            (SecurityType::None, _) => acc,
+            (_, s) if s == SecurityType::None as u8 => SecurityType::None,
+            (_, s) if s == SecurityType::VncAuth as u8 => SecurityType::VncAuth,
+            _ => acc,
+        })
+}
+
+/**
+ * Encrypt the VncAuth challenge with the password. Only the first eight
+ * bytes of the password are used.
+ */
+pub fn vnc_auth_response(password: &str, challenge: &[u8; 16]) -> [u8; 16] {
+    let mut passwd_buf = [0u8; 8];
+    let n = password.len().min(8);
+    passwd_buf[..n].copy_from_slice(&password.as_bytes()[..n]);
+
+    let des = Des::new(&passwd_buf, Direction::Encrypt);
+    let mut response = [0u8; 16];
+    response[0..8].copy_from_slice(&des.encrypt_block(&challenge[0..8]));
+    response[8..16].copy_from_slice(&des.encrypt_block(&challenge[8..16]));
+    response
+}
+
+/**
+ * Pointer button state for PointerEvent. Buttons 4 to 7 are the wheel: a
+ * single wheel click is sent as a press followed by a release.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ButtonMask(pub u8);
+
+impl ButtonMask {
+    pub const NONE: ButtonMask = ButtonMask(0);
+    pub const LEFT: ButtonMask = ButtonMask(1 << 0);
+    pub const MIDDLE: ButtonMask = ButtonMask(1 << 1);
+    pub const RIGHT: ButtonMask = ButtonMask(1 << 2);
+    pub const WHEEL_UP: ButtonMask = ButtonMask(1 << 3);
+    pub const WHEEL_DOWN: ButtonMask = ButtonMask(1 << 4);
+    pub const WHEEL_LEFT: ButtonMask = ButtonMask(1 << 5);
+    pub const WHEEL_RIGHT: ButtonMask = ButtonMask(1 << 6);
+
+    pub fn with(self, other: ButtonMask, pressed: bool) -> ButtonMask {
+        if pressed {
+            ButtonMask(self.0 | other.0)
+        } else {
+            ButtonMask(self.0 & !other.0)
+        }
+    }
+}
+
+impl std::ops::BitOr for ButtonMask {
+    type Output = ButtonMask;
+
+    fn bitor(self, rhs: ButtonMask) -> ButtonMask {
+        ButtonMask(self.0 | rhs.0)
+    }
+}
+
+/**
+ * Cut text is ISO 8859-1 on the wire. Characters outside of it are
+ * replaced by '?'.
+ */
+pub fn to_latin1(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' })
+        .collect()
+}
+
+pub fn from_latin1(data: &[u8]) -> String {
+    data.iter().map(|&b| b as char).collect()
+}
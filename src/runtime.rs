@@ -0,0 +1,455 @@
+/*
+ * Threaded runtime around a `Session` that completed its handshake.
+ *
+ * A reader thread owns the session, decodes server messages into a shared
+ * `Canvas` and keeps requesting incremental updates. Client messages are
+ * written through `InputSender`, which can be cloned and used from any
+ * thread, so input is not held up by a large update being received.
+ * Everything the reader observes is reported on the event channel, and
+ * every update is rendered to the display.
+ */
+
+use crate::canvas::{Canvas, Change, Rect};
+use crate::codec::{ClientMessage, ServerMessage};
+use crate::display::Display;
+use crate::keysym;
+use crate::rfb;
+use crate::scancode;
+use crate::session::{ReceiveError, Session};
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    FramebufferUpdated(Vec<Rect>),
+    DesktopResized(u16, u16),
+    CursorChanged,
+    CursorMoved(u16, u16),
+    Bell,
+    ServerCutText(String),
+    Disconnected,
+}
+
+#[derive(Clone, Debug)]
+pub struct InputSender {
+    stream: Arc<Mutex<TcpStream>>,
+    qemu_key_events: Arc<AtomicBool>,
+}
+
+/**
+ * Renders the canvas to the display from outside the reader thread, for
+ * when what the display shows changes without an update from the server.
+ */
+#[derive(Clone)]
+pub struct Renderer {
+    canvas: Arc<Mutex<Canvas>>,
+    display: Arc<Mutex<Box<dyn Display>>>,
+}
+
+pub struct Runtime {
+    input: InputSender,
+    canvas: Arc<Mutex<Canvas>>,
+    display: Arc<Mutex<Box<dyn Display>>>,
+    events: Receiver<Event>,
+    reader: JoinHandle<Result<(), ReceiveError>>,
+}
+
+impl InputSender {
+    /**
+     * Encode and write a message. Messages from concurrent callers are never
+     * interleaved on the wire.
+     */
+    pub fn send(&self, msg: &ClientMessage) -> Result<(), std::io::Error> {
+        let mut encoded = Vec::new();
+        msg.encode(&mut encoded);
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(&encoded)
+    }
+
+    /**
+     * Press or release the key with the given X11 keysym.
+     */
+    pub fn key_event(&self, down: bool, keysym: u32) -> Result<(), std::io::Error> {
+        self.send(&ClientMessage::KeyEvent { down, key: keysym })
+    }
+
+    /**
+     * Whether the server acknowledged the QEMU Extended Key Event
+     * pseudo-encoding. Updated by the reader thread.
+     */
+    pub fn qemu_key_events(&self) -> bool {
+        self.qemu_key_events.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Press or release a physical key, given by its Linux keycode. Servers
+     * supporting QEMU key events get the scancode along with the keysym, so
+     * they don't depend on the keysym matching their keyboard layout.
+     */
+    pub fn physical_key_event(
+        &self,
+        down: bool,
+        keysym: u32,
+        keycode: u16,
+    ) -> Result<(), std::io::Error> {
+        match scancode::from_keycode(keycode).filter(|_| self.qemu_key_events()) {
+            Some(keycode) => self.send(&ClientMessage::QemuExtendedKeyEvent {
+                down,
+                keysym,
+                keycode,
+            }),
+            None => self.key_event(down, keysym),
+        }
+    }
+
+    /**
+     * Type text by pressing and releasing the keysym of every character.
+     * Control characters without a key are left out.
+     */
+    pub fn type_text(&self, text: &str) -> Result<(), std::io::Error> {
+        for keysym in text.chars().filter_map(keysym::from_char) {
+            self.key_event(true, keysym)?;
+            self.key_event(false, keysym)?;
+        }
+        Ok(())
+    }
+
+    pub fn pointer_event(
+        &self,
+        buttons: rfb::ButtonMask,
+        x: u16,
+        y: u16,
+    ) -> Result<(), std::io::Error> {
+        self.send(&ClientMessage::PointerEvent {
+            button_mask: buttons.0,
+            x,
+            y,
+        })
+    }
+
+    /**
+     * Send a single wheel click, `wheel` being one of the wheel buttons.
+     * `buttons` are the other buttons currently held down.
+     */
+    pub fn scroll(
+        &self,
+        wheel: rfb::ButtonMask,
+        buttons: rfb::ButtonMask,
+        x: u16,
+        y: u16,
+    ) -> Result<(), std::io::Error> {
+        self.pointer_event(buttons | wheel, x, y)?;
+        self.pointer_event(buttons, x, y)
+    }
+
+    pub fn client_cut_text(&self, text: &str) -> Result<(), std::io::Error> {
+        self.send(&ClientMessage::ClientCutText(rfb::to_latin1(text)))
+    }
+
+    /**
+     * Close the connection, which ends the reader thread.
+     */
+    pub fn shutdown(&self) {
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+impl Renderer {
+    pub fn new(canvas: Arc<Mutex<Canvas>>, display: Arc<Mutex<Box<dyn Display>>>) -> Self {
+        Self { canvas, display }
+    }
+
+    /**
+     * Show the canvas again without damage, so a display can catch up with
+     * a change of the view.
+     */
+    pub fn redraw(&self) -> Result<(), std::io::Error> {
+        let canvas = self.canvas.lock().unwrap();
+        self.display.lock().unwrap().update(&canvas, &[])
+    }
+}
+
+impl Runtime {
+    /**
+     * Start the reader thread. The pixel format and encodings should be set
+     * on the session before, the runtime only requests updates.
+     */
+    pub fn start(session: Session, display: Box<dyn Display>) -> Result<Self, std::io::Error> {
+        let input = InputSender {
+            stream: Arc::new(Mutex::new(session.try_clone_stream()?)),
+            qemu_key_events: Arc::new(AtomicBool::new(session.qemu_key_events())),
+        };
+        let canvas = Arc::new(Mutex::new(Canvas::new(
+            session.screen_w(),
+            session.screen_h(),
+        )));
+        let display = Arc::new(Mutex::new(display));
+        let (event_tx, events) = mpsc::channel();
+
+        let reader = {
+            let input = input.clone();
+            let canvas = canvas.clone();
+            let display = display.clone();
+            std::thread::spawn(move || {
+                let result = Self::run(session, &display, &input, &canvas, &event_tx);
+                let _ = event_tx.send(Event::Disconnected);
+                result
+            })
+        };
+
+        Ok(Self {
+            input,
+            canvas,
+            display,
+            events,
+            reader,
+        })
+    }
+
+    pub fn input(&self) -> InputSender {
+        self.input.clone()
+    }
+
+    pub fn canvas(&self) -> Arc<Mutex<Canvas>> {
+        self.canvas.clone()
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        Renderer::new(self.canvas.clone(), self.display.clone())
+    }
+
+    pub fn events(&self) -> &Receiver<Event> {
+        &self.events
+    }
+
+    /**
+     * Wait for the session to end, which is Ok if the connection was closed
+     * between messages.
+     */
+    pub fn wait(self) -> Result<(), ReceiveError> {
+        self.reader.join().expect("reader thread panicked")
+    }
+
+    fn run(
+        mut session: Session,
+        display: &Mutex<Box<dyn Display>>,
+        input: &InputSender,
+        canvas: &Mutex<Canvas>,
+        events: &Sender<Event>,
+    ) -> Result<(), ReceiveError> {
+        input.send(&ClientMessage::FramebufferUpdateRequest {
+            incremental: false,
+            x: 0,
+            y: 0,
+            width: session.screen_w(),
+            height: session.screen_h(),
+        })?;
+
+        loop {
+            let msg = match session.read_message() {
+                Ok(msg) => msg,
+                Err(ReceiveError::Closed) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            input
+                .qemu_key_events
+                .store(session.qemu_key_events(), Ordering::Relaxed);
+            match msg {
+                ServerMessage::FramebufferUpdate(rects) => {
+                    let format = session
+                        .pixel_format()
+                        .cloned()
+                        .ok_or(ReceiveError::NotInitialized)?;
+                    let mut damage = Vec::new();
+                    let mut notifications = Vec::new();
+                    let (width, height) = {
+                        let mut canvas = canvas.lock().unwrap();
+                        for rect in &rects {
+                            match canvas
+                                .apply(rect, &format)
+                                .map_err(ReceiveError::DecodeError)?
+                            {
+                                Change::Damaged(area) if !area.is_empty() => damage.push(area),
+                                Change::Damaged(_) | Change::Unchanged => {}
+                                Change::Resized(w, h) => {
+                                    notifications.push(Event::DesktopResized(w, h))
+                                }
+                                Change::CursorChanged => notifications.push(Event::CursorChanged),
+                                Change::CursorMoved(x, y) => {
+                                    notifications.push(Event::CursorMoved(x, y))
+                                }
+                            }
+                        }
+                        (canvas.width(), canvas.height())
+                    };
+
+                    input.send(&ClientMessage::FramebufferUpdateRequest {
+                        incremental: true,
+                        x: 0,
+                        y: 0,
+                        width,
+                        height,
+                    })?;
+
+                    {
+                        let canvas = canvas.lock().unwrap();
+                        let mut display = display.lock().unwrap();
+                        if notifications
+                            .iter()
+                            .any(|event| matches!(event, Event::DesktopResized(..)))
+                        {
+                            display.resized(&canvas)?;
+                        } else if !damage.is_empty() {
+                            display.update(&canvas, &damage)?;
+                        }
+                    }
+
+                    for notification in notifications {
+                        let _ = events.send(notification);
+                    }
+                    if !damage.is_empty() {
+                        let _ = events.send(Event::FramebufferUpdated(damage));
+                    }
+                }
+                ServerMessage::SetColourMapEntries {
+                    first_colour,
+                    colours,
+                } => canvas
+                    .lock()
+                    .unwrap()
+                    .set_colour_map_entries(first_colour, &colours),
+                ServerMessage::Bell => {
+                    let _ = events.send(Event::Bell);
+                }
+                ServerMessage::ServerCutText(text) => {
+                    let _ = events.send(Event::ServerCutText(rfb::from_latin1(&text)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::ImageDisplay;
+    use crate::image::ImageFormat;
+    use crate::mock_server::{MockServer, Script};
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn connect(server: &MockServer) -> InputSender {
+        let mut session = Session::new("127.0.0.1", server.port()).unwrap();
+        session.handshake().unwrap();
+        InputSender {
+            stream: Arc::new(Mutex::new(session.try_clone_stream().unwrap())),
+            qemu_key_events: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    #[test]
+    fn test_updates_and_input() {
+        let server = MockServer::start(
+            Script::new()
+                .handshake(2, 1, &Session::PREFERRED_PIXEL_FORMAT, "")
+                .expect(&[3, 0, 0, 0, 0, 0, 0, 2, 0, 1])
+                .framebuffer_update(&[(
+                    0,
+                    0,
+                    2,
+                    1,
+                    rfb::Encoding::Raw as i32,
+                    &[0x33, 0x22, 0x11, 0, 0x66, 0x55, 0x44, 0],
+                )])
+                .expect(&[3, 1, 0, 0, 0, 0, 0, 2, 0, 1])
+                .expect(&[4, 1, 0, 0, 0, 0, 0xff, 0x0d])
+                .send(&[2]),
+        );
+        let mut session = Session::new("127.0.0.1", server.port()).unwrap();
+        session.handshake().unwrap();
+        let dir = std::env::temp_dir().join(format!("vncvwr-runtime-{}", std::process::id()));
+        let display = ImageDisplay::new(dir.clone(), ImageFormat::Ppm).unwrap();
+        let runtime = Runtime::start(session, Box::new(display)).unwrap();
+
+        assert_eq!(
+            runtime.events().recv_timeout(TIMEOUT),
+            Ok(Event::FramebufferUpdated(vec![Rect::new(0, 0, 2, 1)]))
+        );
+        assert_eq!(
+            runtime.canvas().lock().unwrap().pixels(),
+            [0x112233, 0x445566]
+        );
+        assert_eq!(
+            std::fs::read(dir.join("frame-000001.ppm")).unwrap(),
+            b"P6\n2 1\n255\n\x11\x22\x33\x44\x55\x66"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let input = runtime.input();
+        std::thread::spawn(move || input.key_event(true, keysym::RETURN).unwrap())
+            .join()
+            .unwrap();
+
+        assert_eq!(runtime.events().recv_timeout(TIMEOUT), Ok(Event::Bell));
+        server.finish();
+        assert_eq!(
+            runtime.events().recv_timeout(TIMEOUT),
+            Ok(Event::Disconnected)
+        );
+        assert!(runtime.wait().is_ok());
+    }
+
+    #[test]
+    fn test_input_messages() {
+        let server = MockServer::start(
+            Script::new()
+                .handshake(640, 480, &Session::PREFERRED_PIXEL_FORMAT, "")
+                .expect(&[4, 1, 0, 0, 0, 0, 0, 0x41])
+                .expect(&[4, 0, 0, 0, 0, 0, 0xff, 0xe1])
+                .expect(&[5, 0b101, 0, 10, 0, 20])
+                .expect(&[5, 0b1001, 0, 10, 0, 20])
+                .expect(&[5, 0b0001, 0, 10, 0, 20])
+                .expect(&[6, 0, 0, 0, 0, 0, 0, 4, b'a', 0xe9, b'?', b'z'])
+                .expect(&[4, 1, 0, 0, 0, 0, 0x06, 0xf6])
+                .expect(&[4, 0, 0, 0, 0, 0, 0x06, 0xf6])
+                .expect(&[4, 1, 0, 0, 0x01, 0x00, 0x4e, 0x2d])
+                .expect(&[4, 0, 0, 0, 0x01, 0x00, 0x4e, 0x2d]),
+        );
+        let input = connect(&server);
+        input.key_event(true, 'A' as u32).unwrap();
+        input.key_event(false, keysym::SHIFT_L).unwrap();
+        input
+            .pointer_event(rfb::ButtonMask::LEFT | rfb::ButtonMask::RIGHT, 10, 20)
+            .unwrap();
+        input
+            .scroll(rfb::ButtonMask::WHEEL_UP, rfb::ButtonMask::LEFT, 10, 20)
+            .unwrap();
+        input.client_cut_text("a\u{e9}\u{20ac}z").unwrap();
+        input.type_text("\u{416}\u{1}\u{4e2d}").unwrap();
+        server.finish();
+    }
+
+    #[test]
+    fn test_physical_key_events() {
+        let server = MockServer::start(
+            Script::new()
+                .handshake(640, 480, &Session::PREFERRED_PIXEL_FORMAT, "")
+                .expect(&[4, 1, 0, 0, 0, 0, 0, 0x71])
+                .expect(&[255, 0, 0, 1, 0, 0, 0, 0x71, 0, 0, 0, 0x10])
+                .expect(&[255, 0, 0, 0, 0, 0, 0xff, 0x54, 0, 0, 0, 0xd0])
+                .expect(&[4, 1, 0, 0, 0, 0, 0, 0x61]),
+        );
+        let input = connect(&server);
+        input.physical_key_event(true, 'q' as u32, 16).unwrap();
+        input.qemu_key_events.store(true, Ordering::Relaxed);
+        input.physical_key_event(true, 'q' as u32, 16).unwrap();
+        input.physical_key_event(false, keysym::DOWN, 108).unwrap();
+        input.physical_key_event(true, 'a' as u32, 0x2ff).unwrap();
+        server.finish();
+    }
+}
@@ -0,0 +1,311 @@
+/*
+ * Scaling the remote desktop to the local display.
+ *
+ * The scale and position come from the view transform, so what is shown
+ * and where input goes always agree. `ScaledDisplay` keeps a local sized
+ * frame and only re-samples the part of it covered by the damaged remote
+ * areas; a change of the view itself renders the whole frame again. Areas
+ * of the frame outside the remote desktop stay black.
+ */
+
+use crate::canvas::{Canvas, Rect};
+use crate::display::Display;
+use crate::view::ViewTransform;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScaleMode {
+    /** One remote pixel per local pixel. */
+    #[default]
+    None,
+    /** As large as fits, keeping the aspect ratio, letterboxed. */
+    Fit,
+    /** Fill the local display, ignoring the aspect ratio. */
+    Stretch,
+    /** The largest whole multiple that fits, letterboxed. */
+    Integer,
+}
+
+/**
+ * How local pixels are sampled from the remote desktop.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Filter {
+    #[default]
+    Nearest,
+    Bilinear,
+    /** Average of all remote pixels under a local pixel, for downscaling. */
+    Box,
+}
+
+impl ScaleMode {
+    pub fn parse(mode: &str) -> Result<Self, String> {
+        match mode {
+            "none" => Ok(ScaleMode::None),
+            "fit" => Ok(ScaleMode::Fit),
+            "stretch" => Ok(ScaleMode::Stretch),
+            "integer" => Ok(ScaleMode::Integer),
+            _ => Err(format!("invalid scaling mode {}", mode)),
+        }
+    }
+
+    /**
+     * Horizontal and vertical scale of a remote desktop on a local display.
+     */
+    pub fn factors(&self, local: (u16, u16), remote: (u16, u16)) -> (f64, f64) {
+        let x = local.0 as f64 / remote.0.max(1) as f64;
+        let y = local.1 as f64 / remote.1.max(1) as f64;
+        match self {
+            ScaleMode::None => (1.0, 1.0),
+            ScaleMode::Fit => (x.min(y), x.min(y)),
+            ScaleMode::Stretch => (x, y),
+            ScaleMode::Integer => {
+                let factor = x.min(y).floor().max(1.0);
+                (factor, factor)
+            }
+        }
+    }
+}
+
+impl Filter {
+    pub fn parse(filter: &str) -> Result<Self, String> {
+        match filter {
+            "nearest" => Ok(Filter::Nearest),
+            "bilinear" => Ok(Filter::Bilinear),
+            "box" => Ok(Filter::Box),
+            _ => Err(format!("invalid filter {}", filter)),
+        }
+    }
+}
+
+/**
+ * Mix two 0x00RRGGBB pixels, `t` of the way from `a` to `b`.
+ */
+fn mix(a: u32, b: u32, t: f64) -> u32 {
+    [16, 8, 0].iter().fold(0, |pixel, shift| {
+        let a = ((a >> shift) & 0xff) as f64;
+        let b = ((b >> shift) & 0xff) as f64;
+        pixel | ((a + (b - a) * t).round() as u32) << shift
+    })
+}
+
+fn bilinear(src: &Canvas, x: f64, y: f64) -> u32 {
+    let x = x.clamp(0.0, (src.width() - 1) as f64);
+    let y = y.clamp(0.0, (src.height() - 1) as f64);
+    let (x0, y0) = (x.floor() as u16, y.floor() as u16);
+    let x1 = (x0 + 1).min(src.width() - 1);
+    let y1 = (y0 + 1).min(src.height() - 1);
+    let (tx, ty) = (x - x0 as f64, y - y0 as f64);
+    let top = mix(src.pixel(x0, y0), src.pixel(x1, y0), tx);
+    let bottom = mix(src.pixel(x0, y1), src.pixel(x1, y1), tx);
+    mix(top, bottom, ty)
+}
+
+fn average(src: &Canvas, left: f64, top: f64, right: f64, bottom: f64) -> u32 {
+    let span = |start: f64, end: f64, size: u16| {
+        let start = start.floor().clamp(0.0, (size - 1) as f64) as u16;
+        let end = (end.ceil().min(size as f64) as u16).max(start + 1);
+        start..end
+    };
+    let mut sum = [0u64; 3];
+    let mut count = 0;
+    for y in span(top, bottom, src.height()) {
+        for x in span(left, right, src.width()) {
+            let pixel = src.pixel(x, y);
+            sum[0] += ((pixel >> 16) & 0xff) as u64;
+            sum[1] += ((pixel >> 8) & 0xff) as u64;
+            sum[2] += (pixel & 0xff) as u64;
+            count += 1;
+        }
+    }
+    let channel = |sum: u64| ((sum + count / 2) / count) as u32;
+    channel(sum[0]) << 16 | channel(sum[1]) << 8 | channel(sum[2])
+}
+
+/**
+ * Remote pixel value for the local pixel at (`x`, `y`).
+ */
+fn sample(src: &Canvas, view: &ViewTransform, x: f64, y: f64, filter: Filter) -> u32 {
+    let left = x / view.scale_x + view.origin_x;
+    let top = y / view.scale_y + view.origin_y;
+    let right = (x + 1.0) / view.scale_x + view.origin_x;
+    let bottom = (y + 1.0) / view.scale_y + view.origin_y;
+    let (centre_x, centre_y) = ((left + right) / 2.0, (top + bottom) / 2.0);
+    if centre_x < 0.0
+        || centre_y < 0.0
+        || centre_x >= src.width() as f64
+        || centre_y >= src.height() as f64
+    {
+        return 0;
+    }
+    match filter {
+        Filter::Nearest => src.pixel(centre_x as u16, centre_y as u16),
+        Filter::Bilinear => bilinear(src, centre_x - 0.5, centre_y - 0.5),
+        Filter::Box => average(src, left, top, right, bottom),
+    }
+}
+
+/**
+ * Render the local `area` of `dst` from the remote canvas `src`.
+ */
+pub fn render(src: &Canvas, dst: &mut Canvas, view: &ViewTransform, area: Rect, filter: Filter) {
+    let area = area.clip(dst.width(), dst.height());
+    let width = dst.width() as usize;
+    let pixels = dst.pixels_mut();
+    for y in area.y as usize..area.bottom() as usize {
+        for x in area.x as usize..area.right() as usize {
+            pixels[y * width + x] = if src.width() == 0 || src.height() == 0 {
+                0
+            } else {
+                sample(src, view, x as f64, y as f64, filter)
+            };
+        }
+    }
+}
+
+/**
+ * Local area affected by a change of the remote `rect`, including the
+ * neighbours a filter may read.
+ */
+pub fn local_area(view: &ViewTransform, rect: Rect) -> Rect {
+    let (left, top) = view.to_local(rect.x as f64, rect.y as f64);
+    let (right, bottom) = view.to_local(rect.right() as f64, rect.bottom() as f64);
+    let margin_x = view.scale_x.max(1.0).ceil();
+    let margin_y = view.scale_y.max(1.0).ceil();
+    let clamp = |value: f64, size: u16| value.clamp(0.0, size as f64) as u16;
+    let x = clamp(left.floor() - margin_x, view.local_width);
+    let y = clamp(top.floor() - margin_y, view.local_height);
+    let right = clamp(right.ceil() + margin_x, view.local_width);
+    let bottom = clamp(bottom.ceil() + margin_y, view.local_height);
+    Rect::new(x, y, right - x, bottom - y)
+}
+
+/**
+ * Shows the remote desktop on a display of fixed size through the shared
+ * view transform.
+ */
+pub struct ScaledDisplay {
+    inner: Box<dyn Display>,
+    view: Arc<Mutex<ViewTransform>>,
+    filter: Filter,
+    frame: Canvas,
+    rendered: Option<ViewTransform>,
+}
+
+impl ScaledDisplay {
+    pub fn new(inner: Box<dyn Display>, view: Arc<Mutex<ViewTransform>>, filter: Filter) -> Self {
+        let (width, height) = inner.size().unwrap_or_default();
+        Self {
+            inner,
+            view,
+            filter,
+            frame: Canvas::new(width, height),
+            rendered: None,
+        }
+    }
+}
+
+impl Display for ScaledDisplay {
+    fn size(&self) -> Option<(u16, u16)> {
+        self.inner.size()
+    }
+
+    fn update(&mut self, canvas: &Canvas, damage: &[Rect]) -> Result<(), std::io::Error> {
+        let view = self.view.lock().unwrap().clone();
+        let frame = Rect::new(0, 0, self.frame.width(), self.frame.height());
+        if self.rendered.as_ref() != Some(&view) {
+            render(canvas, &mut self.frame, &view, frame, self.filter);
+            self.rendered = Some(view);
+            return self.inner.update(&self.frame, &[frame]);
+        }
+        let areas: Vec<Rect> = damage
+            .iter()
+            .map(|rect| local_area(&view, *rect))
+            .filter(|area| !area.is_empty())
+            .collect();
+        for area in &areas {
+            render(canvas, &mut self.frame, &view, *area, self.filter);
+        }
+        if areas.is_empty() {
+            return Ok(());
+        }
+        self.inner.update(&self.frame, &areas)
+    }
+
+    fn resized(&mut self, canvas: &Canvas) -> Result<(), std::io::Error> {
+        self.view
+            .lock()
+            .unwrap()
+            .set_remote_size(canvas.width(), canvas.height());
+        self.rendered = None;
+        self.update(canvas, &[])
+    }
+
+    fn redraw(&mut self, canvas: &Canvas) -> Result<(), std::io::Error> {
+        self.rendered = None;
+        self.update(canvas, &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MockDisplay;
+
+    #[test]
+    fn test_filters() {
+        let mut src = Canvas::new(4, 2);
+        src.fill_rect(Rect::new(0, 0, 2, 2), 0x000000);
+        src.fill_rect(Rect::new(2, 0, 2, 2), 0x0000ff);
+        src.fill_rect(Rect::new(1, 0, 1, 1), 0x0000ff);
+        let mut view = ViewTransform::new(2, 1, 4, 2);
+        view.set_mode(ScaleMode::Fit);
+        let mut dst = Canvas::new(2, 1);
+        let all = Rect::new(0, 0, 2, 1);
+
+        render(&src, &mut dst, &view, all, Filter::Nearest);
+        assert_eq!(dst.pixels(), [0x000000, 0x0000ff]);
+        render(&src, &mut dst, &view, all, Filter::Box);
+        assert_eq!(dst.pixels(), [0x000040, 0x0000ff]);
+        render(&src, &mut dst, &view, all, Filter::Bilinear);
+        assert_eq!(dst.pixels(), [0x000040, 0x0000ff]);
+
+        let mut view = ViewTransform::new(6, 2, 2, 1);
+        view.set_mode(ScaleMode::Integer);
+        let mut dst = Canvas::new(6, 2);
+        let mut src = Canvas::new(2, 1);
+        src.fill_rect(Rect::new(1, 0, 1, 1), 0xffffff);
+        render(
+            &src,
+            &mut dst,
+            &view,
+            Rect::new(0, 0, 6, 2),
+            Filter::Nearest,
+        );
+        assert_eq!(dst.pixels()[..6], [0, 0, 0, 0xffffff, 0xffffff, 0]);
+    }
+
+    #[test]
+    fn test_scaled_display() {
+        let mock = MockDisplay::new(4, 2);
+        let mut view = ViewTransform::new(4, 2, 8, 4);
+        view.set_mode(ScaleMode::Fit);
+        let view = Arc::new(Mutex::new(view));
+        let mut display = ScaledDisplay::new(Box::new(mock.clone()), view.clone(), Filter::Nearest);
+        let mut canvas = Canvas::new(8, 4);
+
+        display.update(&canvas, &[Rect::new(0, 0, 1, 1)]).unwrap();
+        assert_eq!(mock.log().updates[0].1, [Rect::new(0, 0, 4, 2)]);
+
+        canvas.fill_rect(Rect::new(6, 2, 2, 2), 0xffffff);
+        display.update(&canvas, &[Rect::new(6, 2, 2, 2)]).unwrap();
+        let (pixels, damage) = mock.log().updates[1].clone();
+        assert_eq!(damage, [Rect::new(2, 0, 2, 2)]);
+        assert_eq!(pixels, [0, 0, 0, 0, 0, 0, 0, 0xffffff]);
+
+        canvas.resize(4, 4);
+        display.resized(&canvas).unwrap();
+        assert_eq!(view.lock().unwrap().scale_x, 0.5);
+        assert_eq!(mock.log().updates[2].1, [Rect::new(0, 0, 4, 2)]);
+    }
+}
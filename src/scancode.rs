@@ -0,0 +1,68 @@
+/*
+ * Linux keycodes to XT (set 1) scancodes, as used by the QEMU Extended Key
+ * Event message.
+ *
+ * The first 88 Linux keycodes are the XT scancodes. Keys sent with an 0xe0
+ * prefix are encoded as 0x80 | scancode, the way QEMU numbers them.
+ */
+
+pub fn from_keycode(keycode: u16) -> Option<u32> {
+    let scancode = match keycode {
+        1..=83 | 86..=88 => keycode,
+        85 => 0x76,  // Zenkaku/Hankaku
+        89 => 0x73,  // Ro
+        92 => 0x79,  // Henkan
+        93 => 0x70,  // Katakana/Hiragana
+        94 => 0x7b,  // Muhenkan
+        96 => 0x9c,  // KP Enter
+        97 => 0x9d,  // Right Ctrl
+        98 => 0xb5,  // KP Divide
+        99 => 0xb7,  // Print
+        100 => 0xb8, // Right Alt
+        102 => 0xc7, // Home
+        103 => 0xc8, // Up
+        104 => 0xc9, // Page Up
+        105 => 0xcb, // Left
+        106 => 0xcd, // Right
+        107 => 0xcf, // End
+        108 => 0xd0, // Down
+        109 => 0xd1, // Page Down
+        110 => 0xd2, // Insert
+        111 => 0xd3, // Delete
+        113 => 0xa0, // Mute
+        114 => 0xae, // Volume Down
+        115 => 0xb0, // Volume Up
+        116 => 0xde, // Power
+        117 => 0x59, // KP Equal
+        119 => 0xc6, // Pause
+        121 => 0x7e, // KP Comma
+        124 => 0x7d, // Yen
+        125 => 0xdb, // Left Meta
+        126 => 0xdc, // Right Meta
+        127 => 0xdd, // Compose
+        142 => 0xdf, // Sleep
+        143 => 0xe3, // Wake Up
+        163 => 0x99, // Next Song
+        164 => 0xa2, // Play/Pause
+        165 => 0x90, // Previous Song
+        166 => 0xa4, // Stop CD
+        _ => return None,
+    };
+    Some(scancode as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_keycode() {
+        assert_eq!(from_keycode(1), Some(0x01));
+        assert_eq!(from_keycode(30), Some(0x1e));
+        assert_eq!(from_keycode(86), Some(0x56));
+        assert_eq!(from_keycode(100), Some(0xb8));
+        assert_eq!(from_keycode(103), Some(0xc8));
+        assert_eq!(from_keycode(84), None);
+        assert_eq!(from_keycode(0), None);
+    }
+}
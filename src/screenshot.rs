@@ -0,0 +1,193 @@
+/*
+ * One-shot screenshots: connect, request the whole screen once, decode the
+ * update and disconnect.
+ *
+ * Everything from connecting to the last byte of the update has to happen
+ * within one timeout, so a server that hangs cannot stall a script that
+ * goes through many of them.
+ */
+
+use crate::canvas::Canvas;
+use crate::codec::ServerMessage;
+use crate::rfb;
+use crate::session::{HandshakeError, ReceiveError, Session};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const ENCODINGS: [rfb::Encoding; 4] = [
+    rfb::Encoding::Hextile,
+    rfb::Encoding::RRE,
+    rfb::Encoding::CopyRect,
+    rfb::Encoding::Raw,
+];
+
+#[derive(Debug)]
+pub enum ScreenshotError {
+    ConnectError(std::io::Error),
+    HandshakeError(HandshakeError),
+    ReceiveError(ReceiveError),
+    TimedOut,
+}
+
+impl std::fmt::Display for ScreenshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ScreenshotError::ConnectError(err) => write!(f, "cannot connect: {}", err),
+            ScreenshotError::HandshakeError(err) => write!(f, "handshake failed: {}", err),
+            ScreenshotError::ReceiveError(err) => write!(f, "update failed: {}", err),
+            ScreenshotError::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+impl From<HandshakeError> for ScreenshotError {
+    fn from(err: HandshakeError) -> Self {
+        match err {
+            HandshakeError::IoError(err) if timed_out(&err) => ScreenshotError::TimedOut,
+            err => ScreenshotError::HandshakeError(err),
+        }
+    }
+}
+
+impl From<ReceiveError> for ScreenshotError {
+    fn from(err: ReceiveError) -> Self {
+        match err {
+            ReceiveError::IoError(err) if timed_out(&err) => ScreenshotError::TimedOut,
+            err => ScreenshotError::ReceiveError(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for ScreenshotError {
+    fn from(err: std::io::Error) -> Self {
+        ReceiveError::IoError(err).into()
+    }
+}
+
+/**
+ * Whether a read or write gave up because of its timeout, which shows up
+ * as WouldBlock on some platforms.
+ */
+fn timed_out(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+    )
+}
+
+/**
+ * Let the next reads and writes of `session` block until `deadline` at most.
+ */
+fn limit(session: &Session, deadline: Instant) -> Result<(), ScreenshotError> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(ScreenshotError::TimedOut);
+    }
+    Ok(session.set_timeout(Some(remaining))?)
+}
+
+/**
+ * Take a screenshot of the desktop at `address`:`port`, giving up after
+ * `timeout`. The password is used if the server insists on VncAuth.
+ */
+pub fn capture(
+    address: &str,
+    port: u16,
+    password: Option<&str>,
+    timeout: Duration,
+) -> Result<Canvas, ScreenshotError> {
+    let deadline = Instant::now() + timeout;
+    let mut session =
+        Session::connect_timeout(address, port, timeout).map_err(|err| match timed_out(&err) {
+            true => ScreenshotError::TimedOut,
+            false => ScreenshotError::ConnectError(err),
+        })?;
+    if let Some(password) = password {
+        session.set_password(password);
+    }
+    limit(&session, deadline)?;
+    session.handshake()?;
+    session.set_pixel_format(&Session::PREFERRED_PIXEL_FORMAT)?;
+    session.set_encodings(&ENCODINGS)?;
+    let (width, height) = (session.screen_w(), session.screen_h());
+    session.framebuffer_update_request(false, 0, 0, width, height)?;
+
+    let mut canvas = Canvas::new(width, height);
+    let format = Session::PREFERRED_PIXEL_FORMAT;
+    loop {
+        limit(&session, deadline)?;
+        match session.read_message()? {
+            ServerMessage::FramebufferUpdate(rects) => {
+                for rect in &rects {
+                    canvas
+                        .apply(rect, &format)
+                        .map_err(ReceiveError::DecodeError)?;
+                }
+                return Ok(canvas);
+            }
+            ServerMessage::SetColourMapEntries {
+                first_colour,
+                colours,
+            } => canvas.set_colour_map_entries(first_colour, &colours),
+            ServerMessage::Bell | ServerMessage::ServerCutText(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockServer, Script};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_capture() {
+        let server = MockServer::start(
+            Script::new()
+                .version()
+                .security(&[2], 2)
+                .send(&std::array::from_fn::<u8, 16, _>(|i| i as u8))
+                // DES of the challenge with the bit reversed password as key.
+                .expect(&[
+                    0xb8, 0x66, 0x92, 0x41, 0x25, 0xc8, 0xee, 0xbb, 0x9d, 0xeb, 0xc1, 0xdb, 0x61,
+                    0xc5, 0x38, 0xe2,
+                ])
+                .security_result(0)
+                .client_init(false)
+                .server_init(2, 1, &Session::PREFERRED_PIXEL_FORMAT, "")
+                .skip(20)
+                .skip(4 + 4 * ENCODINGS.len())
+                .expect(&[3, 0, 0, 0, 0, 0, 0, 2, 0, 1])
+                .send(&[2])
+                .framebuffer_update(&[(
+                    0,
+                    0,
+                    2,
+                    1,
+                    rfb::Encoding::Raw as i32,
+                    &[0x33, 0x22, 0x11, 0, 0x66, 0x55, 0x44, 0],
+                )]),
+        );
+        let canvas = capture(
+            "127.0.0.1",
+            server.port(),
+            Some("password"),
+            DEFAULT_TIMEOUT,
+        )
+        .unwrap();
+        server.finish();
+        assert_eq!(canvas.pixels(), [0x112233, 0x445566]);
+    }
+
+    #[test]
+    fn test_timeout() {
+        // Connections are accepted by the kernel, but nothing is ever sent.
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let started = Instant::now();
+        let result = capture("127.0.0.1", port, None, Duration::from_millis(200));
+        assert!(matches!(result, Err(ScreenshotError::TimedOut)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
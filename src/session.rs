@@ -1,7 +1,10 @@
-use crate::d3des::{Des, Direction};
-use crate::rfb::{self, RfbVersion};
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use crate::codec::{ClientMessage, DecodeError, ServerMessage};
+use crate::fbs::Recorder;
+use crate::handshake::{Handshake, Step};
+use crate::rfb::{self, PixelFormat, RfbVersion};
+use std::io::{IsTerminal, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct Session {
@@ -11,6 +14,10 @@ pub struct Session {
     screen_w: u16,
     screen_h: u16,
     name: String,
+    recv_buf: Vec<u8>,
+    qemu_key_events: bool,
+    recorder: Option<Recorder>,
+    password: Option<String>,
 }
 
 #[derive(Debug)]
@@ -22,56 +29,35 @@ pub enum HandshakeError {
 }
 
 #[derive(Debug)]
-pub struct PixelFormat {
-    bits_per_pixel: u8,
-    depth: u8,
-    big_endian_flag: u8,
-    true_color_flag: u8,
-    red_max: u16,
-    green_max: u16,
-    blue_max: u16,
-    red_shift: u8,
-    green_shift: u8,
-    blue_shift: u8,
+pub enum ReceiveError {
+    IoError(std::io::Error),
+    DecodeError(DecodeError),
+    NotInitialized,
+    /** The server closed the connection between two messages. */
+    Closed,
 }
 
-impl From<[u8; 16]> for PixelFormat {
-    fn from(src: [u8; 16]) -> Self {
-        PixelFormat {
-            bits_per_pixel: src[0],
-            depth: src[1],
-            big_endian_flag: src[2],
-            true_color_flag: src[3],
-            red_max: u16::from_be_bytes([src[4], src[5]]),
-            green_max: u16::from_be_bytes([src[6], src[7]]),
-            blue_max: u16::from_be_bytes([src[8], src[9]]),
-            red_shift: src[10],
-            green_shift: src[11],
-            blue_shift: src[12],
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HandshakeError::IoError(err) => write!(f, "{}", err),
+            HandshakeError::UnsupportedRfbVersion => write!(f, "unsupported RFB version"),
+            HandshakeError::UnsupportedSecurity(reason) => write!(f, "security: {}", reason),
+            HandshakeError::UnsupportedServerSettings(reason) => {
+                write!(f, "unsupported server settings: {}", reason)
+            }
         }
     }
 }
 
-impl From<&PixelFormat> for [u8; 16] {
-    fn from(src: &PixelFormat) -> Self {
-        [
-            src.bits_per_pixel,
-            src.depth,
-            src.big_endian_flag,
-            src.true_color_flag,
-            (src.red_max >> 8) as u8,
-            src.red_max as u8,
-            (src.green_max >> 8) as u8,
-            src.green_max as u8,
-            (src.blue_max >> 8) as u8,
-            src.blue_max as u8,
-            src.red_shift,
-            src.green_shift,
-            src.blue_shift,
-            0u8,
-            0u8,
-            0u8,
-        ]
+impl std::fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReceiveError::IoError(err) => write!(f, "{}", err),
+            ReceiveError::DecodeError(err) => write!(f, "{}", err),
+            ReceiveError::NotInitialized => write!(f, "no handshake yet"),
+            ReceiveError::Closed => write!(f, "connection closed"),
+        }
     }
 }
 
@@ -81,6 +67,12 @@ impl From<std::io::Error> for HandshakeError {
     }
 }
 
+impl From<std::io::Error> for ReceiveError {
+    fn from(err: std::io::Error) -> Self {
+        ReceiveError::IoError(err)
+    }
+}
+
 impl Session {
     pub const PREFERRED_PIXEL_FORMAT: PixelFormat = PixelFormat {
         bits_per_pixel: 32,
@@ -96,60 +88,147 @@ impl Session {
     };
 
     pub fn new(address: &str, port: u16) -> Result<Self, std::io::Error> {
-        let stream = TcpStream::connect((address, port))?;
-        Ok(Self {
-            stream: stream,
+        Ok(Self::with_stream(TcpStream::connect((address, port))?))
+    }
+
+    /**
+     * Connect, giving up after `timeout`, which then also applies to every
+     * read and write until changed with `set_timeout`.
+     */
+    pub fn connect_timeout(
+        address: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> Result<Self, std::io::Error> {
+        let mut last_err = std::io::Error::from(std::io::ErrorKind::NotFound);
+        for addr in (address, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    let session = Self::with_stream(stream);
+                    session.set_timeout(Some(timeout))?;
+                    return Ok(session);
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    fn with_stream(stream: TcpStream) -> Self {
+        Self {
+            stream,
             rfb_version: rfb::RfbVersion::Unsupported,
             pixel_format: None,
             screen_w: 0,
             screen_h: 0,
             name: String::new(),
-        })
+            recv_buf: Vec::new(),
+            qemu_key_events: false,
+            recorder: None,
+            password: None,
+        }
     }
 
-    pub fn handshake(&mut self) -> Result<(), HandshakeError> {
-        self.rfb_version = Self::handle_protocol_version(&mut self.stream)?;
-        let security = Self::handle_security_handshake(&mut self.stream)?;
+    /**
+     * Limit how long a read or write may block, None to wait forever.
+     */
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)
+    }
 
-        let shared = [0u8];
-        self.stream.write_all(&shared);
+    /**
+     * Answer VncAuth with this password instead of asking for it on the
+     * terminal. Without one the handshake fails if there is no terminal.
+     */
+    pub fn set_password(&mut self, password: &str) {
+        self.password = Some(password.to_string());
+    }
 
-        self.screen_w = Self::read_u16(&mut self.stream)?;
-        self.screen_h = Self::read_u16(&mut self.stream)?;
+    pub fn screen_w(&self) -> u16 {
+        self.screen_w
+    }
 
-        let mut pixel_format = [0u8; 16];
-        self.stream.read_exact(&mut pixel_format)?;
-        self.pixel_format = Some(pixel_format.into());
+    pub fn screen_h(&self) -> u16 {
+        self.screen_h
+    }
 
-        let name_len = Self::read_u32(&mut self.stream)?;
-        if name_len > 1000 {
-            return Err(HandshakeError::UnsupportedServerSettings(
-                "Too long name".to_string(),
-            ));
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /**
+     * Record everything received from now on.
+     */
+    pub fn record(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub fn pixel_format(&self) -> Option<&PixelFormat> {
+        self.pixel_format.as_ref()
+    }
+
+    /**
+     * Whether the server acknowledged the QEMU Extended Key Event
+     * pseudo-encoding, so scancodes can be sent.
+     */
+    pub fn qemu_key_events(&self) -> bool {
+        self.qemu_key_events
+    }
+
+    /**
+     * Clone the underlying socket, so client messages can be written while
+     * another thread is blocked in `read_message`.
+     */
+    pub fn try_clone_stream(&self) -> Result<TcpStream, std::io::Error> {
+        self.stream.try_clone()
+    }
+
+    pub fn handshake(&mut self) -> Result<(), HandshakeError> {
+        let mut handshake = Handshake::new();
+        let mut out = Vec::new();
+        let mut step = handshake.step(&[], &mut out)?;
+        loop {
+            self.stream.write_all(&out)?;
+            out.clear();
+            step = match step {
+                Step::Read(len) => {
+                    let mut input = vec![0u8; len];
+                    self.stream.read_exact(&mut input)?;
+                    handshake.step(&input, &mut out)?
+                }
+                Step::Password => {
+                    let password = match &self.password {
+                        Some(password) => password.clone(),
+                        None => Self::prompt_password()?,
+                    };
+                    handshake.password(&password, &mut out)
+                }
+                Step::Done(init) => {
+                    self.rfb_version = RfbVersion::Rfb38;
+                    self.screen_w = init.width;
+                    self.screen_h = init.height;
+                    self.pixel_format = Some(init.pixel_format);
+                    self.name = init.name;
+                    return Ok(());
+                }
+            };
         }
-        let mut name = Self::read_dynamic(&mut self.stream, name_len as usize)?;
-        self.name = String::from_utf8(name).unwrap();
-        Ok(())
     }
 
     pub fn set_pixel_format(&mut self, format: &PixelFormat) -> Result<(), std::io::Error> {
-        self.stream.write_all(&[0u8, 0, 0, 0])?;
-        let encoded: [u8; 16] = format.into();
-        self.stream.write_all(&encoded[..])
+        self.send(&ClientMessage::SetPixelFormat(format.clone()))?;
+        self.pixel_format = Some(format.clone());
+        Ok(())
     }
 
     /**
      * Set encodings in order of preference. First element is most preferred.
      */
     pub fn set_encodings(&mut self, encodings: &[rfb::Encoding]) -> Result<(), std::io::Error> {
-        let encoding_data: Vec<u8> = encodings
-            .iter()
-            .map(|&x| (x as i32).to_be_bytes())
-            .flatten()
-            .collect();
-        let len_data = (encodings.len() as u16).to_be_bytes();
-        self.stream.write_all(&[2u8, 0, len_data[0], len_data[1]])?;
-        self.stream.write_all(&encoding_data)
+        self.send(&ClientMessage::SetEncodings(
+            encodings.iter().map(|&x| x as i32).collect(),
+        ))
     }
 
     pub fn framebuffer_update_request(
@@ -160,131 +239,277 @@ impl Session {
         width: u16,
         height: u16,
     ) -> Result<(), std::io::Error> {
-        self.stream.write_all(&[
-            3u8,
-            incremental as u8,
-            (xpos >> 8) as u8,
-            xpos as u8,
-            (ypos >> 8) as u8,
-            ypos as u8,
-            (width >> 8) as u8,
-            width as u8,
-            (height >> 8) as u8,
-            height as u8,
-        ])
-    }
-
-    fn read_u8(stream: &mut TcpStream) -> Result<u8, std::io::Error> {
-        let mut buf = [0u8; 1];
-        stream.read_exact(&mut buf)?;
-        Ok(buf[0])
-    }
-
-    fn read_u16(stream: &mut TcpStream) -> Result<u16, std::io::Error> {
-        let mut buf = [0u8; 2];
-        stream.read_exact(&mut buf)?;
-        Ok(u16::from_be_bytes(buf))
-    }
-
-    fn read_u32(stream: &mut TcpStream) -> Result<u32, std::io::Error> {
-        let mut buf = [0u8; 4];
-        stream.read_exact(&mut buf)?;
-        Ok(u32::from_be_bytes(buf))
-    }
-
-    fn read_dynamic(stream: &mut TcpStream, len: usize) -> Result<Vec<u8>, std::io::Error> {
-        let mut buf = Vec::with_capacity(len);
-        buf.resize(len, 0);
-        stream.read_exact(buf.as_mut_slice())?;
-        Ok(buf)
-    }
-
-    fn handle_protocol_version(stream: &mut TcpStream) -> Result<rfb::RfbVersion, HandshakeError> {
-        let mut protocol_version = [0u8; 12];
-        stream.read_exact(&mut protocol_version)?;
-        let rfb_version = rfb::parse_offered_version(&protocol_version);
-        if rfb_version != RfbVersion::Rfb38 {
-            return Err(HandshakeError::UnsupportedRfbVersion);
+        self.send(&ClientMessage::FramebufferUpdateRequest {
+            incremental,
+            x: xpos,
+            y: ypos,
+            width,
+            height,
+        })
+    }
+
+    pub fn send(&mut self, msg: &ClientMessage) -> Result<(), std::io::Error> {
+        let mut encoded = Vec::new();
+        msg.encode(&mut encoded);
+        self.stream.write_all(&encoded)
+    }
+
+    /**
+     * Block until a complete server message has been received.
+     */
+    pub fn read_message(&mut self) -> Result<ServerMessage, ReceiveError> {
+        let format = self
+            .pixel_format
+            .clone()
+            .ok_or(ReceiveError::NotInitialized)?;
+        loop {
+            let needed = match ServerMessage::decode(&self.recv_buf, &format) {
+                Ok((msg, len)) => {
+                    self.recv_buf.drain(..len);
+                    if let ServerMessage::FramebufferUpdate(rects) = &msg {
+                        self.qemu_key_events |= rects.iter().any(|rect| {
+                            rect.encoding == rfb::Encoding::QemuExtendedKeyEventPseudo as i32
+                        });
+                    }
+                    return Ok(msg);
+                }
+                Err(DecodeError::Incomplete(needed)) => needed,
+                Err(err) => return Err(ReceiveError::DecodeError(err)),
+            };
+            // Decoding again only pays off once the missing bytes are in.
+            while self.recv_buf.len() < needed {
+                let mut chunk = [0u8; 4096];
+                let len = self.stream.read(&mut chunk)?;
+                if len == 0 && self.recv_buf.is_empty() {
+                    return Err(ReceiveError::Closed);
+                }
+                if len == 0 {
+                    return Err(ReceiveError::IoError(
+                        std::io::ErrorKind::UnexpectedEof.into(),
+                    ));
+                }
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.write(&chunk[..len])?;
+                }
+                self.recv_buf.extend_from_slice(&chunk[..len]);
+            }
         }
-        stream.write_all(b"RFB 003.008\n")?;
-        Ok(rfb_version)
     }
 
-    fn handle_security_handshake(
-        stream: &mut TcpStream,
-    ) -> Result<rfb::SecurityType, HandshakeError> {
-        let security_type_count = Self::read_u8(stream)?;
-        if security_type_count == 0 {
-            let reason_str_len = Self::read_u32(stream)? as usize;
-            let mut reason_str = Self::read_dynamic(stream, std::cmp::min(1000, reason_str_len))?;
+    fn prompt_password() -> Result<String, HandshakeError> {
+        if !std::io::stdin().is_terminal() {
             return Err(HandshakeError::UnsupportedSecurity(
-                String::from_utf8(reason_str).unwrap_or(String::new()),
+                "Password required, but standard input is not a terminal".to_string(),
             ));
         }
+        eprintln!("Password:");
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        Ok(input.trim_end().to_string())
+    }
+}
 
-        let mut server_security_types = [0u8; 255];
-        let slice = &mut server_security_types[0..security_type_count as usize];
-        stream.read_exact(slice)?;
-
-        let preferred_security_type =
-            slice
-                .iter()
-                .fold(rfb::SecurityType::Invalid, |acc, &x| match (acc, x) {
-                    (rfb::SecurityType::None, _) => acc,
-                    (_, s) if s == rfb::SecurityType::None as u8 => rfb::SecurityType::None,
-                    (_, s) if s == rfb::SecurityType::VncAuth as u8 => rfb::SecurityType::VncAuth,
-                    _ => acc,
-                });
-
-        match preferred_security_type {
-            rfb::SecurityType::None => Self::handle_none_auth(stream),
-            rfb::SecurityType::VncAuth => Self::handle_vnc_auth(stream),
-            _ => Err(HandshakeError::UnsupportedSecurity(
-                "No suitable security".to_string(),
-            )),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockServer, Script};
+
+    fn connect(server: &MockServer) -> Session {
+        Session::new("127.0.0.1", server.port()).unwrap()
     }
 
-    fn handle_none_auth(stream: &mut TcpStream) -> Result<rfb::SecurityType, HandshakeError> {
-        stream.write_all(&[rfb::SecurityType::None as u8])?;
-        let security_handshake_result = Self::read_u32(stream)?;
-        match security_handshake_result {
-            0 => Ok(rfb::SecurityType::None),
-            _ => Err(HandshakeError::UnsupportedSecurity(format!(
-                "Security type not accepted by server ({})",
-                security_handshake_result
-            ))),
-        }
+    #[test]
+    fn test_handshake_none_auth() {
+        let server = MockServer::start(Script::new().handshake(
+            800,
+            600,
+            &Session::PREFERRED_PIXEL_FORMAT,
+            "mock desktop",
+        ));
+        let mut session = connect(&server);
+        session.handshake().unwrap();
+        server.finish();
+
+        assert_eq!(session.rfb_version, RfbVersion::Rfb38);
+        assert_eq!(session.screen_w, 800);
+        assert_eq!(session.screen_h, 600);
+        assert_eq!(session.name, "mock desktop");
+        let format = session.pixel_format.as_ref().unwrap();
+        assert_eq!(
+            <[u8; 16]>::from(format),
+            <[u8; 16]>::from(&Session::PREFERRED_PIXEL_FORMAT)
+        );
     }
 
-    fn handle_vnc_auth(stream: &mut TcpStream) -> Result<rfb::SecurityType, HandshakeError> {
-        stream.write_all(&[rfb::SecurityType::VncAuth as u8])?;
-        let mut challenge = [0u8; 16];
-        stream.read_exact(&mut challenge)?;
+    #[test]
+    fn test_handshake_prefers_none_auth() {
+        let server = MockServer::start(
+            Script::new()
+                .version()
+                .security(&[2, 1], 1)
+                .security_result(0)
+                .client_init(false)
+                .server_init(1, 1, &Session::PREFERRED_PIXEL_FORMAT, ""),
+        );
+        let mut session = connect(&server);
+        session.handshake().unwrap();
+        server.finish();
+    }
 
-        println!("Password:");
-        let mut input = String::new();
-        let _ = std::io::stdin().read_line(&mut input)?;
-        let trimmed = input.trim_end();
+    #[test]
+    fn test_handshake_unsupported_version() {
+        let server = MockServer::start(Script::new().send(b"RFB 003.003\n"));
+        let mut session = connect(&server);
+        let result = session.handshake();
+        server.finish();
+        assert!(matches!(result, Err(HandshakeError::UnsupportedRfbVersion)));
+    }
 
-        let mut passwd_buf = [0u8; 8];
-        for i in 0..std::cmp::min(trimmed.len(), passwd_buf.len()) {
-            passwd_buf[i] = trimmed.as_bytes()[i];
+    #[test]
+    fn test_handshake_security_failure() {
+        let server = MockServer::start(
+            Script::new()
+                .version()
+                .security_failure("too many connections"),
+        );
+        let mut session = connect(&server);
+        let result = session.handshake();
+        server.finish();
+        match result {
+            Err(err @ HandshakeError::UnsupportedSecurity(_)) => {
+                assert_eq!(err.to_string(), "security: too many connections")
+            }
+            other => panic!("unexpected result {:?}", other),
         }
+    }
+
+    #[test]
+    fn test_handshake_no_suitable_security() {
+        let server = MockServer::start(Script::new().version().send(&[1, 16]));
+        let mut session = connect(&server);
+        let result = session.handshake();
+        server.finish();
+        assert!(matches!(
+            result,
+            Err(HandshakeError::UnsupportedSecurity(_))
+        ));
+    }
+
+    #[test]
+    fn test_handshake_security_rejected() {
+        let server =
+            MockServer::start(Script::new().version().security(&[1], 1).security_result(1));
+        let mut session = connect(&server);
+        let result = session.handshake();
+        server.finish();
+        assert!(matches!(
+            result,
+            Err(HandshakeError::UnsupportedSecurity(_))
+        ));
+    }
+
+    #[test]
+    fn test_handshake_vnc_auth() {
+        let challenge: Vec<u8> = (0..16).collect();
+        let server = MockServer::start(
+            Script::new()
+                .version()
+                .security(&[2], 2)
+                .send(&challenge)
+                // DES of the challenge with the bit reversed password as key.
+                .expect(&[
+                    0xb8, 0x66, 0x92, 0x41, 0x25, 0xc8, 0xee, 0xbb, 0x9d, 0xeb, 0xc1, 0xdb, 0x61,
+                    0xc5, 0x38, 0xe2,
+                ])
+                .security_result(0)
+                .client_init(false)
+                .server_init(1, 1, &Session::PREFERRED_PIXEL_FORMAT, ""),
+        );
+        let mut session = connect(&server);
+        session.set_password("password");
+        session.handshake().unwrap();
+        server.finish();
+    }
 
-        let des = Des::new(&passwd_buf, Direction::Encrypt);
-        let block_a = des.encrypt_block(&challenge[0..8]);
-        let block_b = des.encrypt_block(&challenge[8..16]);
-        stream.write_all(&block_a);
-        stream.write_all(&block_b);
-
-        let security_handshake_result = Self::read_u32(stream)?;
-        match security_handshake_result {
-            0 => Ok(rfb::SecurityType::VncAuth),
-            _ => Err(HandshakeError::UnsupportedSecurity(format!(
-                "Security type not accepted by server ({})",
-                security_handshake_result
-            ))),
+    #[test]
+    fn test_client_messages() {
+        let server = MockServer::start(
+            Script::new()
+                .handshake(640, 480, &Session::PREFERRED_PIXEL_FORMAT, "")
+                .expect(&[0, 0, 0, 0])
+                .expect(&[32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0])
+                .expect(&[2, 0, 0, 3])
+                .expect(&[0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0xff, 0xff, 0x21])
+                .expect(&[3, 1, 0, 10, 0, 20, 2, 128, 1, 224]),
+        );
+        let mut session = connect(&server);
+        session.handshake().unwrap();
+        session
+            .set_pixel_format(&Session::PREFERRED_PIXEL_FORMAT)
+            .unwrap();
+        session
+            .set_encodings(&[
+                rfb::Encoding::Raw,
+                rfb::Encoding::CopyRect,
+                rfb::Encoding::DesktopSizePseudo,
+            ])
+            .unwrap();
+        session
+            .framebuffer_update_request(true, 10, 20, 640, 480)
+            .unwrap();
+        server.finish();
+    }
+
+    #[test]
+    fn test_read_messages() {
+        let pixels = [1u8, 2, 3, 0, 4, 5, 6, 0];
+        let server = MockServer::start(
+            Script::new()
+                .handshake(2, 1, &Session::PREFERRED_PIXEL_FORMAT, "")
+                .skip(10)
+                .framebuffer_update(&[
+                    (0, 0, 2, 1, rfb::Encoding::Raw as i32, &pixels),
+                    (0, 0, 1, 1, rfb::Encoding::CopyRect as i32, &[0, 1, 0, 0]),
+                ])
+                .send(&[2]),
+        );
+        let mut session = connect(&server);
+        session.handshake().unwrap();
+        session
+            .framebuffer_update_request(false, 0, 0, 2, 1)
+            .unwrap();
+        match session.read_message().unwrap() {
+            ServerMessage::FramebufferUpdate(rects) => {
+                assert_eq!(rects.len(), 2);
+                assert_eq!(rects[0].data, pixels);
+                assert_eq!(rects[1].encoding, rfb::Encoding::CopyRect as i32);
+            }
+            other => panic!("unexpected message {:?}", other),
         }
+        assert_eq!(session.read_message().unwrap(), ServerMessage::Bell);
+        server.finish();
+        assert!(matches!(session.read_message(), Err(ReceiveError::Closed)));
+    }
+
+    #[test]
+    fn test_qemu_key_events() {
+        let server = MockServer::start(
+            Script::new()
+                .handshake(640, 480, &Session::PREFERRED_PIXEL_FORMAT, "")
+                .framebuffer_update(&[(
+                    0,
+                    0,
+                    640,
+                    480,
+                    rfb::Encoding::QemuExtendedKeyEventPseudo as i32,
+                    &[],
+                )]),
+        );
+        let mut session = connect(&server);
+        session.handshake().unwrap();
+        assert!(!session.qemu_key_events());
+        session.read_message().unwrap();
+        assert!(session.qemu_key_events());
+        server.finish();
     }
 }
@@ -0,0 +1,431 @@
+/*
+ * Display in a terminal emulator.
+ *
+ * Without pixel graphics every character cell shows two pixels as an upper
+ * half block, the top one in the foreground and the bottom one in the
+ * background colour, both as 24-bit colour. The colours of every cell are
+ * remembered, so an update only writes the cells that really changed.
+ *
+ * Terminals that announce sixel graphics in their primary device
+ * attributes get the damaged areas as sixel images instead, widened to
+ * whole cells and quantised to a 6x6x6 colour cube. The last row is left
+ * empty so that an image never makes the terminal scroll.
+ *
+ * The alternate screen is used from the first update until the display is
+ * dropped.
+ */
+
+use crate::canvas::{Canvas, Rect};
+use crate::display::Display;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+const ENTER: &[u8] = b"\x1b[?1049h\x1b[?25l\x1b[?7l\x1b[2J";
+const LEAVE: &[u8] = b"\x1b[0m\x1b[?7h\x1b[?25h\x1b[?1049l";
+const UPPER_HALF_BLOCK: &str = "\u{2580}";
+const QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TerminalMode {
+    Blocks,
+    Sixel,
+}
+
+/**
+ * Puts a terminal into raw mode until dropped: no echo, no line editing,
+ * and no signals from Ctrl+C.
+ */
+pub struct RawMode {
+    fd: RawFd,
+    saved: libc::termios,
+}
+
+pub struct TerminalDisplay {
+    out: Box<dyn Write + Send>,
+    mode: TerminalMode,
+    columns: u16,
+    rows: u16,
+    cell_width: u16,
+    cell_height: u16,
+    /** Colours of the upper and lower half of every cell, as drawn. */
+    cells: Vec<Option<(u32, u32)>>,
+    started: bool,
+}
+
+impl RawMode {
+    pub fn enable(fd: RawFd) -> Result<Self, std::io::Error> {
+        let mut saved = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut raw = saved;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self { fd, saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.saved) };
+    }
+}
+
+/**
+ * Size of the terminal as (columns, rows, width, height), the pixel size
+ * being 0 if the terminal does not tell.
+ */
+pub fn window_size(fd: RawFd) -> Result<(u16, u16, u16, u16), std::io::Error> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((size.ws_col, size.ws_row, size.ws_xpixel, size.ws_ypixel))
+}
+
+/**
+ * Whether primary device attributes, as in "\x1b[?62;4;22c", include
+ * sixel graphics (4).
+ */
+pub fn has_sixel(attributes: &[u8]) -> bool {
+    let Some(start) = attributes.windows(3).position(|w| w == b"\x1b[?") else {
+        return false;
+    };
+    let params = &attributes[start + 3..];
+    let end = params
+        .iter()
+        .position(|&b| b == b'c')
+        .unwrap_or(params.len());
+    params[..end]
+        .split(|&b| b == b';')
+        .any(|param| param == b"4")
+}
+
+/**
+ * Read what is available from `fd`, waiting at most `timeout` for it, or
+ * as long as it takes if None. Empty if nothing arrived in time.
+ */
+pub fn read_input(fd: RawFd, timeout: Option<Duration>) -> Result<Vec<u8>, std::io::Error> {
+    let mut poll = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = timeout.map_or(-1, |timeout| timeout.as_millis() as libc::c_int);
+    match unsafe { libc::poll(&mut poll, 1, timeout) } {
+        0 => return Ok(Vec::new()),
+        n if n < 0 => return Err(std::io::Error::last_os_error()),
+        _ => {}
+    }
+    let mut buf = [0u8; 256];
+    let len = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+    match len {
+        0 => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        n if n < 0 => Err(std::io::Error::last_os_error()),
+        n => Ok(buf[..n as usize].to_vec()),
+    }
+}
+
+/**
+ * Ask the terminal on stdin and stdout for its device attributes.
+ */
+fn query_sixel() -> Result<bool, std::io::Error> {
+    let stdin = std::io::stdin();
+    let _raw = RawMode::enable(stdin.as_raw_fd())?;
+    let mut stdout = std::io::stdout();
+    stdout.write_all(b"\x1b[c")?;
+    stdout.flush()?;
+    let mut answer = Vec::new();
+    while !answer.ends_with(b"c") && answer.len() < 64 {
+        let data = read_input(stdin.as_raw_fd(), Some(QUERY_TIMEOUT))?;
+        if data.is_empty() {
+            break;
+        }
+        answer.extend_from_slice(&data);
+    }
+    Ok(has_sixel(&answer))
+}
+
+/**
+ * Index of the nearest colour of the 6x6x6 cube.
+ */
+fn cube_index(rgb: u32) -> usize {
+    let level = |shift: u32| (((rgb >> shift) & 0xff) * 5 + 127) as usize / 255;
+    level(16) * 36 + level(8) * 6 + level(0)
+}
+
+/**
+ * Encode an area of the canvas as sixel image.
+ */
+pub fn encode_sixel(canvas: &Canvas, area: Rect, out: &mut Vec<u8>) {
+    let area = area.clip(canvas.width(), canvas.height());
+    write!(out, "\x1bPq\"1;1;{};{}", area.width, area.height).unwrap();
+    for index in 0..216 {
+        let (r, g, b) = (index / 36, index / 6 % 6, index % 6);
+        write!(out, "#{};2;{};{};{}", index, r * 20, g * 20, b * 20).unwrap();
+    }
+    for top in (area.y..area.bottom() as u16).step_by(6) {
+        let bottom = (top as u32 + 6).min(area.bottom()) as u16;
+        let columns: Vec<Vec<usize>> = (area.x..area.right() as u16)
+            .map(|x| {
+                (top..bottom)
+                    .map(|y| cube_index(canvas.pixel(x, y)))
+                    .collect()
+            })
+            .collect();
+        let mut used = [false; 216];
+        for &index in columns.iter().flatten() {
+            used[index] = true;
+        }
+        for colour in (0..216).filter(|&colour| used[colour]) {
+            write!(out, "#{}", colour).unwrap();
+            let mut runs: Vec<(u8, usize)> = Vec::new();
+            for column in &columns {
+                let bits = column
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &index)| index == colour)
+                    .fold(0, |bits, (row, _)| bits | 1 << row);
+                let sixel = b'?' + bits;
+                match runs.last_mut() {
+                    Some((last, count)) if *last == sixel => *count += 1,
+                    _ => runs.push((sixel, 1)),
+                }
+            }
+            if runs.last().map(|&(sixel, _)| sixel) == Some(b'?') {
+                runs.pop();
+            }
+            for (sixel, count) in runs {
+                if count > 3 {
+                    write!(out, "!{}", count).unwrap();
+                    out.push(sixel);
+                } else {
+                    out.extend(std::iter::repeat_n(sixel, count));
+                }
+            }
+            out.push(b'$');
+        }
+        out.push(b'-');
+    }
+    out.extend_from_slice(b"\x1b\\");
+}
+
+impl TerminalDisplay {
+    pub fn new(
+        out: Box<dyn Write + Send>,
+        mode: TerminalMode,
+        columns: u16,
+        rows: u16,
+        cell_width: u16,
+        cell_height: u16,
+    ) -> Self {
+        Self {
+            out,
+            mode,
+            columns,
+            rows,
+            cell_width: cell_width.max(1),
+            cell_height: cell_height.max(1),
+            cells: vec![None; columns as usize * rows as usize],
+            started: false,
+        }
+    }
+
+    /**
+     * Display on stdout, using sixel graphics if `mode` says so or, if not
+     * given, the terminal supports them.
+     */
+    pub fn open(mode: Option<TerminalMode>) -> Result<Self, std::io::Error> {
+        let stdout = std::io::stdout();
+        let (columns, rows, width, height) = window_size(stdout.as_raw_fd())?;
+        let pixels = width > 0 && height > 0 && columns > 0 && rows > 0;
+        let mode = match mode {
+            Some(mode) => mode,
+            None if pixels && query_sixel()? => TerminalMode::Sixel,
+            None => TerminalMode::Blocks,
+        };
+        Ok(match mode {
+            TerminalMode::Sixel if pixels => Self::new(
+                Box::new(stdout),
+                mode,
+                columns,
+                rows,
+                width / columns,
+                height / rows,
+            ),
+            _ => Self::new(Box::new(stdout), TerminalMode::Blocks, columns, rows, 1, 2),
+        })
+    }
+
+    /**
+     * Local pixels per character cell, for mapping mouse reports.
+     */
+    pub fn cell_size(&self) -> (u16, u16) {
+        (self.cell_width, self.cell_height)
+    }
+
+    /**
+     * Write the cells of `area` that changed.
+     */
+    fn draw_blocks(&mut self, canvas: &Canvas, area: Rect, out: &mut Vec<u8>) {
+        let area = area.clip(canvas.width(), canvas.height());
+        if area.is_empty() {
+            return;
+        }
+        let mut colours = None;
+        let rows = area.y / 2..area.bottom().div_ceil(2) as u16;
+        for row in rows.filter(|&row| row < self.rows) {
+            let mut cursor = None;
+            for column in area.x..(area.right() as u16).min(self.columns) {
+                let pixel = |y: u16| match y < canvas.height() {
+                    true => canvas.pixel(column, y),
+                    false => 0,
+                };
+                let cell = (pixel(row * 2), pixel(row * 2 + 1));
+                let index = row as usize * self.columns as usize + column as usize;
+                if self.cells[index] == Some(cell) {
+                    continue;
+                }
+                self.cells[index] = Some(cell);
+                if cursor != Some(column) {
+                    write!(out, "\x1b[{};{}H", row + 1, column + 1).unwrap();
+                }
+                if colours != Some(cell) {
+                    let (top, bottom) = cell;
+                    write!(
+                        out,
+                        "\x1b[38;2;{};{};{};48;2;{};{};{}m",
+                        top >> 16,
+                        (top >> 8) & 0xff,
+                        top & 0xff,
+                        bottom >> 16,
+                        (bottom >> 8) & 0xff,
+                        bottom & 0xff
+                    )
+                    .unwrap();
+                    colours = Some(cell);
+                }
+                out.extend_from_slice(UPPER_HALF_BLOCK.as_bytes());
+                cursor = Some(column + 1);
+            }
+        }
+    }
+
+    /**
+     * Write `area`, widened to whole cells, as sixel image.
+     */
+    fn draw_sixel(&self, canvas: &Canvas, area: Rect, out: &mut Vec<u8>) {
+        let (width, height) = (self.cell_width as u32, self.cell_height as u32);
+        let left = area.x as u32 / width;
+        let top = area.y as u32 / height;
+        let right = area.right().div_ceil(width);
+        let bottom = area.bottom().div_ceil(height);
+        let cells = Rect::new(
+            (left * width) as u16,
+            (top * height) as u16,
+            ((right - left) * width).min(u16::MAX as u32) as u16,
+            ((bottom - top) * height).min(u16::MAX as u32) as u16,
+        )
+        .clip(canvas.width(), canvas.height());
+        if cells.is_empty() {
+            return;
+        }
+        write!(out, "\x1b[{};{}H", top + 1, left + 1).unwrap();
+        encode_sixel(canvas, cells, out);
+    }
+}
+
+impl Display for TerminalDisplay {
+    fn size(&self) -> Option<(u16, u16)> {
+        Some(match self.mode {
+            TerminalMode::Blocks => (self.columns, self.rows * 2),
+            TerminalMode::Sixel => (
+                self.columns * self.cell_width,
+                self.rows.saturating_sub(1) * self.cell_height,
+            ),
+        })
+    }
+
+    fn update(&mut self, canvas: &Canvas, damage: &[Rect]) -> Result<(), std::io::Error> {
+        let mut out = Vec::new();
+        if !self.started {
+            out.extend_from_slice(ENTER);
+            self.started = true;
+        }
+        for &area in damage {
+            match self.mode {
+                TerminalMode::Blocks => self.draw_blocks(canvas, area, &mut out),
+                TerminalMode::Sixel => self.draw_sixel(canvas, area, &mut out),
+            }
+        }
+        self.out.write_all(&out)?;
+        self.out.flush()
+    }
+}
+
+impl Drop for TerminalDisplay {
+    fn drop(&mut self) {
+        if self.started {
+            let _ = self.out.write_all(LEAVE);
+            let _ = self.out.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::SharedBuffer;
+
+    #[test]
+    fn test_blocks() {
+        let output = SharedBuffer::default();
+        let mut display =
+            TerminalDisplay::new(Box::new(output.clone()), TerminalMode::Blocks, 2, 1, 1, 2);
+        assert_eq!(display.size(), Some((2, 2)));
+        let mut canvas = Canvas::new(2, 2);
+        canvas.fill_rect(Rect::new(0, 0, 2, 1), 0xff0000);
+        let all = [Rect::new(0, 0, 2, 2)];
+        display.update(&canvas, &all).unwrap();
+        let red_on_black = "\x1b[38;2;255;0;0;48;2;0;0;0m";
+        assert_eq!(
+            output.take_string(),
+            format!(
+                "{}\x1b[1;1H{}\u{2580}\u{2580}",
+                std::str::from_utf8(ENTER).unwrap(),
+                red_on_black
+            )
+        );
+
+        canvas.fill_rect(Rect::new(1, 1, 1, 1), 0x0000ff);
+        display.update(&canvas, &all).unwrap();
+        assert_eq!(
+            output.take_string(),
+            "\x1b[1;2H\x1b[38;2;255;0;0;48;2;0;0;255m\u{2580}"
+        );
+        display.update(&canvas, &all).unwrap();
+        assert_eq!(output.take_string(), "");
+
+        drop(display);
+        assert_eq!(output.take_string().as_bytes(), LEAVE);
+    }
+
+    #[test]
+    fn test_sixel() {
+        assert!(has_sixel(b"\x1b[?62;4;22c"));
+        assert!(!has_sixel(b"\x1b[?62;22c"));
+        assert!(!has_sixel(b""));
+
+        let mut canvas = Canvas::new(5, 2);
+        canvas.fill_rect(Rect::new(0, 0, 5, 2), 0xffffff);
+        canvas.fill_rect(Rect::new(0, 1, 1, 1), 0x000000);
+        let mut out = Vec::new();
+        encode_sixel(&canvas, Rect::new(0, 0, 5, 2), &mut out);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\x1bPq\"1;1;5;2#0;2;0;0;0#1;2;0;0;20"));
+        assert!(out.ends_with("#0A$#215@!4B$-\x1b\\"));
+    }
+}
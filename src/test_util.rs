@@ -0,0 +1,92 @@
+/*
+ * Test doubles shared by the tests of several modules.
+ *
+ * Both are cheap to clone and keep their state behind a shared lock, so a
+ * test can hand one clone to the code under test, usually boxed, and
+ * inspect what arrived through another.
+ */
+
+use crate::canvas::{Canvas, Rect};
+use crate::display::Display;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/**
+ * A writer that collects everything written to it.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+/**
+ * What a `MockDisplay` was asked to show.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DisplayLog {
+    /** Pixels of the canvas and the damage, for every update. */
+    pub updates: Vec<(Vec<u32>, Vec<Rect>)>,
+    /** Number of whole canvas redraws, which are not updates. */
+    pub redraws: usize,
+}
+
+/**
+ * A display of a fixed size that logs updates instead of showing them.
+ */
+#[derive(Clone, Debug)]
+pub struct MockDisplay {
+    size: (u16, u16),
+    log: Arc<Mutex<DisplayLog>>,
+}
+
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /**
+     * Everything written since the last call, as text.
+     */
+    pub fn take_string(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MockDisplay {
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            size: (width, height),
+            log: Arc::new(Mutex::new(DisplayLog::default())),
+        }
+    }
+
+    pub fn log(&self) -> DisplayLog {
+        self.log.lock().unwrap().clone()
+    }
+}
+
+impl Display for MockDisplay {
+    fn size(&self) -> Option<(u16, u16)> {
+        Some(self.size)
+    }
+
+    fn update(&mut self, canvas: &Canvas, damage: &[Rect]) -> Result<(), std::io::Error> {
+        let update = (canvas.pixels().to_vec(), damage.to_vec());
+        self.log.lock().unwrap().updates.push(update);
+        Ok(())
+    }
+
+    fn redraw(&mut self, _canvas: &Canvas) -> Result<(), std::io::Error> {
+        self.log.lock().unwrap().redraws += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,448 @@
+/*
+ * Keyboard and mouse input from the terminal the viewer runs in.
+ *
+ * The terminal is put into raw mode and asked for xterm mouse reports in
+ * SGR format, which has no limit on the coordinates and tells which button
+ * was released. Keys arrive as characters or escape sequences, without
+ * releases, so every key is sent as press and release, surrounded by the
+ * modifiers the sequence reports. A lone escape is only told apart from the
+ * start of a sequence by nothing following it for a moment.
+ *
+ * Ctrl+C and friends go to the remote desktop; Ctrl+] ends the session.
+ */
+
+use crate::keymap::Modifiers;
+use crate::keysym;
+use crate::rfb::ButtonMask;
+use crate::runtime::InputSender;
+use crate::terminal::{self, RawMode};
+use crate::view::ViewTransform;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/** Ctrl+], the key that ends the session. */
+pub const QUIT: u8 = 0x1d;
+const ESCAPE_TIMEOUT: Duration = Duration::from_millis(50);
+/** Longest escape sequence waited for, anything longer is dropped. */
+const MAX_SEQUENCE: usize = 32;
+const MOUSE_ON: &[u8] = b"\x1b[?1003h\x1b[?1006h";
+const MOUSE_OFF: &[u8] = b"\x1b[?1006l\x1b[?1003l";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TtyEvent {
+    Key(u32, Modifiers),
+    /** The pointer moved or a button changed, over a cell counting from 0. */
+    Mouse(ButtonMask, u16, u16),
+    /** A wheel click over a cell. */
+    Wheel(ButtonMask, u16, u16),
+    Quit,
+}
+
+/**
+ * Turns the bytes read from the terminal into events.
+ */
+#[derive(Debug, Default)]
+pub struct Parser {
+    buf: Vec<u8>,
+    buttons: ButtonMask,
+}
+
+/**
+ * Keeps the terminal in raw mode with mouse reporting while alive.
+ */
+pub struct TtyInput {
+    _raw: RawMode,
+}
+
+/**
+ * Modifiers of the parameter in "\x1b[1;5A", 1 + a bit mask.
+ */
+fn modifiers(param: u32) -> Modifiers {
+    let bits = param.saturating_sub(1);
+    Modifiers {
+        shift: bits & 1 != 0,
+        alt: bits & 2 != 0,
+        ctrl: bits & 4 != 0,
+        meta: bits & 8 != 0,
+        ..Default::default()
+    }
+}
+
+fn control(byte: u8) -> TtyEvent {
+    let ctrl = Modifiers {
+        ctrl: true,
+        ..Default::default()
+    };
+    match byte {
+        QUIT => TtyEvent::Quit,
+        b'\r' | b'\n' => TtyEvent::Key(keysym::RETURN, Modifiers::default()),
+        b'\t' => TtyEvent::Key(keysym::TAB, Modifiers::default()),
+        0x08 | 0x7f => TtyEvent::Key(keysym::BACKSPACE, Modifiers::default()),
+        0x1b => TtyEvent::Key(keysym::ESCAPE, Modifiers::default()),
+        0 => TtyEvent::Key(' ' as u32, ctrl),
+        0x01..=0x1a => TtyEvent::Key((byte + b'a' - 1) as u32, ctrl),
+        _ => TtyEvent::Key((byte + 0x40) as u32, ctrl),
+    }
+}
+
+/**
+ * Key of the final byte of a CSI or SS3 sequence, or of the number before
+ * "~" in sequences like "\x1b[5~".
+ */
+fn special_key(last: u8, number: u32) -> Option<u32> {
+    Some(match (last, number) {
+        (b'A', _) => keysym::UP,
+        (b'B', _) => keysym::DOWN,
+        (b'C', _) => keysym::RIGHT,
+        (b'D', _) => keysym::LEFT,
+        (b'H', _) | (b'~', 1 | 7) => keysym::HOME,
+        (b'F', _) | (b'~', 4 | 8) => keysym::END,
+        (b'P', _) => keysym::F1,
+        (b'Q', _) => keysym::F2,
+        (b'R', _) => keysym::F3,
+        (b'S', _) => keysym::F4,
+        (b'~', 2) => keysym::INSERT,
+        (b'~', 3) => keysym::DELETE,
+        (b'~', 5) => keysym::PAGE_UP,
+        (b'~', 6) => keysym::PAGE_DOWN,
+        (b'~', 11) => keysym::F1,
+        (b'~', 12) => keysym::F2,
+        (b'~', 13) => keysym::F3,
+        (b'~', 14) => keysym::F4,
+        (b'~', 15) => keysym::F5,
+        (b'~', 17) => keysym::F6,
+        (b'~', 18) => keysym::F7,
+        (b'~', 19) => keysym::F8,
+        (b'~', 20) => keysym::F9,
+        (b'~', 21) => keysym::F10,
+        (b'~', 23) => keysym::F11,
+        (b'~', 24) => keysym::F12,
+        _ => return None,
+    })
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /**
+     * Next event from the bytes fed so far. If `complete`, no more bytes
+     * are expected soon and unfinished sequences are taken as they are.
+     */
+    pub fn next_event(&mut self, complete: bool) -> Option<TtyEvent> {
+        while !self.buf.is_empty() {
+            let (event, len) = self.parse(complete)?;
+            self.buf.drain(..len);
+            if event.is_some() {
+                return event;
+            }
+        }
+        None
+    }
+
+    /**
+     * Event at the start of the buffer and its length in bytes, None if
+     * more bytes are needed.
+     */
+    fn parse(&mut self, complete: bool) -> Option<(Option<TtyEvent>, usize)> {
+        if self.buf[0] != 0x1b {
+            return Self::character(&self.buf, complete);
+        }
+        let sequence = match self.buf.get(1) {
+            None => None,
+            Some(b'[') => self.csi(),
+            Some(b'O') => self.buf.get(2).map(|&last| {
+                let key = special_key(last, 0);
+                (key.map(|key| TtyEvent::Key(key, Modifiers::default())), 3)
+            }),
+            Some(_) => match Self::character(&self.buf[1..], complete)? {
+                (Some(TtyEvent::Key(key, mut modifiers)), len) => {
+                    modifiers.alt = true;
+                    Some((Some(TtyEvent::Key(key, modifiers)), len + 1))
+                }
+                (event, len) => Some((event, len + 1)),
+            },
+        };
+        match sequence {
+            None if complete => Some((Some(control(0x1b)), 1)),
+            sequence => sequence,
+        }
+    }
+
+    /**
+     * A character, or a control character other than escape.
+     */
+    fn character(buf: &[u8], complete: bool) -> Option<(Option<TtyEvent>, usize)> {
+        let len = match buf[0] {
+            0..=0x1f | 0x7f => return Some((Some(control(buf[0])), 1)),
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        if buf.len() < len {
+            return complete.then_some((None, buf.len()));
+        }
+        match std::str::from_utf8(&buf[..len]) {
+            Ok(text) => {
+                let c = text.chars().next().unwrap();
+                let keysym = keysym::from_char(c);
+                Some((keysym.map(|k| TtyEvent::Key(k, Modifiers::default())), len))
+            }
+            Err(_) => Some((None, 1)),
+        }
+    }
+
+    /**
+     * A control sequence starting with "\x1b[": a special key, or a mouse
+     * report like "\x1b[<0;12;5M".
+     */
+    fn csi(&mut self) -> Option<(Option<TtyEvent>, usize)> {
+        let body = &self.buf[2..];
+        let Some(end) = body.iter().position(|b| (0x40..=0x7e).contains(b)) else {
+            return (body.len() >= MAX_SEQUENCE).then_some((None, self.buf.len()));
+        };
+        let len = end + 3;
+        let last = body[end];
+        let (mouse, params) = match body.first() {
+            Some(b'<') => (true, &body[1..end]),
+            _ => (false, &body[..end]),
+        };
+        let params: Vec<u32> = std::str::from_utf8(params)
+            .unwrap_or("")
+            .split(';')
+            .map(|param| param.parse().unwrap_or(0))
+            .collect();
+        let param = |index: usize| params.get(index).copied().unwrap_or(0);
+        if mouse {
+            return Some((self.mouse(param(0), param(1), param(2), last == b'M'), len));
+        }
+        if last == b'Z' {
+            let shift = modifiers(2);
+            return Some((Some(TtyEvent::Key(keysym::TAB, shift)), len));
+        }
+        let event =
+            special_key(last, param(0)).map(|key| TtyEvent::Key(key, modifiers(param(1).max(1))));
+        Some((event, len))
+    }
+
+    fn mouse(&mut self, code: u32, x: u32, y: u32, pressed: bool) -> Option<TtyEvent> {
+        let x = x.saturating_sub(1).min(u16::MAX as u32) as u16;
+        let y = y.saturating_sub(1).min(u16::MAX as u32) as u16;
+        if code & 64 != 0 {
+            let wheel = [
+                ButtonMask::WHEEL_UP,
+                ButtonMask::WHEEL_DOWN,
+                ButtonMask::WHEEL_LEFT,
+                ButtonMask::WHEEL_RIGHT,
+            ][(code & 3) as usize];
+            return pressed.then_some(TtyEvent::Wheel(wheel, x, y));
+        }
+        if code & 32 == 0 {
+            let button = [ButtonMask::LEFT, ButtonMask::MIDDLE, ButtonMask::RIGHT];
+            if let Some(&button) = button.get((code & 3) as usize) {
+                self.buttons = self.buttons.with(button, pressed);
+            }
+        }
+        Some(TtyEvent::Mouse(self.buttons, x, y))
+    }
+}
+
+/**
+ * Send a key press and release, with the modifiers held around it.
+ */
+fn send_key(input: &InputSender, key: u32, modifiers: Modifiers) -> Result<(), std::io::Error> {
+    let held: Vec<u32> = [
+        (modifiers.ctrl, keysym::CONTROL_L),
+        (modifiers.alt, keysym::ALT_L),
+        (modifiers.shift, keysym::SHIFT_L),
+        (modifiers.meta, keysym::SUPER_L),
+    ]
+    .iter()
+    .filter(|(held, _)| *held)
+    .map(|&(_, keysym)| keysym)
+    .collect();
+    for &keysym in &held {
+        input.key_event(true, keysym)?;
+    }
+    input.key_event(true, key)?;
+    input.key_event(false, key)?;
+    for &keysym in held.iter().rev() {
+        input.key_event(false, keysym)?;
+    }
+    Ok(())
+}
+
+/**
+ * Hand the events read from the terminal on `fd` to `handle`, until Ctrl+]
+ * is pressed.
+ */
+pub fn read<F>(fd: RawFd, mut handle: F) -> Result<(), std::io::Error>
+where
+    F: FnMut(TtyEvent) -> Result<(), std::io::Error>,
+{
+    let mut parser = Parser::new();
+    let mut pending = false;
+    loop {
+        let timeout = pending.then_some(ESCAPE_TIMEOUT);
+        let data = terminal::read_input(fd, timeout)?;
+        parser.feed(&data);
+        while let Some(event) = parser.next_event(data.is_empty()) {
+            let quit = event == TtyEvent::Quit;
+            handle(event)?;
+            if quit {
+                return Ok(());
+            }
+        }
+        pending = !parser.buf.is_empty();
+    }
+}
+
+/**
+ * Forward keys and mouse reports read from `fd` until Ctrl+] is pressed.
+ * Cells of `cell` local pixels are mapped through the current view.
+ */
+pub fn forward(
+    fd: RawFd,
+    view: &Mutex<ViewTransform>,
+    cell: (u16, u16),
+    input: &InputSender,
+) -> Result<(), std::io::Error> {
+    let position = |x: u16, y: u16| {
+        let (width, height) = (cell.0 as f64, cell.1 as f64);
+        view.lock()
+            .unwrap()
+            .to_remote((x as f64 + 0.5) * width, (y as f64 + 0.5) * height)
+    };
+    let mut buttons = ButtonMask::NONE;
+    read(fd, |event| match event {
+        TtyEvent::Key(key, modifiers) => send_key(input, key, modifiers),
+        TtyEvent::Mouse(held, x, y) => {
+            buttons = held;
+            let (x, y) = position(x, y);
+            input.pointer_event(buttons, x, y)
+        }
+        TtyEvent::Wheel(wheel, x, y) => {
+            let (x, y) = position(x, y);
+            input.scroll(wheel, buttons, x, y)
+        }
+        TtyEvent::Quit => Ok(()),
+    })
+}
+
+impl TtyInput {
+    /**
+     * Run `f` with the terminal on stdin in raw mode, on a thread of its
+     * own, see `read` and `forward`.
+     */
+    pub fn start<F>(f: F) -> Result<Self, std::io::Error>
+    where
+        F: FnOnce(RawFd) + Send + 'static,
+    {
+        let fd = std::io::stdin().as_raw_fd();
+        let raw = RawMode::enable(fd)?;
+        let mut stdout = std::io::stdout();
+        stdout.write_all(MOUSE_ON)?;
+        stdout.flush()?;
+        std::thread::spawn(move || f(fd));
+        Ok(Self { _raw: raw })
+    }
+}
+
+impl Drop for TtyInput {
+    fn drop(&mut self) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(MOUSE_OFF);
+        let _ = stdout.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(data: &[u8], complete: bool) -> Vec<TtyEvent> {
+        let mut parser = Parser::new();
+        parser.feed(data);
+        std::iter::from_fn(|| parser.next_event(complete)).collect()
+    }
+
+    fn key(keysym: u32, modifiers: Modifiers) -> TtyEvent {
+        TtyEvent::Key(keysym, modifiers)
+    }
+
+    #[test]
+    fn test_keys() {
+        let none = Modifiers::default();
+        let ctrl = Modifiers {
+            ctrl: true,
+            ..Default::default()
+        };
+        let alt = Modifiers {
+            alt: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            events("aé\r\x7f\x03".as_bytes(), false),
+            [
+                key('a' as u32, none),
+                key(0xe9, none),
+                key(keysym::RETURN, none),
+                key(keysym::BACKSPACE, none),
+                key('c' as u32, ctrl),
+            ]
+        );
+        assert_eq!(
+            events(b"\x1b[A\x1bOP\x1b[15~\x1b[24~\x1b[3~\x1b[1;5D\x1bx", false),
+            [
+                key(keysym::UP, none),
+                key(keysym::F1, none),
+                key(keysym::F5, none),
+                key(keysym::F12, none),
+                key(keysym::DELETE, none),
+                key(keysym::LEFT, ctrl),
+                key('x' as u32, alt),
+            ]
+        );
+        assert_eq!(events(b"\x1d", false), [TtyEvent::Quit]);
+
+        // A lone escape waits for what follows.
+        let mut parser = Parser::new();
+        parser.feed(b"\x1b");
+        assert_eq!(parser.next_event(false), None);
+        parser.feed(b"[");
+        assert_eq!(parser.next_event(false), None);
+        parser.feed(b"B");
+        assert_eq!(parser.next_event(false), Some(key(keysym::DOWN, none)));
+        parser.feed(b"\x1b");
+        assert_eq!(parser.next_event(true), Some(key(keysym::ESCAPE, none)));
+        assert_eq!(parser.next_event(true), None);
+    }
+
+    #[test]
+    fn test_mouse() {
+        assert_eq!(
+            events(
+                b"\x1b[<0;10;5M\x1b[<32;11;5M\x1b[<0;11;5m\x1b[<35;1;1M",
+                false
+            ),
+            [
+                TtyEvent::Mouse(ButtonMask::LEFT, 9, 4),
+                TtyEvent::Mouse(ButtonMask::LEFT, 10, 4),
+                TtyEvent::Mouse(ButtonMask::NONE, 10, 4),
+                TtyEvent::Mouse(ButtonMask::NONE, 0, 0),
+            ]
+        );
+        assert_eq!(
+            events(b"\x1b[<65;3;2M\x1b[<65;3;2m", false),
+            [TtyEvent::Wheel(ButtonMask::WHEEL_DOWN, 2, 1)]
+        );
+    }
+}
@@ -0,0 +1,227 @@
+/*
+ * Mapping between local display coordinates and remote desktop
+ * coordinates.
+ *
+ * The local display shows the remote desktop scaled by `scale_x` and
+ * `scale_y`, with the remote point (`origin_x`, `origin_y`) at the local top
+ * left corner. The origin is negative where the scaled desktop is smaller
+ * than the display and gets centred. Input devices work in local
+ * coordinates and go through `to_remote` before anything is sent to the
+ * server.
+ */
+
+use crate::scale::ScaleMode;
+
+pub const MIN_SCALE: f64 = 0.1;
+pub const MAX_SCALE: f64 = 8.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ViewTransform {
+    pub local_width: u16,
+    pub local_height: u16,
+    pub remote_width: u16,
+    pub remote_height: u16,
+    pub mode: ScaleMode,
+    pub scale_x: f64,
+    pub scale_y: f64,
+    pub origin_x: f64,
+    pub origin_y: f64,
+}
+
+impl ViewTransform {
+    /**
+     * The remote desktop unscaled, with its top left corner at the top
+     * left of the local display.
+     */
+    pub fn new(local_width: u16, local_height: u16, remote_width: u16, remote_height: u16) -> Self {
+        let mut view = Self {
+            local_width,
+            local_height,
+            remote_width,
+            remote_height,
+            mode: ScaleMode::None,
+            scale_x: 1.0,
+            scale_y: 1.0,
+            origin_x: 0.0,
+            origin_y: 0.0,
+        };
+        view.clamp_origin();
+        view
+    }
+
+    /**
+     * A local display of the same size as the remote desktop, unscaled.
+     */
+    pub fn identity(width: u16, height: u16) -> Self {
+        Self::new(width, height, width, height)
+    }
+
+    /**
+     * Scale the whole remote desktop according to `mode`, dropping any
+     * zoom and pan.
+     */
+    pub fn set_mode(&mut self, mode: ScaleMode) {
+        self.mode = mode;
+        (self.scale_x, self.scale_y) = mode.factors(
+            (self.local_width, self.local_height),
+            (self.remote_width, self.remote_height),
+        );
+        self.origin_x = 0.0;
+        self.origin_y = 0.0;
+        self.clamp_origin();
+    }
+
+    /**
+     * Follow a resize of the remote desktop.
+     */
+    pub fn set_remote_size(&mut self, width: u16, height: u16) {
+        self.remote_width = width;
+        self.remote_height = height;
+        self.set_mode(self.mode);
+    }
+
+    /**
+     * Remote pixel under the local point, clamped to the remote desktop.
+     */
+    pub fn to_remote(&self, x: f64, y: f64) -> (u16, u16) {
+        let clamp =
+            |value: f64, size: u16| value.floor().clamp(0.0, size.saturating_sub(1) as f64) as u16;
+        (
+            clamp(x / self.scale_x + self.origin_x, self.remote_width),
+            clamp(y / self.scale_y + self.origin_y, self.remote_height),
+        )
+    }
+
+    pub fn to_local(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            (x - self.origin_x) * self.scale_x,
+            (y - self.origin_y) * self.scale_y,
+        )
+    }
+
+    /**
+     * Move the view along with a drag of (`dx`, `dy`) local pixels.
+     */
+    pub fn pan(&mut self, dx: f64, dy: f64) {
+        self.origin_x -= dx / self.scale_x;
+        self.origin_y -= dy / self.scale_y;
+        self.clamp_origin();
+    }
+
+    /**
+     * Change the scale by `factor`, keeping the remote point under the
+     * local point (`x`, `y`) in place.
+     */
+    pub fn zoom(&mut self, factor: f64, x: f64, y: f64) {
+        let remote_x = x / self.scale_x + self.origin_x;
+        let remote_y = y / self.scale_y + self.origin_y;
+        self.scale_x = (self.scale_x * factor).clamp(MIN_SCALE, MAX_SCALE);
+        self.scale_y = (self.scale_y * factor).clamp(MIN_SCALE, MAX_SCALE);
+        self.origin_x = remote_x - x / self.scale_x;
+        self.origin_y = remote_y - y / self.scale_y;
+        self.clamp_origin();
+    }
+
+    /**
+     * Keep the view within the remote desktop, or centre the desktop on
+     * an axis where it is smaller than the view.
+     */
+    fn clamp_origin(&mut self) {
+        let clamp = |origin: f64, local: u16, remote: u16, scale: f64| {
+            let max = remote as f64 - local as f64 / scale;
+            if max < 0.0 {
+                max / 2.0
+            } else {
+                origin.clamp(0.0, max)
+            }
+        };
+        self.origin_x = clamp(
+            self.origin_x,
+            self.local_width,
+            self.remote_width,
+            self.scale_x,
+        );
+        self.origin_y = clamp(
+            self.origin_y,
+            self.local_height,
+            self.remote_height,
+            self.scale_y,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transform() {
+        let identity = ViewTransform::identity(640, 480);
+        assert_eq!(identity.to_remote(10.5, 20.0), (10, 20));
+        assert_eq!(identity.to_remote(-3.0, 480.0), (0, 479));
+
+        let view = ViewTransform {
+            local_width: 800,
+            local_height: 480,
+            remote_width: 1920,
+            remote_height: 1080,
+            mode: ScaleMode::None,
+            scale_x: 0.5,
+            scale_y: 0.5,
+            origin_x: 100.0,
+            origin_y: 50.0,
+        };
+        assert_eq!(view.to_remote(0.0, 0.0), (100, 50));
+        assert_eq!(view.to_remote(400.0, 240.0), (900, 530));
+        assert_eq!(view.to_local(900.0, 530.0), (400.0, 240.0));
+    }
+
+    #[test]
+    fn test_pan_and_zoom() {
+        let mut view = ViewTransform::identity(640, 480);
+        view.pan(10.0, 10.0);
+        assert_eq!((view.origin_x, view.origin_y), (0.0, 0.0));
+
+        view.zoom(2.0, 100.0, 50.0);
+        assert_eq!((view.scale_x, view.scale_y), (2.0, 2.0));
+        assert_eq!((view.origin_x, view.origin_y), (50.0, 25.0));
+        assert_eq!(view.to_remote(100.0, 50.0), (100, 50));
+
+        view.pan(-40.0, 1000.0);
+        assert_eq!((view.origin_x, view.origin_y), (70.0, 0.0));
+        view.pan(-10000.0, -10000.0);
+        assert_eq!((view.origin_x, view.origin_y), (320.0, 240.0));
+
+        view.zoom(0.01, 0.0, 0.0);
+        assert_eq!(view.scale_x, MIN_SCALE);
+        assert_eq!((view.origin_x, view.origin_y), (-2880.0, -2160.0));
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut view = ViewTransform::new(800, 480, 1920, 1080);
+        assert_eq!((view.origin_x, view.origin_y), (0.0, 0.0));
+
+        view.set_mode(ScaleMode::Fit);
+        assert_eq!(
+            (view.scale_x, view.scale_y),
+            (800.0 / 1920.0, 800.0 / 1920.0)
+        );
+        assert_eq!((view.origin_x, view.origin_y), (0.0, -36.0));
+        assert_eq!(view.to_remote(400.0, 0.0), (960, 0));
+        assert_eq!(view.to_local(0.0, 1080.0), (0.0, 465.0));
+
+        view.set_mode(ScaleMode::Stretch);
+        assert_eq!(
+            (view.scale_x, view.scale_y),
+            (800.0 / 1920.0, 480.0 / 1080.0)
+        );
+        assert_eq!(view.to_remote(799.0, 479.0), (1917, 1077));
+
+        view.set_remote_size(320, 200);
+        assert_eq!((view.scale_x, view.scale_y), (2.5, 2.4));
+        view.set_mode(ScaleMode::Integer);
+        assert_eq!((view.scale_x, view.scale_y), (2.0, 2.0));
+        assert_eq!((view.origin_x, view.origin_y), (-40.0, -20.0));
+    }
+}
@@ -0,0 +1,187 @@
+/*
+ * Moving the view over a remote desktop larger than the display.
+ *
+ * The view pans when a mouse pointer rests at an edge of the display, with
+ * Ctrl+Alt+Shift and the arrow keys, by dragging on a touchscreen, and, if
+ * enabled, along with the remote cursor so that it stays in view. Every
+ * change of the view is followed by a redraw of the display.
+ */
+
+use crate::evdev;
+use crate::keymap::Modifiers;
+use crate::runtime::Renderer;
+use crate::view::ViewTransform;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/** Distance from the border, in local pixels, at which edge scrolling starts. */
+pub const EDGE: f64 = 4.0;
+/** Local pixels to scroll by every `EDGE_INTERVAL` at an edge. */
+pub const EDGE_STEP: f64 = 16.0;
+pub const EDGE_INTERVAL: Duration = Duration::from_millis(40);
+/** Local pixels to scroll by per arrow key press. */
+pub const KEY_STEP: f64 = 64.0;
+/** Local pixels to keep between the followed cursor and the border. */
+pub const FOLLOW_MARGIN: f64 = 32.0;
+
+#[derive(Clone)]
+pub struct Viewport {
+    view: Arc<Mutex<ViewTransform>>,
+    renderer: Renderer,
+    pub edge_scrolling: bool,
+    pub follow_cursor: bool,
+}
+
+/**
+ * Direction to scroll in, -1, 0 or 1 on each axis, for a pointer at the
+ * local point (`x`, `y`).
+ */
+pub fn edge_direction(view: &ViewTransform, x: f64, y: f64) -> (f64, f64) {
+    let direction = |pos: f64, size: u16| {
+        if pos < EDGE {
+            -1.0
+        } else if pos >= size as f64 - EDGE {
+            1.0
+        } else {
+            0.0
+        }
+    };
+    (
+        direction(x, view.local_width),
+        direction(y, view.local_height),
+    )
+}
+
+/**
+ * Local pixels to scroll by for a key press, if it is a shortcut.
+ */
+pub fn shortcut(modifiers: &Modifiers, keycode: u16) -> Option<(f64, f64)> {
+    if !(modifiers.ctrl && modifiers.alt && modifiers.shift) {
+        return None;
+    }
+    match keycode {
+        evdev::KEY_LEFT => Some((-KEY_STEP, 0.0)),
+        evdev::KEY_RIGHT => Some((KEY_STEP, 0.0)),
+        evdev::KEY_UP => Some((0.0, -KEY_STEP)),
+        evdev::KEY_DOWN => Some((0.0, KEY_STEP)),
+        _ => None,
+    }
+}
+
+/**
+ * Pan as little as needed to have the remote point (`x`, `y`) at least
+ * `FOLLOW_MARGIN` local pixels inside the view.
+ */
+pub fn follow(view: &mut ViewTransform, x: u16, y: u16) {
+    let (x, y) = view.to_local(x as f64, y as f64);
+    let drag = |pos: f64, size: u16| {
+        let margin = FOLLOW_MARGIN.min(size as f64 / 2.0);
+        if pos < margin {
+            margin - pos
+        } else if pos > size as f64 - margin {
+            size as f64 - margin - pos
+        } else {
+            0.0
+        }
+    };
+    let (dx, dy) = (drag(x, view.local_width), drag(y, view.local_height));
+    view.pan(dx, dy);
+}
+
+impl Viewport {
+    pub fn new(view: Arc<Mutex<ViewTransform>>, renderer: Renderer) -> Self {
+        Self {
+            view,
+            renderer,
+            edge_scrolling: false,
+            follow_cursor: false,
+        }
+    }
+
+    pub fn view(&self) -> MutexGuard<'_, ViewTransform> {
+        self.view.lock().unwrap()
+    }
+
+    /**
+     * Change the view and redraw the display if anything moved. Returns
+     * whether it did.
+     */
+    pub fn change<F: FnOnce(&mut ViewTransform)>(&self, f: F) -> Result<bool, std::io::Error> {
+        let changed = {
+            let mut view = self.view();
+            let before = view.clone();
+            f(&mut view);
+            *view != before
+        };
+        if changed {
+            self.renderer.redraw()?;
+        }
+        Ok(changed)
+    }
+
+    /**
+     * Move the view by (`dx`, `dy`) local pixels.
+     */
+    pub fn scroll(&self, dx: f64, dy: f64) -> Result<bool, std::io::Error> {
+        self.change(|view| view.pan(-dx, -dy))
+    }
+
+    /**
+     * Direction to keep scrolling in while a mouse pointer is at the local
+     * point (`x`, `y`), if edge scrolling is on and it is at an edge.
+     */
+    pub fn edge(&self, x: f64, y: f64) -> Option<(f64, f64)> {
+        let direction = edge_direction(&self.view(), x, y);
+        (self.edge_scrolling && direction != (0.0, 0.0)).then_some(direction)
+    }
+
+    /**
+     * The remote cursor moved to (`x`, `y`).
+     */
+    pub fn cursor_moved(&self, x: u16, y: u16) -> Result<(), std::io::Error> {
+        if self.follow_cursor {
+            self.change(|view| follow(view, x, y))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edges() {
+        let view = ViewTransform::new(800, 480, 1920, 1080);
+        assert_eq!(edge_direction(&view, 400.0, 240.0), (0.0, 0.0));
+        assert_eq!(edge_direction(&view, 0.0, 479.0), (-1.0, 1.0));
+        assert_eq!(edge_direction(&view, 799.0, 3.0), (1.0, -1.0));
+
+        let modifiers = Modifiers {
+            shift: true,
+            ctrl: true,
+            alt: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            shortcut(&modifiers, evdev::KEY_LEFT),
+            Some((-KEY_STEP, 0.0))
+        );
+        assert_eq!(shortcut(&modifiers, evdev::KEY_A), None);
+        assert_eq!(shortcut(&Modifiers::default(), evdev::KEY_DOWN), None);
+    }
+
+    #[test]
+    fn test_follow() {
+        let mut view = ViewTransform::new(800, 480, 1920, 1080);
+        follow(&mut view, 400, 240);
+        assert_eq!((view.origin_x, view.origin_y), (0.0, 0.0));
+
+        follow(&mut view, 1000, 500);
+        assert_eq!((view.origin_x, view.origin_y), (232.0, 52.0));
+        assert_eq!(view.to_local(1000.0, 500.0), (768.0, 448.0));
+
+        follow(&mut view, 1919, 0);
+        assert_eq!((view.origin_x, view.origin_y), (1120.0, 0.0));
+    }
+}
@@ -0,0 +1,286 @@
+/*
+ * Taking over the Linux virtual terminal the framebuffer is shown on.
+ *
+ * The VT is put into KD_GRAPHICS mode, so neither the fbcon text cursor
+ * nor kernel messages are drawn over the desktop, and into VT_PROCESS
+ * mode, so switching to another VT has to be acknowledged: on SIGUSR1 the
+ * display stops drawing before the VT is released, on SIGUSR2 it is given
+ * back and everything is drawn again.
+ *
+ * Signals are blocked in all threads and handled by one thread with
+ * sigwait(), so `Vt::acquire` has to be called before any other thread is
+ * started. Text mode is restored when the `Vt` is dropped, on panic, and on
+ * SIGTERM, SIGINT and SIGHUP, which then end the process.
+ */
+
+use crate::canvas::{Canvas, Rect};
+use crate::display::Display;
+use crate::runtime::Renderer;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Arc;
+
+const KDSETMODE: u32 = 0x4b3a;
+const KD_TEXT: libc::c_int = 0;
+const KD_GRAPHICS: libc::c_int = 1;
+const VT_SETMODE: u32 = 0x5602;
+const VT_GETSTATE: u32 = 0x5603;
+const VT_RELDISP: u32 = 0x5605;
+const VT_AUTO: libc::c_char = 0;
+const VT_PROCESS: libc::c_char = 1;
+const VT_ACKACQ: libc::c_int = 2;
+
+const RELEASE_SIGNAL: libc::c_int = libc::SIGUSR1;
+const ACQUIRE_SIGNAL: libc::c_int = libc::SIGUSR2;
+const EXIT_SIGNALS: [libc::c_int; 3] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP];
+
+/**
+ * The VT to restore from the panic hook and the signal thread, -1 if none.
+ */
+static CONSOLE: AtomicI32 = AtomicI32::new(-1);
+
+#[repr(C)]
+#[derive(Default)]
+struct VtMode {
+    mode: libc::c_char,
+    waitv: libc::c_char,
+    relsig: libc::c_short,
+    acqsig: libc::c_short,
+    frsig: libc::c_short,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VtStat {
+    v_active: libc::c_ushort,
+    v_signal: libc::c_ushort,
+    v_state: libc::c_ushort,
+}
+
+pub struct Vt {
+    file: File,
+    number: u16,
+    active: Arc<AtomicBool>,
+}
+
+/**
+ * Draws to the inner display only while the VT is in the foreground, and
+ * everything once it comes back.
+ */
+pub struct VtDisplay {
+    inner: Box<dyn Display>,
+    active: Arc<AtomicBool>,
+    stale: bool,
+    resized: bool,
+}
+
+fn ioctl<T>(fd: RawFd, request: u32, arg: T) -> Result<(), std::io::Error> {
+    if unsafe { libc::ioctl(fd, request as _, arg) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn signal_set() -> libc::sigset_t {
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in [RELEASE_SIGNAL, ACQUIRE_SIGNAL].iter().chain(&EXIT_SIGNALS) {
+            libc::sigaddset(&mut set, *signal);
+        }
+        set
+    }
+}
+
+/**
+ * Back to text mode with automatic VT switching and a visible cursor.
+ * Errors are ignored, this runs on the way out.
+ */
+fn restore(fd: RawFd) {
+    let _ = ioctl(fd, KDSETMODE, KD_TEXT);
+    let mode = VtMode {
+        mode: VT_AUTO,
+        ..Default::default()
+    };
+    let _ = ioctl(fd, VT_SETMODE, &mode as *const VtMode);
+    let _ = unsafe { libc::write(fd, b"\x1b[?25h".as_ptr() as *const _, 6) };
+}
+
+impl Vt {
+    /**
+     * Open the VT that is currently in the foreground.
+     */
+    pub fn open_active() -> Result<Self, std::io::Error> {
+        let console = File::open("/dev/tty0")?;
+        let mut stat = VtStat::default();
+        ioctl(console.as_raw_fd(), VT_GETSTATE, &mut stat as *mut VtStat)?;
+        let path = format!("/dev/tty{}", stat.v_active);
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self {
+            file,
+            number: stat.v_active,
+            active: Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    pub fn number(&self) -> u16 {
+        self.number
+    }
+
+    /**
+     * Whether the VT is in the foreground, shared with `VtDisplay`.
+     */
+    pub fn active(&self) -> Arc<AtomicBool> {
+        self.active.clone()
+    }
+
+    /**
+     * Switch to graphics mode and take control of VT switching. Must be
+     * called before starting any threads.
+     */
+    pub fn acquire(&self) -> Result<(), std::io::Error> {
+        let set = signal_set();
+        let err = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+        if err != 0 {
+            return Err(std::io::Error::from_raw_os_error(err));
+        }
+
+        let fd = self.file.as_raw_fd();
+        CONSOLE.store(fd, Ordering::SeqCst);
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let fd = CONSOLE.swap(-1, Ordering::SeqCst);
+            if fd >= 0 {
+                restore(fd);
+            }
+            hook(info);
+        }));
+
+        let mode = VtMode {
+            mode: VT_PROCESS,
+            relsig: RELEASE_SIGNAL as libc::c_short,
+            acqsig: ACQUIRE_SIGNAL as libc::c_short,
+            ..Default::default()
+        };
+        ioctl(fd, VT_SETMODE, &mode as *const VtMode)?;
+        ioctl(fd, KDSETMODE, KD_GRAPHICS)
+    }
+
+    /**
+     * Handle VT switches and exit signals on a thread of its own. The
+     * renderer is used to wait for an update in progress before the VT is
+     * released, and to draw everything when it is back.
+     */
+    pub fn handle_signals(&self, renderer: Renderer) {
+        let fd = self.file.as_raw_fd();
+        let active = self.active.clone();
+        std::thread::spawn(move || loop {
+            let set = signal_set();
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+                continue;
+            }
+            match signal {
+                RELEASE_SIGNAL => {
+                    active.store(false, Ordering::SeqCst);
+                    let _ = renderer.redraw();
+                    let _ = ioctl(fd, VT_RELDISP, 1 as libc::c_int);
+                }
+                ACQUIRE_SIGNAL => {
+                    let _ = ioctl(fd, VT_RELDISP, VT_ACKACQ);
+                    active.store(true, Ordering::SeqCst);
+                    let _ = renderer.redraw();
+                }
+                signal => {
+                    if CONSOLE.swap(-1, Ordering::SeqCst) >= 0 {
+                        restore(fd);
+                    }
+                    std::process::exit(128 + signal);
+                }
+            }
+        });
+    }
+}
+
+impl Drop for Vt {
+    fn drop(&mut self) {
+        if CONSOLE.swap(-1, Ordering::SeqCst) >= 0 {
+            restore(self.file.as_raw_fd());
+        }
+    }
+}
+
+impl VtDisplay {
+    pub fn new(inner: Box<dyn Display>, active: Arc<AtomicBool>) -> Self {
+        Self {
+            inner,
+            active,
+            stale: false,
+            resized: false,
+        }
+    }
+}
+
+impl Display for VtDisplay {
+    fn size(&self) -> Option<(u16, u16)> {
+        self.inner.size()
+    }
+
+    fn update(&mut self, canvas: &Canvas, damage: &[Rect]) -> Result<(), std::io::Error> {
+        if !self.active.load(Ordering::SeqCst) {
+            self.stale = true;
+            return Ok(());
+        }
+        if std::mem::take(&mut self.resized) {
+            self.stale = false;
+            return self.inner.resized(canvas);
+        }
+        if std::mem::take(&mut self.stale) {
+            return self.inner.redraw(canvas);
+        }
+        self.inner.update(canvas, damage)
+    }
+
+    fn resized(&mut self, canvas: &Canvas) -> Result<(), std::io::Error> {
+        if !self.active.load(Ordering::SeqCst) {
+            self.resized = true;
+            return Ok(());
+        }
+        self.stale = false;
+        self.resized = false;
+        self.inner.resized(canvas)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MockDisplay;
+
+    #[test]
+    fn test_vt_display() {
+        let mock = MockDisplay::new(4, 4);
+        let active = Arc::new(AtomicBool::new(true));
+        let mut display = VtDisplay::new(Box::new(mock.clone()), active.clone());
+        // Damaged areas shown and whole canvas redraws.
+        let counts = || {
+            let log = mock.log();
+            let damage = log.updates.iter().map(|(_, damage)| damage.len()).sum();
+            (damage, log.redraws)
+        };
+        let canvas = Canvas::new(4, 4);
+        let damage = [Rect::new(0, 0, 1, 1)];
+
+        display.update(&canvas, &damage).unwrap();
+        assert_eq!(counts(), (1, 0));
+        active.store(false, Ordering::SeqCst);
+        display.update(&canvas, &damage).unwrap();
+        assert_eq!(counts(), (1, 0));
+        active.store(true, Ordering::SeqCst);
+        display.update(&canvas, &[]).unwrap();
+        assert_eq!(counts(), (1, 1));
+        display.update(&canvas, &damage).unwrap();
+        assert_eq!(counts(), (2, 1));
+    }
+}
@@ -0,0 +1,311 @@
+/*
+ * Parser for compiled XKB keymaps.
+ *
+ * A compiled keymap has every include resolved, so a layout can be taken
+ * from the machine the operator normally types on:
+ *
+ *   xkbcli compile-keymap --layout de > de.xkb
+ *   xkbcomp -xkb $DISPLAY de.xkb
+ *
+ * Only the keycodes and the first group of the symbols section are used.
+ * XKB keycodes are the Linux keycodes plus 8. Key types are not evaluated,
+ * the levels are used in the order `translate` expects: plain, Shift,
+ * AltGr, Shift+AltGr, which is what the common key types use.
+ */
+
+use crate::keymap::Layout;
+use crate::keysym;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token<'a> {
+    KeyName(&'a str),
+    Ident(&'a str),
+    Str(&'a str),
+    Punct(char),
+}
+
+/**
+ * Whether the text looks like a keymap this module can parse.
+ */
+pub fn is_keymap(text: &str) -> bool {
+    text.contains("xkb_keycodes")
+}
+
+pub fn parse(text: &str) -> Result<Layout, String> {
+    let tokens = tokenize(text)?;
+    let keycodes = parse_keycodes(section(&tokens, "xkb_keycodes")?)?;
+    let mut layout = Layout::default();
+    for (name, levels) in parse_symbols(section(&tokens, "xkb_symbols")?)? {
+        let keycode = keycodes
+            .get(name)
+            .ok_or(format!("unknown key <{}>", name))?;
+        if let Some(keycode) = keycode.checked_sub(8) {
+            layout.set_levels(keycode, levels);
+        }
+    }
+    Ok(layout)
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token<'_>>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_whitespace() {
+            c.len_utf8()
+        } else if rest.starts_with("//") || c == '#' {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if c == '<' || c == '"' {
+            let end = if c == '<' { '>' } else { '"' };
+            let len = rest[1..].find(end).ok_or(format!("unterminated {}", c))?;
+            let inner = &rest[1..1 + len];
+            tokens.push(if c == '<' {
+                Token::KeyName(inner)
+            } else {
+                Token::Str(inner)
+            });
+            len + 2
+        } else if c.is_alphanumeric() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(&rest[..len]));
+            len
+        } else {
+            tokens.push(Token::Punct(c));
+            c.len_utf8()
+        };
+        rest = &rest[len..];
+    }
+    Ok(tokens)
+}
+
+/**
+ * Length of the block starting with the opening bracket at `tokens[0]`,
+ * including both brackets.
+ */
+fn block_len(tokens: &[Token<'_>]) -> Result<usize, String> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Punct('{' | '[' | '(') => depth += 1,
+            Token::Punct('}' | ']' | ')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    Err("unbalanced brackets".to_string())
+}
+
+/**
+ * Tokens inside the braces of the section `xkb_<kind> "name" { ... };`.
+ */
+fn section<'a, 'b>(tokens: &'b [Token<'a>], kind: &str) -> Result<&'b [Token<'a>], String> {
+    let start = tokens
+        .iter()
+        .position(|token| *token == Token::Ident(kind))
+        .ok_or(format!("no {} section", kind))?;
+    let open = start
+        + tokens[start..]
+            .iter()
+            .position(|token| *token == Token::Punct('{'))
+            .ok_or(format!("empty {} section", kind))?;
+    let len = block_len(&tokens[open..])?;
+    Ok(&tokens[open + 1..open + len - 1])
+}
+
+/**
+ * Split at the separator, ignoring separators inside nested blocks.
+ */
+fn split<'a, 'b>(tokens: &'b [Token<'a>], separator: char) -> Vec<&'b [Token<'a>]> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Punct('{' | '[' | '(') => depth += 1,
+            Token::Punct('}' | ']' | ')') => depth -= 1,
+            Token::Punct(c) if *c == separator && depth == 0 => {
+                parts.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&tokens[start..]);
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+fn parse_keycodes<'a>(tokens: &[Token<'a>]) -> Result<HashMap<&'a str, u16>, String> {
+    let mut keycodes = HashMap::new();
+    let mut aliases = Vec::new();
+    for statement in split(tokens, ';') {
+        match statement {
+            [Token::KeyName(name), Token::Punct('='), Token::Ident(code)] => {
+                let code = code
+                    .parse()
+                    .map_err(|_| format!("invalid keycode {}", code))?;
+                keycodes.insert(*name, code);
+            }
+            [Token::Ident("alias"), Token::KeyName(alias), Token::Punct('='), Token::KeyName(name)] => {
+                aliases.push((*alias, *name))
+            }
+            _ => {}
+        }
+    }
+    for (alias, name) in aliases {
+        if let Some(&code) = keycodes.get(name) {
+            keycodes.entry(alias).or_insert(code);
+        }
+    }
+    Ok(keycodes)
+}
+
+/**
+ * Keysyms of the first group of every `key <NAME> { ... };` statement.
+ */
+fn parse_symbols<'a>(tokens: &[Token<'a>]) -> Result<Vec<(&'a str, Vec<u32>)>, String> {
+    let mut keys = Vec::new();
+    for statement in split(tokens, ';') {
+        let [Token::Ident("key"), Token::KeyName(name), Token::Punct('{'), body @ .., Token::Punct('}')] =
+            statement
+        else {
+            continue;
+        };
+        let group1 = split(body, ',').into_iter().find_map(|item| match item {
+            [Token::Punct('['), ..] => Some(item),
+            [Token::Ident("symbols"), Token::Punct('['), Token::Ident(group), Token::Punct(']'), Token::Punct('='), list @ ..]
+                if group.eq_ignore_ascii_case("group1") || *group == "1" =>
+            {
+                Some(list)
+            }
+            _ => None,
+        });
+        let Some([Token::Punct('['), list @ .., Token::Punct(']')]) = group1 else {
+            continue;
+        };
+        let levels = split(list, ',')
+            .into_iter()
+            .map(|level| match level {
+                [Token::Ident(name)] => Ok(keysym::from_name(name).unwrap_or(0)),
+                _ => Err(format!("invalid symbols for <{}>", name)),
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+        keys.push((*name, levels));
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::{translate, Keyboard, Modifiers};
+
+    const GERMAN: &str = r#"
+xkb_keymap {
+xkb_keycodes "evdev+aliases(qwertz)" {
+    minimum = 8;
+    maximum = 255;
+    <TLDE> = 49;
+    <AE12> = 21;
+    <AD01> = 24;
+    <AD03> = 26;
+    <AD06> = 29;
+    <AC10> = 47;
+    <LFSH> = 50;
+    <RALT> = 108;
+    <SPCE> = 65;
+    indicator 1 = "Caps Lock";
+    alias <LatZ> = <AD06>;
+};
+xkb_types "complete" {
+    type "ONE_LEVEL" {
+        modifiers= none;
+        level_name[Level1]= "Any";
+    };
+};
+xkb_symbols "pc+de+inet(evdev)" {
+    name[group1]="German";
+    key <TLDE> { type= "FOUR_LEVEL", [ dead_circumflex, degree, notsign, notsign ] };
+    key <AE12> { [ dead_acute, dead_grave ] };
+    key <AD01> {
+        type= "FOUR_LEVEL_SEMIALPHABETIC",
+        symbols[Group1]= [ q, Q, at, Greek_OMEGA ]
+    };
+    key <AD03> { type= "FOUR_LEVEL_SEMIALPHABETIC", [ e, E, EuroSign, EuroSign ] };
+    key <LatZ> { [ z, Z, leftarrow, yen ] };
+    key <AC10> { [ odiaeresis, Odiaeresis, dead_doubleacute, NoSymbol ] };
+    key <SPCE> { repeat= Yes, symbols[1]= [ space ], actions[1]= [ NoAction() ] };
+    key <LFSH> { [ Shift_L ] };
+    key <RALT> {
+        type= "ONE_LEVEL",
+        symbols[Group1]= [ ISO_Level3_Shift ]
+    };
+    modifier_map Shift { <LFSH> };
+    modifier_map Mod5 { <RALT> };
+};
+};
+"#;
+
+    #[test]
+    fn test_parse_keymap() {
+        assert!(is_keymap(GERMAN));
+        let layout = parse(GERMAN).unwrap();
+        assert_eq!(
+            layout.levels(16),
+            Some(&['q' as u32, 'Q' as u32, '@' as u32, 0x7d9][..])
+        );
+        assert_eq!(
+            layout.levels(21),
+            Some(&['z' as u32, 'Z' as u32, 0x8fb, 0xa5][..])
+        );
+        assert_eq!(layout.levels(39), Some(&[0xf6, 0xd6, 0xfe59, 0][..]));
+        assert_eq!(layout.levels(57), Some(&[' ' as u32][..]));
+
+        let altgr = Modifiers {
+            level3: true,
+            ..Default::default()
+        };
+        assert_eq!(translate(&layout, 16, &altgr), Some('@' as u32));
+        assert_eq!(translate(&layout, 18, &altgr), Some(0x20ac));
+        assert_eq!(
+            translate(
+                &layout,
+                39,
+                &Modifiers {
+                    shift: true,
+                    ..altgr
+                }
+            ),
+            Some(0xd6)
+        );
+
+        assert!(parse("xkb_keycodes { <AE01> = 10; };").is_err());
+        assert!(
+            parse("xkb_keycodes { <AE01> = 10; }; xkb_symbols { key <FOO> { [ a ] }; };").is_err()
+        );
+    }
+
+    #[test]
+    fn test_german_keyboard() {
+        let mut keyboard = Keyboard::new(parse(GERMAN).unwrap());
+        keyboard.handle_key(100, 1);
+        assert_eq!(keyboard.handle_key(18, 1), [(true, 0x20ac)]);
+        keyboard.handle_key(18, 0);
+        keyboard.handle_key(100, 0);
+
+        keyboard.handle_key(13, 1);
+        keyboard.handle_key(13, 0);
+        assert_eq!(keyboard.handle_key(18, 1), [(true, 0xe9)]);
+        keyboard.handle_key(18, 0);
+
+        keyboard.handle_key(41, 1);
+        keyboard.handle_key(41, 0);
+        assert_eq!(keyboard.handle_key(57, 1), [(true, '^' as u32)]);
+    }
+}
@@ -0,0 +1,110 @@
+/*
+ * Drives `AsyncSession` through the library API, the way an async
+ * application embedding the protocol side would, against a hand written
+ * server on a loopback socket.
+ */
+
+#![cfg(feature = "async")]
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use vncvwr::async_session::AsyncSession;
+use vncvwr::codec::{ClientMessage, ServerMessage};
+use vncvwr::rfb::{Encoding, PixelFormat};
+use vncvwr::session::Session;
+
+const FORMAT: PixelFormat = PixelFormat {
+    bits_per_pixel: 8,
+    depth: 8,
+    big_endian_flag: 0,
+    true_color_flag: 1,
+    red_max: 7,
+    green_max: 7,
+    blue_max: 3,
+    red_shift: 5,
+    green_shift: 2,
+    blue_shift: 0,
+};
+
+fn expect(stream: &mut impl Read, expected: &[u8]) {
+    let mut actual = vec![0u8; expected.len()];
+    stream.read_exact(&mut actual).unwrap();
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn test_async_session() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"RFB 003.008\n").unwrap();
+        expect(&mut stream, b"RFB 003.008\n");
+        stream.write_all(&[1, 1]).unwrap();
+        expect(&mut stream, &[1]);
+        stream.write_all(&[0, 0, 0, 0]).unwrap();
+        expect(&mut stream, &[0]);
+        let mut init = vec![0, 2, 0, 1];
+        init.extend_from_slice(&<[u8; 16]>::from(&Session::PREFERRED_PIXEL_FORMAT));
+        init.extend_from_slice(&[0, 0, 0, 4]);
+        init.extend_from_slice(b"test");
+        stream.write_all(&init).unwrap();
+
+        let mut set_pixel_format = vec![0, 0, 0, 0];
+        set_pixel_format.extend_from_slice(&<[u8; 16]>::from(&FORMAT));
+        expect(&mut stream, &set_pixel_format);
+        expect(
+            &mut stream,
+            &[2, 0, 0, 2, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0x21],
+        );
+        expect(&mut stream, &[4, 1, 0, 0, 0, 0, 0, 0x61]);
+
+        // A 2x1 raw rectangle in the client's 8 bit format, then a bell.
+        stream
+            .write_all(&[
+                0, 0, 0, 1, 0, 0, 0, 0, 0, 2, 0, 1, 0, 0, 0, 0, 0xe0, 0x03, 2,
+            ])
+            .unwrap();
+    });
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let mut session = AsyncSession::new("127.0.0.1", port).await.unwrap();
+            session.handshake(None).await.unwrap();
+            assert_eq!((session.screen_w(), session.screen_h()), (2, 1));
+            assert_eq!(session.name(), "test");
+
+            session.set_pixel_format(&FORMAT).await.unwrap();
+            session
+                .set_encodings(&[Encoding::Raw, Encoding::DesktopSizePseudo])
+                .await
+                .unwrap();
+            session
+                .send(&ClientMessage::KeyEvent {
+                    down: true,
+                    key: 0x61,
+                })
+                .await
+                .unwrap();
+
+            let (_sender, mut messages) = session.into_split().unwrap();
+            match messages.next_message().await {
+                Some(Ok(ServerMessage::FramebufferUpdate(rects))) => {
+                    assert_eq!(rects.len(), 1);
+                    assert_eq!((rects[0].width, rects[0].height), (2, 1));
+                    assert_eq!(rects[0].data, [0xe0, 0x03]);
+                }
+                other => panic!("expected a framebuffer update, got {:?}", other),
+            }
+            assert!(matches!(
+                messages.next_message().await,
+                Some(Ok(ServerMessage::Bell))
+            ));
+            assert!(messages.next_message().await.is_none());
+        });
+    server.join().unwrap();
+}